@@ -0,0 +1,59 @@
+#!/usr/bin/env python3
+# Converts the public domain 8x13 "fixed" BDF font from xorg (font/misc-misc, 8x13.bdf) into the
+# 8x16 PSF2 font the framebuffer console embeds with include_bytes!. Glyphs are in code page 437
+# order, the same bytes the VGA text buffer holds, so the console draws them without converting.
+# Control characters are blank and characters the font lacks get its default character.
+#
+#   python3 fonts/build.py path/to/8x13.bdf fonts/fixed-8x16.psf
+import struct
+import sys
+
+GLYPHS = 256
+WIDTH, HEIGHT = 8, 16
+# 8x13 glyphs go in 8x16 cells with this many blank rows above them (and the rest below)
+TOP_PADDING = 1
+
+
+def parse_bdf(path):
+    glyphs, default_char = {}, 0
+    ascent = None
+    with open(path) as bdf:
+        lines = iter(bdf.read().splitlines())
+    for line in lines:
+        if line.startswith("DEFAULT_CHAR"):
+            default_char = int(line.split()[1])
+        elif line.startswith("FONT_ASCENT"):
+            ascent = int(line.split()[1])
+        elif line.startswith("ENCODING"):
+            code = int(line.split()[1])
+        elif line.startswith("BBX"):
+            w, h, x, y = map(int, line.split()[1:])
+        elif line == "BITMAP":
+            rows = [int(next(lines), 16) for _ in range(h)]
+            # place the bounding box in the 8x13 cell, baseline `ascent` rows from the top
+            cell = [0] * 13
+            top = ascent - (h + y)
+            for i, row in enumerate(rows):
+                if 0 <= top + i < 13:
+                    cell[top + i] = (row >> x) & 0xFF
+            glyphs[code] = cell
+    return glyphs, default_char
+
+
+def main(bdf_path, psf_path):
+    glyphs, default_char = parse_bdf(bdf_path)
+    out = struct.pack("<IIIIIIII", 0x864AB572, 0, 32, 0, GLYPHS, WIDTH * HEIGHT // 8, HEIGHT, WIDTH)
+    for byte in range(GLYPHS):
+        code = ord(bytes([byte]).decode("cp437"))
+        cell = glyphs.get(code, glyphs[default_char]) if code >= 0x20 else [0] * 13
+        # box drawing and block characters have to touch the cells above and below
+        connects = 0x2500 <= code <= 0x259F
+        rows = [cell[0] if connects else 0] * TOP_PADDING + cell
+        rows += [cell[-1] if connects else 0] * (HEIGHT - len(rows))
+        out += bytes(rows)
+    with open(psf_path, "wb") as psf:
+        psf.write(out)
+
+
+if __name__ == "__main__":
+    main(*sys.argv[1:])
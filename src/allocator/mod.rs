@@ -1,5 +1,6 @@
-use alloc::alloc::Layout;
-use bootloader::bootinfo::MemoryMap;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::paging::{page_table::PageTableFlags, Page};
 use x86_64::VirtAddr;
 
@@ -18,9 +19,28 @@ pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 pub const HEAP_END: usize = HEAP_START + HEAP_SIZE;
 
+/// Set once init() mapped the heap
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
 #[global_allocator]
-static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+static ALLOCATOR: NonPreemptible<Locked<LinkedListAllocator>> =
+    NonPreemptible(Locked::new(LinkedListAllocator::new()));
+// static ALLOCATOR: NonPreemptible<Locked<BumpAllocator>> = NonPreemptible(Locked::new(BumpAllocator::new()));
+
+/// Runs the inner allocator with interrupts disabled. Code that allocates with interrupts
+/// disabled (e.g. under the scheduler lock) would otherwise spin forever on an allocator lock
+/// held by a preempted thread.
+struct NonPreemptible<A>(A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for NonPreemptible<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.0.dealloc(ptr, layout))
+    }
+}
 
 /// Ensures that start_addr is correctly aligned by layout.align().
 /// As almost all of rust dynamic types are base 2 aligned, this will rarely be needed.
@@ -40,9 +60,8 @@ pub fn align_up(start_addr: usize, layout: &Layout) -> usize {
 }
 
 /// Maps all the heap virtual memory locations to usable physical memory frames.
-pub fn init<'init_life>(memory_map: &'init_life MemoryMap) {
+pub fn init() {
     logf!(Level::Info, "Mapping heap...");
-    let mut frame_allocator = unsafe { memory::FrameAllocator::new(memory_map) };
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
     let heap_start = VirtAddr::new(HEAP_START as u64);
@@ -50,12 +69,22 @@ pub fn init<'init_life>(memory_map: &'init_life MemoryMap) {
     let heap_end = heap_start + HEAP_SIZE as u64 - 1;
     let heap_end_page = Page::containing_address(heap_end);
 
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        memory::map_virt(page, flags, &mut frame_allocator);
-    }
+    memory::with_frame_allocator(|frame_allocator| {
+        for page in Page::range_inclusive(heap_start_page, heap_end_page) {
+            memory::map_virt(page, flags, frame_allocator);
+        }
+    });
+    INITIALIZED.store(true, Ordering::SeqCst);
     log!(Level::Info, "OK");
 }
 
+/// Whether allocating right now would work: the heap is mapped and we aren't inside the
+/// allocator (e.g. logging from it), where allocating again would deadlock
+pub fn is_available() -> bool {
+    // allocating disables interrupts, so only the allocator itself can be holding its lock here
+    INITIALIZED.load(Ordering::SeqCst) && !ALLOCATOR.0.is_locked()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
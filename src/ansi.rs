@@ -0,0 +1,213 @@
+//! # ANSI
+//! Parser for the ANSI/VT100 escape sequences consoles understand: `ESC [` control sequences
+//! (CSI) such as `ESC[31m` (red text) or `ESC[2J` (clear the screen), and the two character
+//! `ESC 7`/`ESC 8` (save/restore the cursor). Text written with them looks the same on the VGA
+//! console and on a terminal attached to the serial port.
+//!
+//! ## Examples
+//! ```
+//! let mut parser = ansi::Parser::new();
+//! for c in "\x1b[1;31mhi".chars() {
+//!     match parser.advance(c) {
+//!         Some(Action::Print(c)) => ...,
+//!         Some(Action::Csi(csi)) => ...,
+//!         _ => {}
+//!     }
+//! }
+//! ```
+
+#[allow(unused)]
+use crate::prelude::*;
+
+pub const ESC: char = '\x1b';
+
+/// Parameters kept from a sequence, the rest are ignored
+pub const MAX_PARAMS: usize = 8;
+
+/// Parsed control sequence, `ESC [ <private> <params> <final_byte>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    /// Set by a `?` before the parameters (DEC private modes, like `ESC[?25l`)
+    pub private: bool,
+    pub final_byte: char,
+}
+
+impl Csi {
+    /// Parameter `i`, or `default` when it was left out (or given as 0, which means the same for
+    /// every sequence that has a non-zero default)
+    pub fn param(&self, i: usize, default: u16) -> u16 {
+        match self.params().get(i) {
+            Some(0) | None => default,
+            Some(param) => *param,
+        }
+    }
+
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+}
+
+/// What a character written to the console means
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Not part of a sequence (control characters like `\n` included)
+    Print(char),
+    Csi(Csi),
+    /// `ESC <c>` for any `c` other than `[`
+    Escape(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// Turns characters into actions, one character at a time
+#[derive(Debug, Clone, Copy)]
+pub struct Parser {
+    state: State,
+    csi: Csi,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            csi: Csi {
+                params: [0; MAX_PARAMS],
+                len: 0,
+                private: false,
+                final_byte: '\0',
+            },
+        }
+    }
+
+    /// Feeds the next character. Returns the action it completes, if any.
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                ESC => {
+                    self.state = State::Escape;
+                    None
+                }
+                _ => Some(Action::Print(c)),
+            },
+            State::Escape => match c {
+                '[' => {
+                    self.state = State::Csi;
+                    self.csi = Parser::new().csi;
+                    None
+                }
+                ESC => None,
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(c))
+                }
+            },
+            State::Csi => self.advance_csi(c),
+        }
+    }
+
+    fn advance_csi(&mut self, c: char) -> Option<Action> {
+        let csi = &mut self.csi;
+        match c {
+            '0'..='9' => {
+                if csi.len == 0 {
+                    csi.len = 1;
+                }
+                if let Some(param) = csi.params.get_mut(csi.len - 1) {
+                    let digit = c as u16 - '0' as u16;
+                    *param = param.saturating_mul(10).saturating_add(digit);
+                }
+                None
+            }
+            ';' => {
+                // an empty first parameter still counts
+                csi.len = (csi.len.max(1) + 1).min(MAX_PARAMS + 1);
+                None
+            }
+            '?' if csi.len == 0 => {
+                csi.private = true;
+                None
+            }
+            // final bytes
+            '@'..='~' => {
+                self.state = State::Ground;
+                csi.len = csi.len.min(MAX_PARAMS);
+                csi.final_byte = c;
+                Some(Action::Csi(*csi))
+            }
+            // a sequence can't be interrupted, but an ESC starts over
+            ESC => {
+                self.state = State::Escape;
+                None
+            }
+            // intermediate bytes and anything else we don't support are skipped
+            _ => None,
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Parser::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Vec<Action> {
+        let mut parser = Parser::new();
+        s.chars().filter_map(|c| parser.advance(c)).collect()
+    }
+
+    fn csi(s: &str) -> Csi {
+        match parse(s).as_slice() {
+            [Action::Csi(csi)] => *csi,
+            actions => panic!("{s:?} parsed to {actions:?}"),
+        }
+    }
+
+    #[test_case]
+    fn test_text_and_escapes() {
+        assert_eq!(
+            parse("a\n\x1b7b"),
+            [
+                Action::Print('a'),
+                Action::Print('\n'),
+                Action::Escape('7'),
+                Action::Print('b'),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn test_csi_params() {
+        let sequence = csi("\x1b[1;31m");
+        assert_eq!(sequence.params(), [1, 31]);
+        assert_eq!(sequence.final_byte, 'm');
+        assert!(!sequence.private);
+
+        // left out parameters get their defaults
+        let sequence = csi("\x1b[;5H");
+        assert_eq!((sequence.param(0, 1), sequence.param(1, 1)), (1, 5));
+        assert_eq!(csi("\x1b[A").param(0, 1), 1);
+
+        let sequence = csi("\x1b[?25l");
+        assert!(sequence.private);
+        assert_eq!((sequence.params(), sequence.final_byte), (&[25][..], 'l'));
+    }
+
+    #[test_case]
+    fn test_too_many_params() {
+        let sequence = csi("\x1b[1;2;3;4;5;6;7;8;9;10m");
+        assert_eq!(sequence.params(), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(csi("\x1b[99999999C").param(0, 1), u16::MAX);
+    }
+}
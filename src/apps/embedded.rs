@@ -0,0 +1,17 @@
+//! User programs embedded in the kernel image, so they can be run without a disk.
+//! They are built from the sources in `/user` with `user/build.sh`.
+
+/// Name and ELF executable of every embedded program
+pub static PROGRAMS: &[(&str, &[u8])] = &[
+    ("cat", include_bytes!("../../user/bin/cat")),
+    ("hello", include_bytes!("../../user/bin/hello")),
+    ("segments", include_bytes!("../../user/bin/segments")),
+];
+
+/// Returns the ELF executable of the embedded program called `name`
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS
+        .iter()
+        .find(|(program_name, _)| *program_name == name)
+        .map(|(_, bytes)| *bytes)
+}
@@ -1,15 +1,153 @@
 #[allow(unused)]
 use alloc::{borrow::ToOwned, string::ToString};
 
-use crate::{keyboard::getc, prelude::*};
+use crate::apps::embedded;
+use crate::framebuffer;
+use crate::process::{self, Pid};
+use crate::keyboard::{self, Layout};
+use crate::serial::{self, uart::Config, ComPort};
+use crate::prelude::*;
 
-pub struct Gash {}
+pub mod terminal;
 
-fn echo(args: Vec<&str>) -> Result<()> {
-    println!("{}", args.join(" "));
+pub use terminal::Terminal;
+
+pub struct Gash {
+    terminal: Terminal,
+}
+
+const PROMPT: &str = "root@cruzos # ";
+
+const BACKSPACE: char = '\x08';
+/// Cancels the line being typed
+const CTRL_C: char = '\x03';
+/// Ends the session when the line is empty
+const CTRL_D: char = '\x04';
+/// Clears the screen
+const CTRL_L: char = '\x0c';
+
+fn echo(term: &mut Terminal, args: Vec<&str>) -> Result<()> {
+    writeln!(term, "{}", args.join(" "))?;
+    Ok(())
+}
+
+fn ps(term: &mut Terminal, _args: Vec<&str>) -> Result<()> {
+    writeln!(term, "{:>5} {:>5}  {:<14} NAME", "PID", "PPID", "STATE")?;
+    for process in process::list() {
+        let state = format!("{}", process.state);
+        writeln!(
+            term,
+            "{:>5} {:>5}  {:<14} {}",
+            process.pid, process.parent, state, process.name
+        )?;
+    }
+    Ok(())
+}
+
+/// Runs an embedded program in the background, printing its pid
+fn run(term: &mut Terminal, args: Vec<&str>) -> Result<()> {
+    let name = match args.first() {
+        Some(name) => *name,
+        None => return err!("usage: run <program> [args...]"),
+    };
+    let bytes = match embedded::find(name) {
+        Some(bytes) => bytes,
+        None => return err!("no such program: {name}"),
+    };
+    let pid = process::spawn(name, bytes, &args, &[])?;
+    writeln!(term, "[{pid}]")?;
     Ok(())
 }
 
+fn parse_pid(args: &[&str]) -> Result<Pid> {
+    match args.first().map(|arg| arg.parse::<Pid>()) {
+        Some(Ok(pid)) => Ok(pid),
+        _ => err!("expected a pid"),
+    }
+}
+
+/// Waits for a process to finish and prints its exit code. Other tasks keep running meanwhile.
+async fn wait(term: &mut Terminal, args: Vec<&str>) -> Result<()> {
+    let pid = parse_pid(&args)?;
+    let exit_code = process::wait_async(pid).await?;
+    writeln!(term, "[{pid}] exited with {exit_code}")?;
+    Ok(())
+}
+
+fn kill(_term: &mut Terminal, args: Vec<&str>) -> Result<()> {
+    process::kill(parse_pid(&args)?)
+}
+
+/// Prints the keyboard layout in use, or switches to another one
+fn layout(term: &mut Terminal, args: Vec<&str>) -> Result<()> {
+    let name = match args.first() {
+        Some(name) => *name,
+        None => {
+            let names: Vec<&str> = Layout::ALL.iter().map(|layout| layout.name()).collect();
+            let current = keyboard::layout().name();
+            writeln!(term, "{current} (available: {})", names.join(", "))?;
+            return Ok(());
+        }
+    };
+    match Layout::from_name(name) {
+        Some(layout) => {
+            keyboard::set_layout(layout);
+            Ok(())
+        }
+        None => err!("unknown layout: {name}"),
+    }
+}
+
+/// Switches the screen to a graphics mode, where the consoles are drawn with a bitmap font
+fn video(_term: &mut Terminal, args: Vec<&str>) -> Result<()> {
+    let numbers: Option<Vec<usize>> = args.iter().map(|arg| arg.parse().ok()).collect();
+    match numbers.as_deref() {
+        Some([width, height]) => framebuffer::init(*width, *height, 32),
+        Some([width, height, bpp]) => {
+            framebuffer::init(*width, *height, u8::try_from(*bpp).unwrap_or(0))
+        }
+        _ => err!("usage: video <width> <height> [24|32]"),
+    }
+}
+
+/// Lists the serial ports, configures one, or picks the ports logs and the serial console use
+fn serial(term: &mut Terminal, args: Vec<&str>) -> Result<()> {
+    let port = |name: &str| match ComPort::from_name(name) {
+        Some(port) => Ok(port),
+        None => err!("no such port: {name}"),
+    };
+    match args.as_slice() {
+        [] => {
+            for port in ComPort::ALL {
+                let mut uses = Vec::new();
+                if serial::log_port() == Some(port) {
+                    uses.push("logs");
+                }
+                if serial::console_port() == port {
+                    uses.push("console");
+                }
+                match serial::config(port) {
+                    Some(config) => writeln!(term, "{port} {config} {}", uses.join(", "))?,
+                    None => writeln!(term, "{port} not found")?,
+                }
+            }
+            Ok(())
+        }
+        ["log", "none"] => serial::set_log_port(None),
+        ["log", name] => serial::set_log_port(Some(port(name)?)),
+        ["console", name] => serial::set_console_port(port(name)?),
+        [name, baud, frame @ ..] if frame.len() <= 1 => {
+            let baud = match baud.parse() {
+                Ok(baud) => baud,
+                Err(_) => return err!("bad baud rate: {baud}"),
+            };
+            let config = Config::from_frame(baud, frame.first().unwrap_or(&"8N1"))?;
+            serial::configure(port(name)?, config)
+        }
+        _ => err!("usage: serial [<port> <baud> [8N1] | log <port|none> | console <port>]"),
+    }
+}
+
 fn parse_cmd(input: &str) -> (&str, Vec<&str>) {
     // TODO: trim input
     let mut iter = input.split_ascii_whitespace();
@@ -19,35 +157,69 @@ fn parse_cmd(input: &str) -> (&str, Vec<&str>) {
 
 impl Gash {
     pub fn new() -> Self {
-        Gash {}
+        Gash::on(Terminal::Console)
     }
+
+    /// A shell reading and writing `terminal`
+    pub fn on(terminal: Terminal) -> Self {
+        Gash { terminal }
+    }
+
+    /// Runs the shell until input ends with Ctrl+D on an empty line
     pub async fn run(&self) {
+        let mut term = self.terminal;
         let mut input = String::new();
-        loop {
-            print!("root@cruzos # ");
-            while let c = getc().await {
-                // Handle backspace
-                if c == 8 as char {
-                    if let Some(_) = input.pop() {
-                        stdout().backspace();
+        'prompt: loop {
+            let _ = write!(term, "{PROMPT}");
+            while let c = term.getc().await {
+                match c {
+                    BACKSPACE => {
+                        if input.pop().is_some() {
+                            term.backspace();
+                        }
+                        continue;
                     }
-                    continue;
+                    CTRL_C => {
+                        let _ = writeln!(term, "^C");
+                        input.clear();
+                        continue 'prompt;
+                    }
+                    CTRL_D if input.is_empty() => {
+                        let _ = writeln!(term, "exit");
+                        return;
+                    }
+                    CTRL_L => {
+                        term.clear();
+                        let _ = write!(term, "{PROMPT}{input}");
+                        continue;
+                    }
+                    '\n' => {
+                        let _ = write!(term, "{c}");
+                        break;
+                    }
+                    // other control characters and Alt combinations (ESC + key)
+                    c if c.is_control() => continue,
+                    _ => (),
                 }
 
-                print!("{c}");
-                if c == '\n' {
-                    break;
-                }
+                let _ = write!(term, "{c}");
                 input.push(c);
             }
 
             // TODO: run input
             let (cmd, args) = parse_cmd(input.as_str());
             if let Err(msg) = match cmd {
-                "echo" => echo(args),
+                "echo" => echo(&mut term, args),
+                "ps" => ps(&mut term, args),
+                "run" => run(&mut term, args),
+                "wait" => wait(&mut term, args).await,
+                "kill" => kill(&mut term, args),
+                "layout" => layout(&mut term, args),
+                "video" => video(&mut term, args),
+                "serial" => serial(&mut term, args),
                 _ => err!("command not found: {cmd}"),
             } {
-                println!("gash: {msg}");
+                let _ = writeln!(term, "gash: {msg}");
             }
             input.clear();
         }
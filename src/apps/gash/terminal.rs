@@ -0,0 +1,110 @@
+//! Where a shell session reads and writes: the keyboard and a virtual console, or the serial
+//! console port with a terminal emulator (or `-serial stdio`) on the other end.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::keyboard;
+#[allow(unused)]
+use crate::prelude::*;
+use crate::serial;
+
+use super::BACKSPACE;
+
+/// Terminals get DEL from the backspace key
+const DELETE: u8 = 0x7F;
+
+/// Whether the last byte read from the serial line was a carriage return
+static AFTER_CR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminal {
+    /// The keyboard and the console of the running thread
+    Console,
+    /// The serial console port (see `serial::console_port`)
+    Serial,
+}
+
+impl Terminal {
+    /// Terminals to run shells on, set with CRUZOS_SHELL when building: `console` (the default),
+    /// `serial` or `both`
+    pub fn configured() -> &'static [Terminal] {
+        match option_env!("CRUZOS_SHELL") {
+            Some("serial") => &[Terminal::Serial],
+            Some("both") => &[Terminal::Console, Terminal::Serial],
+            _ => &[Terminal::Console],
+        }
+    }
+
+    /// Reads a character, with Enter as '\n' and backspace as BACKSPACE on both terminals
+    pub async fn getc(&self) -> char {
+        match self {
+            Terminal::Console => keyboard::getc().await,
+            Terminal::Serial => loop {
+                let port = serial::console_port();
+                if let Some(c) = serial_char(serial::read_byte(port).await).await {
+                    return c;
+                }
+            },
+        }
+    }
+
+    /// Erases the character before the cursor
+    pub fn backspace(&self) {
+        match self {
+            Terminal::Console => stdout().backspace(),
+            Terminal::Serial => serial::write(serial::console_port(), format_args!("\x08 \x08")),
+        }
+    }
+
+    pub fn clear(&self) {
+        match self {
+            Terminal::Console => stdout().clear(),
+            Terminal::Serial => {
+                serial::write(serial::console_port(), format_args!("\x1b[2J\x1b[H"))
+            }
+        }
+    }
+}
+
+impl fmt::Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self {
+            Terminal::Console => print!("{s}"),
+            Terminal::Serial => {
+                // terminals in raw mode only go to the start of the line on "\r"
+                // serial::write locks the port a byte at a time, so interrupts get through
+                let port = serial::console_port();
+                for (i, line) in s.split('\n').enumerate() {
+                    if i > 0 {
+                        serial::write(port, format_args!("\r\n"));
+                    }
+                    serial::write(port, format_args!("{line}"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turns `first` and the bytes that follow it on the serial line into a character, or None for
+/// bytes that don't make one
+async fn serial_char(first: u8) -> Option<char> {
+    let after_cr = AFTER_CR.swap(first == b'\r', Ordering::Relaxed);
+    let len = match first {
+        // Enter sends "\r", "\n" or both
+        b'\r' => return Some('\n'),
+        b'\n' if after_cr => return None,
+        DELETE => return Some(BACKSPACE),
+        0x00..=0x7F => return Some(first as char),
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => return None,
+    };
+    let mut bytes = [first, 0, 0, 0];
+    for byte in bytes[1..len].iter_mut() {
+        *byte = serial::read_byte(serial::console_port()).await;
+    }
+    core::str::from_utf8(&bytes[..len]).ok()?.chars().next()
+}
@@ -0,0 +1,206 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use x86_64::structures::paging::{page_table::PageTableFlags, Page};
+use x86_64::VirtAddr;
+
+use super::Elf;
+use crate::memory::{AddressSpace, PAGE_SIZE};
+#[allow(unused)]
+use crate::prelude::*;
+use crate::thread::{self, JoinHandle};
+use crate::usermode::{self, USER_SPACE_END, USER_SPACE_START, USER_STACK_PAGES};
+
+/// Top of the user stack, the page above it is left unmapped as a guard
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE as u64;
+/// Segments must end below this address, leaving a guard page under the stack
+const SEGMENTS_END: u64 = USER_STACK_TOP - (USER_STACK_PAGES + 1) * PAGE_SIZE as u64;
+
+/// Auxiliary vector entry that terminates it
+const AT_NULL: u64 = 0;
+
+/// Program loaded in its own address space, ready to jump to
+#[derive(Debug)]
+pub struct LoadedProgram {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    /// Points to argc, followed by argv, envp and the auxiliary vector (System V ABI)
+    pub stack_pointer: VirtAddr,
+}
+
+/// Loads an ELF executable in a new address space, with `argv` and `envp` on its stack
+pub fn load(elf: &Elf, argv: &[&str], envp: &[&str]) -> Result<LoadedProgram> {
+    // checked before mapping anything, so a bad entry point doesn't cost an address space
+    let entry = elf.entry();
+    if !elf
+        .program_headers()
+        .any(|ph| ph.is_load() && ph.is_executable() && ph.contains(entry))
+    {
+        return err!("entry point {entry:#x} is not in an executable segment");
+    }
+
+    let address_space = AddressSpace::new();
+    map_segments(elf, &address_space)?;
+    let stack_pointer = setup_stack(&address_space, argv, envp)?;
+
+    Ok(LoadedProgram {
+        address_space,
+        entry: VirtAddr::new(entry),
+        stack_pointer,
+    })
+}
+
+/// Loads an ELF executable and runs it in ring 3 on a new thread.
+/// The thread's exit code is the one passed to the exit syscall.
+pub fn spawn(bytes: &[u8], argv: &[&str], envp: &[&str]) -> Result<JoinHandle> {
+    let elf = Elf::parse(bytes)?;
+    let program = load(&elf, argv, envp)?;
+    let (entry, stack_pointer) = (program.entry, program.stack_pointer);
+    log!(Level::Debug, "spawning ELF program at {:?}", entry);
+    Ok(thread::spawn_in(Arc::new(program.address_space), move || unsafe {
+        usermode::enter_user_mode(entry, stack_pointer, 0)
+    }))
+}
+
+/// Page permissions needed by the segments that overlap a page
+#[derive(Debug, Default, Clone, Copy)]
+struct PagePermissions {
+    writable: bool,
+    executable: bool,
+}
+
+/// Maps every PT_LOAD segment with the permissions in its flags and copies its data
+fn map_segments(elf: &Elf, address_space: &AddressSpace) -> Result<()> {
+    // segments may share a page, which then gets the permissions of both
+    let mut pages: BTreeMap<u64, PagePermissions> = BTreeMap::new();
+    for ph in elf.program_headers().filter(|ph| ph.is_load() && ph.mem_size > 0) {
+        // parse() already checked this doesn't overflow
+        let end = ph.vaddr + ph.mem_size;
+        if ph.vaddr < USER_SPACE_START || end > SEGMENTS_END {
+            return err!(
+                "segment {:#x}..{:#x} is outside user space ({USER_SPACE_START:#x}..{SEGMENTS_END:#x})",
+                ph.vaddr,
+                end
+            );
+        }
+        let first_page: Page = Page::containing_address(VirtAddr::new(ph.vaddr));
+        let last_page: Page = Page::containing_address(VirtAddr::new(end - 1));
+        for page in Page::range_inclusive(first_page, last_page) {
+            let permissions = pages.entry(page.start_address().as_u64()).or_default();
+            permissions.writable |= ph.is_writable();
+            permissions.executable |= ph.is_executable();
+        }
+    }
+
+    for (&addr, permissions) in pages.iter() {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if permissions.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !permissions.executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        address_space.map(Page::containing_address(VirtAddr::new(addr)), flags)?;
+    }
+
+    // pages are zeroed, so we only copy what is in the file
+    for ph in elf.program_headers().filter(|ph| ph.is_load()) {
+        address_space.write(VirtAddr::new(ph.vaddr), elf.segment_data(&ph))?;
+    }
+    Ok(())
+}
+
+/// Maps the user stack and puts argc, argv, envp and an empty auxiliary vector on it.
+/// Returns the initial stack pointer (16 byte aligned, pointing to argc).
+fn setup_stack(address_space: &AddressSpace, argv: &[&str], envp: &[&str]) -> Result<VirtAddr> {
+    let stack_size = USER_STACK_PAGES * PAGE_SIZE as u64;
+    let stack_bottom = USER_STACK_TOP - stack_size;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    let first_page: Page = Page::containing_address(VirtAddr::new(stack_bottom));
+    let last_page: Page = Page::containing_address(VirtAddr::new(USER_STACK_TOP - 1));
+    for page in Page::range_inclusive(first_page, last_page) {
+        address_space.map(page, flags)?;
+    }
+
+    // NUL terminated strings go at the very top
+    let mut strings: Vec<u8> = Vec::new();
+    let mut string_offsets: Vec<usize> = Vec::new();
+    for s in argv.iter().chain(envp.iter()) {
+        string_offsets.push(strings.len());
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let strings_start = (USER_STACK_TOP - strings.len() as u64) & !0xf;
+
+    // then argc, argv pointers, NULL, envp pointers, NULL and the auxiliary vector
+    let pointer = |i: usize| strings_start + string_offsets[i] as u64;
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend((0..argv.len()).map(pointer));
+    words.push(0);
+    words.extend((argv.len()..argv.len() + envp.len()).map(pointer));
+    words.push(0);
+    words.extend([AT_NULL, 0]);
+    if words.len() % 2 == 1 {
+        words.push(0); // keep the stack pointer 16 byte aligned
+    }
+
+    let words_size = words.len() as u64 * 8;
+    if strings_start < stack_bottom + words_size {
+        return err!("arguments don't fit in the user stack");
+    }
+    let stack_pointer = strings_start - words_size;
+
+    let word_bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    address_space.write(VirtAddr::new(stack_pointer), &word_bytes)?;
+    address_space.write(VirtAddr::new(strings_start), &strings)?;
+
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apps::embedded;
+    use crate::interrupts::SEGFAULT_EXIT_CODE;
+
+    #[test_case]
+    fn test_spawn_with_arguments() {
+        let hello = embedded::find("hello").unwrap();
+        let handle = spawn(hello, &["hello", "from", "elf"], &["SHELL=gash"]).unwrap();
+        // exits with argc
+        assert_eq!(handle.join(), 3);
+    }
+
+    #[test_case]
+    fn test_same_program_twice() {
+        // both are linked at the same address, each runs in its own address space
+        let hello = embedded::find("hello").unwrap();
+        let first = spawn(hello, &["first"], &[]).unwrap();
+        let second = spawn(hello, &["second", "instance"], &[]).unwrap();
+        assert_eq!(first.join(), 1);
+        assert_eq!(second.join(), 2);
+    }
+
+    #[test_case]
+    fn test_segment_permissions() {
+        let segments = embedded::find("segments").unwrap();
+        // writes .data and reads .bss
+        assert_eq!(spawn(segments, &["segments"], &[]).unwrap().join(), 8);
+        // also writes to .text
+        let handle = spawn(segments, &["segments", "write-text"], &[]).unwrap();
+        assert_eq!(handle.join(), SEGFAULT_EXIT_CODE);
+    }
+
+    #[test_case]
+    fn test_reject_kernel_addresses() {
+        let hello = embedded::find("hello").unwrap();
+        let mut elf = Vec::from(hello);
+        // move the first segment (right after the 64 byte file header) to kernel memory
+        let vaddr_offset = 64 + 16;
+        elf[vaddr_offset..vaddr_offset + 8].copy_from_slice(&0x_4444_4444_0000u64.to_le_bytes());
+        assert!(spawn(&elf, &["hello"], &[]).is_err());
+    }
+}
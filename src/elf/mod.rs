@@ -0,0 +1,210 @@
+//! # ELF
+//! Parsing of ELF64 executables (see `loader` to run them).
+//!
+//! ## Examples
+//! ```
+//! let elf = elf::Elf::parse(bytes)?;
+//! for segment in elf.program_headers().filter(|ph| ph.is_load()) {
+//!     log!(Level::Debug, "{:?}", segment);
+//! }
+//! ```
+
+#[allow(unused)]
+use crate::prelude::*;
+
+pub mod loader;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1; // little endian
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const FILE_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Segment to be loaded in memory
+pub const PT_LOAD: u32 = 1;
+
+/// Segment flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    /// Offset of the segment's bytes in the file
+    pub offset: u64,
+    pub vaddr: u64,
+    /// Number of bytes in the file, the rest (up to mem_size) is zeroed
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.segment_type == PT_LOAD
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    /// Returns true if `addr` is inside the segment in memory
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr - self.vaddr < self.mem_size
+    }
+}
+
+/// Validated ELF64 x86_64 executable
+#[derive(Debug)]
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    entry: u64,
+    program_headers_offset: usize,
+    program_headers_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Validates the file and program headers
+    pub fn parse(bytes: &'a [u8]) -> Result<Elf<'a>> {
+        if bytes.len() < FILE_HEADER_SIZE {
+            return err!("file too small for an ELF header ({} bytes)", bytes.len());
+        }
+        if bytes[0..4] != ELF_MAGIC {
+            return err!("not an ELF file (bad magic)");
+        }
+        if bytes[4] != ELFCLASS64 {
+            return err!("not a 64 bit ELF (class {})", bytes[4]);
+        }
+        if bytes[5] != ELFDATA2LSB {
+            return err!("not a little endian ELF");
+        }
+        if bytes[6] != EV_CURRENT {
+            return err!("unknown ELF version {}", bytes[6]);
+        }
+        let file_type = read_u16(bytes, 16);
+        if file_type != ET_EXEC {
+            return err!("not an executable (type {file_type})");
+        }
+        let machine = read_u16(bytes, 18);
+        if machine != EM_X86_64 {
+            return err!("not an x86_64 executable (machine {machine:#x})");
+        }
+
+        let entry = read_u64(bytes, 24);
+        let program_headers_offset = read_u64(bytes, 32) as usize;
+        let program_header_size = read_u16(bytes, 54) as usize;
+        let program_headers_count = read_u16(bytes, 56) as usize;
+        if program_headers_count > 0 && program_header_size != PROGRAM_HEADER_SIZE {
+            return err!("bad program header size {program_header_size}");
+        }
+        let program_headers_end = program_headers_count
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(program_headers_offset));
+        match program_headers_end {
+            Some(end) if end <= bytes.len() => (),
+            _ => return err!("program headers past the end of the file"),
+        }
+
+        let elf = Elf {
+            bytes,
+            entry,
+            program_headers_offset,
+            program_headers_count,
+        };
+        for ph in elf.program_headers() {
+            if ph.file_size > ph.mem_size {
+                return err!("segment at {:#x} is bigger in the file than in memory", ph.vaddr);
+            }
+            match ph.offset.checked_add(ph.file_size) {
+                Some(end) if end <= bytes.len() as u64 => (),
+                _ => return err!("segment at {:#x} past the end of the file", ph.vaddr),
+            }
+            if ph.vaddr.checked_add(ph.mem_size).is_none() {
+                return err!("segment at {:#x} overflows the address space", ph.vaddr);
+            }
+        }
+        Ok(elf)
+    }
+
+    /// Address of the first instruction
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.program_headers_count).map(|i| {
+            let start = self.program_headers_offset + i * PROGRAM_HEADER_SIZE;
+            let ph = &self.bytes[start..start + PROGRAM_HEADER_SIZE];
+            ProgramHeader {
+                segment_type: read_u32(ph, 0),
+                flags: read_u32(ph, 4),
+                offset: read_u64(ph, 8),
+                vaddr: read_u64(ph, 16),
+                file_size: read_u64(ph, 32),
+                mem_size: read_u64(ph, 40),
+                align: read_u64(ph, 48),
+            }
+        })
+    }
+
+    /// Bytes of a segment stored in the file (file_size bytes)
+    pub fn segment_data(&self, ph: &ProgramHeader) -> &'a [u8] {
+        let start = ph.offset as usize;
+        &self.bytes[start..start + ph.file_size as usize]
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apps::embedded;
+
+    #[test_case]
+    fn test_parse_hello() {
+        let elf = Elf::parse(embedded::find("hello").unwrap()).unwrap();
+        assert_eq!(elf.entry(), 0x_1000_0040_0000);
+        let segments: Vec<ProgramHeader> = elf.program_headers().filter(|ph| ph.is_load()).collect();
+        assert_eq!(segments.len(), 2);
+        assert!(segments[0].is_executable() && !segments[0].is_writable());
+        assert!(segments[0].contains(elf.entry()));
+    }
+
+    #[test_case]
+    fn test_parse_invalid() {
+        let hello = embedded::find("hello").unwrap();
+        assert!(Elf::parse(&hello[..32]).is_err());
+
+        let mut bad_magic = Vec::from(hello);
+        bad_magic[1] = b'X';
+        assert!(Elf::parse(&bad_magic).is_err());
+
+        let mut bad_machine = Vec::from(hello);
+        bad_machine[18] = 0x03; // i386
+        assert!(Elf::parse(&bad_machine).is_err());
+
+        // program headers are right after the file header, cut the file in the middle of them
+        assert!(Elf::parse(&hello[..FILE_HEADER_SIZE + 10]).is_err());
+    }
+}
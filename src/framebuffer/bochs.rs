@@ -0,0 +1,101 @@
+//! Display adapter of Bochs and QEMU (`-vga std`), programmed through its DISPI registers. Any
+//! resolution up to the one it reports works, and its linear framebuffer is the memory of its
+//! first PCI BAR.
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use super::{FramebufferInfo, PixelFormat};
+use crate::pci;
+#[allow(unused)]
+use crate::prelude::*;
+
+const INDEX_PORT: u16 = 0x01CE;
+const DATA_PORT: u16 = 0x01CF;
+
+// registers
+const REGISTER_ID: u16 = 0;
+const REGISTER_XRES: u16 = 1;
+const REGISTER_YRES: u16 = 2;
+const REGISTER_BPP: u16 = 3;
+const REGISTER_ENABLE: u16 = 4;
+const REGISTER_VIRT_WIDTH: u16 = 6;
+const REGISTER_X_OFFSET: u16 = 8;
+const REGISTER_Y_OFFSET: u16 = 9;
+
+/// Versions answered by REGISTER_ID, the lowest one that has 32 bits per pixel first
+const MIN_ID: u16 = 0xB0C2;
+const MAX_ID: u16 = 0xB0C5;
+
+// REGISTER_ENABLE bits
+const ENABLED: u16 = 0x01;
+/// Makes XRES, YRES and BPP read back the maximum ones while set
+const GET_CAPABILITIES: u16 = 0x02;
+const LINEAR_FRAMEBUFFER: u16 = 0x40;
+
+const PCI_VENDOR_ID: u16 = 0x1234;
+const PCI_DEVICE_ID: u16 = 0x1111;
+
+fn read(register: u16) -> u16 {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).read()
+    }
+}
+
+fn write(register: u16, value: u16) {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).write(value);
+    }
+}
+
+/// Whether the adapter is there
+pub fn is_present() -> bool {
+    let id = without_interrupts(|| read(REGISTER_ID));
+    (MIN_ID..=MAX_ID).contains(&id)
+}
+
+/// Largest resolution the adapter supports
+pub fn max_resolution() -> (usize, usize) {
+    without_interrupts(|| {
+        let enable = read(REGISTER_ENABLE);
+        write(REGISTER_ENABLE, enable | GET_CAPABILITIES);
+        let max = (read(REGISTER_XRES) as usize, read(REGISTER_YRES) as usize);
+        write(REGISTER_ENABLE, enable);
+        max
+    })
+}
+
+/// Switches to a graphics mode. Returns the physical address of the framebuffer and its layout.
+pub fn set_mode(
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+) -> Result<(u64, FramebufferInfo)> {
+    if !is_present() {
+        return err!("no Bochs/QEMU display adapter");
+    }
+    let (max_width, max_height) = max_resolution();
+    if width > max_width || height > max_height {
+        return err!("{width}x{height} is over the maximum {max_width}x{max_height}");
+    }
+    let device = pci::find(PCI_VENDOR_ID, PCI_DEVICE_ID);
+    let phys = match device.and_then(|device| device.memory_bar(0)) {
+        Some(phys) => phys,
+        None => return err!("display adapter framebuffer not found on PCI"),
+    };
+    let bpp = (format.bytes_per_pixel() * 8) as u16;
+    without_interrupts(|| {
+        // registers can only change while it's disabled
+        write(REGISTER_ENABLE, 0);
+        write(REGISTER_XRES, width as u16);
+        write(REGISTER_YRES, height as u16);
+        write(REGISTER_BPP, bpp);
+        write(REGISTER_VIRT_WIDTH, width as u16);
+        write(REGISTER_X_OFFSET, 0);
+        write(REGISTER_Y_OFFSET, 0);
+        write(REGISTER_ENABLE, ENABLED | LINEAR_FRAMEBUFFER);
+    });
+    Ok((phys, FramebufferInfo::packed(width, height, format)))
+}
@@ -0,0 +1,108 @@
+//! Bitmap fonts in the PC Screen Font 2 format (the one Linux consoles use). Each glyph is a
+//! bitmap of `height` rows, each row padded to whole bytes with the leftmost pixel in the
+//! highest bit.
+//!
+//! The embedded FONT is xorg's public domain 8x13 "fixed" font in 8x16 cells, with its glyphs
+//! in code page 437 order like the VGA text mode font (see `fonts/build.py`).
+
+#[allow(unused)]
+use crate::prelude::*;
+
+const PSF2_MAGIC: u32 = 0x864A_B572;
+/// Header fields: magic, version, header size, flags, glyph count, bytes per glyph, height, width
+const PSF2_HEADER_SIZE: usize = 8 * 4;
+
+lazy_static! {
+    pub static ref FONT: Font =
+        Font::parse(include_bytes!("../../fonts/fixed-8x16.psf")).expect("embedded font is broken");
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Font {
+    glyphs: &'static [u8],
+    count: usize,
+    glyph_size: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Font {
+    pub fn parse(bytes: &'static [u8]) -> Result<Font> {
+        if bytes.len() < PSF2_HEADER_SIZE {
+            return err!("font too short for a PSF2 header");
+        }
+        let field = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        let magic = field(0);
+        if magic != PSF2_MAGIC {
+            return err!("not a PSF2 font (magic {magic:#x})");
+        }
+        let [header_size, count, glyph_size, height, width] =
+            [2, 4, 5, 6, 7].map(|i| field(i) as usize);
+        if header_size < PSF2_HEADER_SIZE {
+            return err!("bad PSF2 header size: {header_size}");
+        }
+        if width == 0 || height == 0 || glyph_size != width.div_ceil(8) * height {
+            return err!("bad PSF2 glyph size: {width}x{height} in {glyph_size} bytes");
+        }
+        let glyphs = count
+            .checked_mul(glyph_size)
+            .and_then(|size| header_size.checked_add(size))
+            .and_then(|end| bytes.get(header_size..end));
+        match glyphs {
+            Some(glyphs) => Ok(Font {
+                glyphs,
+                count,
+                glyph_size,
+                width,
+                height,
+            }),
+            None => err!("PSF2 font too short for its {count} glyphs"),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Bitmap of glyph `index`, the first glyph for indexes the font doesn't have
+    pub fn glyph(&self, index: usize) -> &'static [u8] {
+        let index = if index < self.count { index } else { 0 };
+        &self.glyphs[index * self.glyph_size..(index + 1) * self.glyph_size]
+    }
+
+    /// Whether the pixel at (`x`, `y`) of `glyph` is set
+    pub fn is_set(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        let row = &glyph[y * self.width.div_ceil(8)..];
+        row[x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_embedded_font() {
+        assert_eq!((FONT.width(), FONT.height()), (8, 16));
+        let glyph = FONT.glyph(b'|' as usize);
+        // a vertical bar in the middle column, nothing in the first one
+        assert!((0..16).any(|y| FONT.is_set(glyph, 3, y)));
+        assert!(!(0..16).any(|y| FONT.is_set(glyph, 0, y)));
+        assert!(FONT.glyph(b' ' as usize).iter().all(|row| *row == 0));
+        // full block (0xDB in code page 437)
+        assert!(FONT.glyph(0xDB).iter().all(|row| *row == 0xFF));
+    }
+
+    #[test_case]
+    fn test_bad_fonts() {
+        assert!(Font::parse(&[0; 16]).is_err());
+        assert!(Font::parse(&[0; 64]).is_err());
+        // a valid header promising more glyphs than there are
+        let font: &'static [u8] = include_bytes!("../../fonts/fixed-8x16.psf");
+        assert!(Font::parse(&font[..1000]).is_err());
+    }
+}
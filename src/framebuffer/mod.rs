@@ -0,0 +1,278 @@
+//! # Framebuffer
+//! Linear framebuffers: memory where every pixel of the screen is a few bytes, row after row.
+//! The Bochs/QEMU display adapter (see `bochs`) provides one at any resolution, and
+//! `vga::use_framebuffer` draws the consoles on it with the bitmap font in `font`.
+//!
+//! ## Examples
+//! ```
+//! // switch the screen to 1024x768 and move the consoles over
+//! framebuffer::init(1024, 768, 32)?;
+//! println!("still works");
+//! ```
+//!
+//! `Framebuffer` also works over memory that isn't the screen:
+//! ```
+//! let mut framebuffer = Framebuffer::off_screen(64, 32, PixelFormat::Bgr32);
+//! framebuffer.fill(0, 0, 8, 8, Rgb::new(255, 0, 0));
+//! ```
+
+pub mod bochs;
+pub mod font;
+
+use alloc::vec;
+use core::ops::DerefMut;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{self, PAGE_SIZE};
+#[allow(unused)]
+use crate::prelude::*;
+use crate::vga;
+
+/// Where the screen's framebuffer is mapped. It shares the heap's layer 4 entry, which exists
+/// before any address space is created, so the mapping is seen from all of them.
+const FRAMEBUFFER_START: u64 = 0x_4444_8000_0000;
+
+lazy_static! {
+    /// Physical address and length of what is mapped at FRAMEBUFFER_START
+    static ref MAPPED: Mutex<(u64, usize)> = Mutex::new((0, 0));
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+}
+
+/// How a pixel is laid out in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Blue, green and red bytes (24 bits per pixel)
+    Bgr24,
+    /// Blue, green, red and an unused byte (32 bits per pixel)
+    Bgr32,
+}
+
+impl PixelFormat {
+    pub fn from_bits_per_pixel(bpp: u8) -> Result<Self> {
+        match bpp {
+            24 => Ok(PixelFormat::Bgr24),
+            32 => Ok(PixelFormat::Bgr32),
+            _ => err!("unsupported color depth: {bpp} bits per pixel"),
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Bgr24 => 3,
+            PixelFormat::Bgr32 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub width: usize,
+    pub height: usize,
+    /// Bytes from the start of a row to the start of the next one
+    pub pitch: usize,
+    pub format: PixelFormat,
+}
+
+impl FramebufferInfo {
+    /// Info for rows that follow each other without padding
+    pub fn packed(width: usize, height: usize, format: PixelFormat) -> Self {
+        FramebufferInfo {
+            width,
+            height,
+            pitch: width * format.bytes_per_pixel(),
+            format,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.pitch * self.height
+    }
+}
+
+/// Pixels in `buffer`, which is the screen when it's the mapped framebuffer memory
+pub struct Framebuffer<B = &'static mut [u8]> {
+    buffer: B,
+    info: FramebufferInfo,
+}
+
+impl Framebuffer<Vec<u8>> {
+    /// A black framebuffer on the heap
+    pub fn off_screen(width: usize, height: usize, format: PixelFormat) -> Self {
+        let info = FramebufferInfo::packed(width, height, format);
+        Framebuffer::new(vec![0; info.size()], info)
+    }
+}
+
+impl<B: DerefMut<Target = [u8]>> Framebuffer<B> {
+    pub fn new(buffer: B, info: FramebufferInfo) -> Self {
+        assert!(buffer.len() >= info.size(), "framebuffer too small");
+        Framebuffer { buffer, info }
+    }
+
+    pub fn info(&self) -> FramebufferInfo {
+        self.info
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn offset(&self, x: usize, y: usize) -> Option<usize> {
+        if x >= self.info.width || y >= self.info.height {
+            return None;
+        }
+        Some(y * self.info.pitch + x * self.info.format.bytes_per_pixel())
+    }
+
+    /// Pixels off the framebuffer are ignored
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        let Some(offset) = self.offset(x, y) else {
+            return;
+        };
+        let pixel = &mut self.buffer[offset..offset + self.info.format.bytes_per_pixel()];
+        pixel[..3].copy_from_slice(&[color.b, color.g, color.r]);
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        let offset = self.offset(x, y)?;
+        let pixel = &self.buffer[offset..offset + 3];
+        Some(Rgb::new(pixel[2], pixel[1], pixel[0]))
+    }
+
+    /// Fills the part of the rectangle that is on the framebuffer
+    pub fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let x_end = (x + width).min(self.info.width);
+        let y_end = (y + height).min(self.info.height);
+        for y in y..y_end {
+            for x in x..x_end {
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: Rgb) {
+        self.fill(0, 0, self.info.width, self.info.height, color);
+    }
+
+    /// Copies the `width`x`height` pixels at (`src_x`, `src_y`) of `src` to (`x`, `y`), clipped
+    /// to both framebuffers
+    pub fn copy_rect<C: DerefMut<Target = [u8]>>(
+        &mut self,
+        src: &Framebuffer<C>,
+        (src_x, src_y, width, height): (usize, usize, usize, usize),
+        (x, y): (usize, usize),
+    ) {
+        let width = width
+            .min(src.width().saturating_sub(src_x))
+            .min(self.width().saturating_sub(x));
+        let height = height
+            .min(src.height().saturating_sub(src_y))
+            .min(self.height().saturating_sub(y));
+        if width == 0 || height == 0 {
+            return;
+        }
+        for row in 0..height {
+            if src.info.format != self.info.format {
+                for col in 0..width {
+                    let color = src.pixel(src_x + col, src_y + row).unwrap();
+                    self.set_pixel(x + col, y + row, color);
+                }
+                continue;
+            }
+            let bytes = width * self.info.format.bytes_per_pixel();
+            let from = src.offset(src_x, src_y + row).unwrap();
+            let to = self.offset(x, y + row).unwrap();
+            self.buffer[to..to + bytes].copy_from_slice(&src.buffer[from..from + bytes]);
+        }
+    }
+}
+
+/// Maps `size` bytes of framebuffer memory at `phys` and returns them. Mapping again (after a
+/// mode change) reuses the pages already mapped.
+fn map(phys: u64, size: usize) -> Result<&'static mut [u8]> {
+    let mut mapped = MAPPED.lock();
+    if mapped.1 > 0 && mapped.0 != phys {
+        return err!("a framebuffer at {:#x} is already mapped", mapped.0);
+    }
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE;
+    memory::with_frame_allocator(|frame_allocator| {
+        for offset in (mapped.1..size).step_by(PAGE_SIZE) {
+            let page = Page::containing_address(VirtAddr::new(FRAMEBUFFER_START + offset as u64));
+            let frame = PhysFrame::containing_address(PhysAddr::new(phys + offset as u64));
+            memory::map_phys(page, frame, flags, frame_allocator);
+        }
+    });
+    *mapped = (phys, mapped.1.max(size.next_multiple_of(PAGE_SIZE)));
+    Ok(unsafe { core::slice::from_raw_parts_mut(FRAMEBUFFER_START as *mut u8, size) })
+}
+
+/// Switches the screen to a `width`x`height` graphics mode with `bpp` bits per pixel (24 or 32)
+/// and draws the consoles on it from then on
+pub fn init(width: usize, height: usize, bpp: u8) -> Result<()> {
+    let format = PixelFormat::from_bits_per_pixel(bpp)?;
+    let (min_width, min_height) = vga::framebuffer_min_size(&font::FONT);
+    if width < min_width || height < min_height {
+        return err!("the consoles need at least {min_width}x{min_height} pixels");
+    }
+    let (phys, info) = bochs::set_mode(width, height, format)?;
+    let framebuffer = Framebuffer::new(map(phys, info.size())?, info);
+    vga::use_framebuffer(framebuffer);
+    log!(Level::Info, "Framebuffer console at {width}x{height}x{bpp}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_pixel_formats() {
+        let red = Rgb::new(0xAA, 0x11, 0x22);
+        let mut framebuffer = Framebuffer::off_screen(4, 2, PixelFormat::Bgr32);
+        framebuffer.set_pixel(1, 1, red);
+        assert_eq!(&framebuffer.buffer()[20..24], &[0x22, 0x11, 0xAA, 0]);
+        assert_eq!(framebuffer.pixel(1, 1), Some(red));
+
+        let mut framebuffer = Framebuffer::off_screen(4, 2, PixelFormat::Bgr24);
+        framebuffer.set_pixel(1, 1, red);
+        assert_eq!(&framebuffer.buffer()[15..18], &[0x22, 0x11, 0xAA]);
+        assert_eq!(framebuffer.pixel(0, 1), Some(Rgb::default()));
+        assert_eq!(framebuffer.pixel(4, 0), None);
+    }
+
+    #[test_case]
+    fn test_fill_clips() {
+        let white = Rgb::new(255, 255, 255);
+        let mut framebuffer = Framebuffer::off_screen(8, 8, PixelFormat::Bgr24);
+        framebuffer.fill(6, 6, 10, 10, white);
+        let filled = (0..8)
+            .flat_map(|y| (0..8).map(move |x| (x, y)))
+            .filter(|(x, y)| framebuffer.pixel(*x, *y) == Some(white))
+            .count();
+        assert_eq!(filled, 4);
+    }
+}
@@ -0,0 +1,661 @@
+//! # GDB
+//! A stub for GDB's remote serial protocol on a serial port of its own, so the kernel can be
+//! debugged without QEMU's gdbserver. It's off unless a port is set (CRUZOS_GDB=com2 at build
+//! time, or `set_port`). The kernel stops in the stub:
+//! - on `int3`, including the software breakpoints GDB inserts (`Z0`)
+//! - after a single step (`s`, which sets the trap flag)
+//! - at the next timer tick after GDB sends a packet or Ctrl+C, or Alt+SysRq is pressed
+//! - on panic
+//!
+//! While stopped interrupts are off and the stub polls the UART, answering register (`g`, `G`,
+//! `p`, `P`) and memory (`m`, `M`) reads and writes until GDB continues or steps.
+//!
+//! ## Examples
+//! ```
+//! gdb::set_port(Some(ComPort::Com2))?;
+//! x86_64::instructions::interrupts::int3(); // waits for GDB
+//! ```
+
+pub mod packet;
+
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::rflags::{self, RFlags};
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::memory;
+#[allow(unused)]
+use crate::prelude::*;
+use crate::serial::{self, uart::Uart, ComPort};
+use crate::thread::context::SavedContext;
+use packet::{checksum, decode_hex, parse_hex, split, Reply, PACKET_SIZE};
+
+/// Stored in PORT while the stub is off
+const NO_PORT: usize = usize::MAX;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xCC;
+/// What GDB sends to interrupt the running kernel
+const CTRL_C: u8 = 0x03;
+/// `g` packet registers: rax..r15 and rip (64 bits), then eflags, cs, ss, ds, es, fs and gs (32
+/// bits), in GDB's amd64 numbering
+const REGISTER_COUNT: usize = 24;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+/// Largest `m` read, so its hex fits in a reply
+const MAX_READ: usize = PACKET_SIZE / 2;
+
+static PORT: AtomicUsize = AtomicUsize::new(NO_PORT);
+static BREAK_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Only one stop is handled at a time: a fault in the stub itself doesn't get to it
+static SESSION: Mutex<Session> = Mutex::new(Session::new());
+
+/// Why the kernel stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// `int3` (the stub tells its own breakpoints apart)
+    Breakpoint,
+    /// Debug exception, after a single step
+    Step,
+    /// GDB or Alt+SysRq asked for it
+    Interrupt,
+    Panic,
+}
+
+impl Stop {
+    /// Unix signal GDB shows for it
+    fn signal(self) -> u8 {
+        match self {
+            Stop::Breakpoint | Stop::Step => 5, // SIGTRAP
+            Stop::Interrupt => 2,               // SIGINT
+            Stop::Panic => 6,                   // SIGABRT
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    /// The byte INT3 replaced
+    original: u8,
+}
+
+/// What to do after replying to a packet
+#[derive(Debug, PartialEq, Eq)]
+enum Next {
+    Wait,
+    Resume,
+}
+
+struct Session {
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+    state: State,
+}
+
+/// What the stub remembers between packets and stops
+struct State {
+    stop: Stop,
+    /// Whether the last stop was on one of `breakpoints` (rip is moved back onto it)
+    on_breakpoint: bool,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// Whether interrupts were enabled before the single step in progress (they are off during
+    /// it so it doesn't end in an interrupt handler), None when not stepping
+    stepping: Option<bool>,
+}
+
+/// Sets up the port named by CRUZOS_GDB at build time, if any
+pub fn init() {
+    let Some(name) = option_env!("CRUZOS_GDB") else {
+        return;
+    };
+    let result = match ComPort::from_name(name) {
+        Some(port) => set_port(Some(port)),
+        None => err!("no such port"),
+    };
+    match result {
+        Ok(()) => log!(Level::Info, "GDB stub on {name}"),
+        Err(e) => log!(Level::Warning, "Can't run the GDB stub on {name}: {e}"),
+    }
+}
+
+/// Port the stub talks on, None when it's off
+pub fn port() -> Option<ComPort> {
+    ComPort::ALL.get(PORT.load(Ordering::Relaxed)).copied()
+}
+
+/// Turns the stub on, on a port nothing else uses, or off
+pub fn set_port(port: Option<ComPort>) -> Result<()> {
+    if let Some(port) = port {
+        if serial::config(port).is_none() {
+            return err!("{port} not found");
+        }
+        if serial::log_port() == Some(port) || serial::console_port() == port {
+            return err!("{port} is the serial log or console port");
+        }
+    }
+    PORT.store(
+        port.map_or(NO_PORT, |port| port as usize),
+        Ordering::Relaxed,
+    );
+    Ok(())
+}
+
+/// Stops in the stub at the next timer tick, if it's on
+pub fn request_break() {
+    if port().is_some() {
+        BREAK_REQUESTED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Whether a byte received on `port` means GDB wants the kernel stopped (a packet, or Ctrl+C)
+pub fn wants_break(port: ComPort, byte: u8) -> bool {
+    self::port() == Some(port) && matches!(byte, b'$' | CTRL_C)
+}
+
+/// Whether a stop was requested, clearing the request
+pub fn take_break_request() -> bool {
+    BREAK_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Talks to GDB about `context`, the registers of the stopped code, until it continues.
+/// Called by the exception and timer handlers with interrupts off. Does nothing if the stub is
+/// off, or already stopped.
+pub fn enter(context: &mut SavedContext, stop: Stop) {
+    let Some(port) = port() else {
+        return;
+    };
+    let Some(mut session) = SESSION.try_lock() else {
+        return;
+    };
+    session.run(port, context, stop);
+}
+
+/// Stops in the stub from the panic handler, if it's on. Continuing returns to the handler.
+pub fn enter_on_panic() {
+    if port().is_none() {
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut context = SavedContext {
+            cs: CS::get_reg().0 as u64,
+            ss: SS::get_reg().0 as u64,
+            rflags: rflags::read_raw(),
+            ..Default::default()
+        };
+        // enough for GDB to unwind the stack from here
+        unsafe {
+            asm!(
+                "lea {rip}, [rip]",
+                "mov {rsp}, rsp",
+                "mov {rbp}, rbp",
+                rip = out(reg) context.rip,
+                rsp = out(reg) context.rsp,
+                rbp = out(reg) context.rbp,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        enter(&mut context, Stop::Panic);
+    });
+}
+
+impl Session {
+    const fn new() -> Self {
+        Session {
+            packet: [0; PACKET_SIZE],
+            reply: Reply::new(),
+            state: State::new(),
+        }
+    }
+
+    fn run(&mut self, port: ComPort, context: &mut SavedContext, stop: Stop) {
+        // not through serial::lock, the stopped code may hold it
+        let mut uart = unsafe { Uart::new(port.base()) };
+        self.state.stopped(context, stop);
+        self.reply.clear();
+        self.state.stop_reply(&mut self.reply);
+        send_packet(port, &mut uart, self.reply.as_bytes());
+        loop {
+            let len = receive_packet(port, &mut uart, &mut self.packet);
+            self.reply.clear();
+            let next = self
+                .state
+                .handle(context, &self.packet[..len], &mut self.reply);
+            if next == Next::Wait || !self.reply.is_empty() {
+                send_packet(port, &mut uart, self.reply.as_bytes());
+            }
+            if next == Next::Resume {
+                return;
+            }
+        }
+    }
+}
+
+impl State {
+    const fn new() -> Self {
+        State {
+            stop: Stop::Interrupt,
+            on_breakpoint: false,
+            breakpoints: [None; MAX_BREAKPOINTS],
+            stepping: None,
+        }
+    }
+
+    /// Takes note of a stop, undoing what the stub did to get there
+    fn stopped(&mut self, context: &mut SavedContext, stop: Stop) {
+        let mut flags = RFlags::from_bits_truncate(context.rflags);
+        flags.remove(RFlags::TRAP_FLAG);
+        if self.stepping.take() == Some(true) {
+            flags.insert(RFlags::INTERRUPT_FLAG);
+        }
+        context.rflags = flags.bits();
+        // int3 leaves rip after itself, GDB wants it on the breakpoint
+        let hit = context.rip.wrapping_sub(1);
+        self.on_breakpoint = stop == Stop::Breakpoint && self.breakpoint(hit).is_some();
+        if self.on_breakpoint {
+            context.rip = hit;
+        }
+        self.stop = stop;
+    }
+
+    fn stop_reply(&self, reply: &mut Reply) {
+        let signal = self.stop.signal();
+        let _ = match self.on_breakpoint {
+            true => write!(reply, "T{signal:02x}swbreak:;"),
+            false => write!(reply, "S{signal:02x}"),
+        };
+    }
+
+    /// Answers a packet into `reply` (empty for the ones the stub doesn't know)
+    fn handle(&mut self, context: &mut SavedContext, packet: &[u8], reply: &mut Reply) -> Next {
+        let ok = |reply: &mut Reply, result: Option<()>| {
+            let _ = reply.write_str(if result.is_some() { "OK" } else { "E01" });
+        };
+        match packet {
+            b"?" => self.stop_reply(reply),
+            b"g" => {
+                for n in 0..REGISTER_COUNT {
+                    let (value, size) = register(context, n);
+                    reply.push_hex(&value.to_le_bytes()[..size]);
+                }
+            }
+            [b'G', digits @ ..] => ok(reply, set_registers(context, digits)),
+            [b'p', n @ ..] => match parse_hex(n) {
+                Some(n) if n < REGISTER_COUNT as u64 => {
+                    let (value, size) = register(context, n as usize);
+                    reply.push_hex(&value.to_le_bytes()[..size]);
+                }
+                // registers past the `g` ones (fs_base, st0...) are unsupported, not errors, or
+                // GDB's `info registers` fails on them
+                Some(_) => {}
+                None => ok(reply, None),
+            },
+            [b'P', assignment @ ..] => ok(reply, set_register_packet(context, assignment)),
+            [b'm', range @ ..] => match parse_range(range) {
+                Some((addr, len)) if len <= MAX_READ && is_mapped(addr, len) => {
+                    for i in 0..len as u64 {
+                        reply.push_hex(&[unsafe { read_byte(addr + i) }]);
+                    }
+                }
+                _ => ok(reply, None),
+            },
+            [b'M', write @ ..] => ok(reply, write_packet(write)),
+            [b'c', addr @ ..] => return resume_at(context, addr),
+            [b's', addr @ ..] => {
+                self.stepping = Some(context.rflags & RFlags::INTERRUPT_FLAG.bits() != 0);
+                let mut flags = RFlags::from_bits_truncate(context.rflags);
+                flags.insert(RFlags::TRAP_FLAG);
+                flags.remove(RFlags::INTERRUPT_FLAG);
+                context.rflags = flags.bits();
+                return resume_at(context, addr);
+            }
+            [b'Z', b'0', b',', range @ ..] => ok(
+                reply,
+                parse_range(range).and_then(|(addr, _)| self.insert(addr)),
+            ),
+            [b'z', b'0', b',', range @ ..] => ok(
+                reply,
+                parse_range(range).and_then(|(addr, _)| self.remove(addr)),
+            ),
+            // detach and kill both leave the kernel running without breakpoints
+            [b'D', ..] | b"k" => {
+                self.remove_all();
+                if packet[0] == b'D' {
+                    ok(reply, Some(()));
+                }
+                return Next::Resume;
+            }
+            // there's a single thread as far as GDB is concerned
+            [b'H', ..] => ok(reply, Some(())),
+            _ if packet.starts_with(b"qSupported") => {
+                let _ = write!(reply, "PacketSize={PACKET_SIZE:x};swbreak+");
+            }
+            b"qAttached" => reply.push(b'1'),
+            _ => {}
+        }
+        Next::Wait
+    }
+
+    fn breakpoint(&self, addr: u64) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| breakpoint.is_some_and(|b| b.addr == addr))
+    }
+
+    fn insert(&mut self, addr: u64) -> Option<()> {
+        if self.breakpoint(addr).is_some() {
+            return Some(());
+        }
+        let slot = self.breakpoints.iter().position(Option::is_none)?;
+        if !is_mapped(addr, 1) {
+            return None;
+        }
+        let original = unsafe { read_byte(addr) };
+        unsafe { write_byte(addr, INT3) };
+        self.breakpoints[slot] = Some(Breakpoint { addr, original });
+        Some(())
+    }
+
+    fn remove(&mut self, addr: u64) -> Option<()> {
+        let slot = self.breakpoint(addr)?;
+        if let Some(breakpoint) = self.breakpoints[slot].take() {
+            unsafe { write_byte(addr, breakpoint.original) };
+        }
+        Some(())
+    }
+
+    fn remove_all(&mut self) {
+        for breakpoint in self.breakpoints.into_iter().flatten() {
+            let _ = self.remove(breakpoint.addr);
+        }
+    }
+}
+
+/// Reads a byte from `port`: what its interrupt already buffered first, then the UART itself
+fn read(port: ComPort, uart: &mut Uart) -> u8 {
+    loop {
+        if let Some(byte) = serial::try_read_byte(port).or_else(|| uart.try_receive()) {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Waits for a packet with a good checksum and acknowledges it, returns its length in `buffer`
+fn receive_packet(port: ComPort, uart: &mut Uart, buffer: &mut [u8]) -> usize {
+    loop {
+        // Ctrl+C and acknowledgements of earlier replies come between packets
+        while read(port, uart) != b'$' {}
+        let mut len = 0;
+        let mut overflow = false;
+        loop {
+            let byte = read(port, uart);
+            if byte == b'#' {
+                break;
+            }
+            match buffer.get_mut(len) {
+                Some(slot) => *slot = byte,
+                None => overflow = true,
+            }
+            len += 1;
+        }
+        let sent = [read(port, uart), read(port, uart)];
+        if !overflow && parse_hex(&sent) == Some(checksum(&buffer[..len]) as u64) {
+            uart.send(b'+');
+            return len;
+        }
+        uart.send(b'-');
+    }
+}
+
+/// Sends a packet until GDB acknowledges it
+fn send_packet(port: ComPort, uart: &mut Uart, data: &[u8]) {
+    loop {
+        uart.send(b'$');
+        for byte in data {
+            uart.send(*byte);
+        }
+        let _ = write!(uart, "#{:02x}", checksum(data));
+        loop {
+            match read(port, uart) {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Value and size in bytes of register `n` (see REGISTER_COUNT)
+fn register(context: &SavedContext, n: usize) -> (u64, usize) {
+    let c = context;
+    let value = match n {
+        0 => c.rax,
+        1 => c.rbx,
+        2 => c.rcx,
+        3 => c.rdx,
+        4 => c.rsi,
+        5 => c.rdi,
+        6 => c.rbp,
+        7 => c.rsp,
+        8 => c.r8,
+        9 => c.r9,
+        10 => c.r10,
+        11 => c.r11,
+        12 => c.r12,
+        13 => c.r13,
+        14 => c.r14,
+        15 => c.r15,
+        RIP => c.rip,
+        EFLAGS => c.rflags,
+        18 => c.cs,
+        19 => c.ss,
+        // not saved on interrupts, they don't change in the kernel
+        20 => DS::get_reg().0 as u64,
+        21 => ES::get_reg().0 as u64,
+        22 => FS::get_reg().0 as u64,
+        _ => GS::get_reg().0 as u64,
+    };
+    (value, if n <= RIP { 8 } else { 4 })
+}
+
+/// Register `n` if GDB may change it (the segment registers stay as they are)
+fn register_mut(context: &mut SavedContext, n: usize) -> Option<&mut u64> {
+    let c = context;
+    Some(match n {
+        0 => &mut c.rax,
+        1 => &mut c.rbx,
+        2 => &mut c.rcx,
+        3 => &mut c.rdx,
+        4 => &mut c.rsi,
+        5 => &mut c.rdi,
+        6 => &mut c.rbp,
+        7 => &mut c.rsp,
+        8 => &mut c.r8,
+        9 => &mut c.r9,
+        10 => &mut c.r10,
+        11 => &mut c.r11,
+        12 => &mut c.r12,
+        13 => &mut c.r13,
+        14 => &mut c.r14,
+        15 => &mut c.r15,
+        RIP => &mut c.rip,
+        EFLAGS => &mut c.rflags,
+        _ => return None,
+    })
+}
+
+/// Sets register `n` from its value in hex (little endian, like in `g` replies)
+fn set_register(context: &mut SavedContext, n: usize, digits: &[u8]) -> Option<()> {
+    let (_, size) = register(context, n);
+    let mut bytes = [0; 8];
+    decode_hex(digits, &mut bytes[..size])?;
+    if let Some(register) = register_mut(context, n) {
+        *register = u64::from_le_bytes(bytes);
+    }
+    Some(())
+}
+
+/// `G`: all registers, in `g` order. Registers the packet stops short of are left alone.
+fn set_registers(context: &mut SavedContext, mut digits: &[u8]) -> Option<()> {
+    for n in 0..REGISTER_COUNT {
+        let (_, size) = register(context, n);
+        if digits.len() < size * 2 {
+            break;
+        }
+        let (value, rest) = digits.split_at(size * 2);
+        set_register(context, n, value)?;
+        digits = rest;
+    }
+    Some(())
+}
+
+/// `P`: one register, as `n=value`
+fn set_register_packet(context: &mut SavedContext, assignment: &[u8]) -> Option<()> {
+    let (n, value) = split(assignment, b'=')?;
+    let n = parse_hex(n).filter(|n| *n < REGISTER_COUNT as u64)? as usize;
+    register_mut(context, n)?;
+    set_register(context, n, value)
+}
+
+/// `M`: `addr,len:bytes`
+fn write_packet(write: &[u8]) -> Option<()> {
+    let (range, digits) = split(write, b':')?;
+    let (addr, len) = parse_range(range)?;
+    if digits.len() != len * 2 || !is_mapped(addr, len) {
+        return None;
+    }
+    for (i, pair) in digits.chunks(2).enumerate() {
+        let mut byte = [0];
+        decode_hex(pair, &mut byte)?;
+        unsafe { write_byte(addr + i as u64, byte[0]) };
+    }
+    Some(())
+}
+
+/// `addr,len` (also `addr,kind` for breakpoints)
+fn parse_range(range: &[u8]) -> Option<(u64, usize)> {
+    let (addr, len) = split(range, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)? as usize))
+}
+
+/// Moves rip to the address in a `c` or `s` packet, if there's one
+fn resume_at(context: &mut SavedContext, addr: &[u8]) -> Next {
+    if let Some(addr) = parse_hex(addr) {
+        context.rip = addr;
+    }
+    Next::Resume
+}
+
+/// Whether all of `[addr, addr + len)` is mapped in the active page tables
+fn is_mapped(addr: u64, len: usize) -> bool {
+    let Some(last) = addr.checked_add(len.max(1) as u64 - 1) else {
+        return false;
+    };
+    let (Ok(first), Ok(last)) = (VirtAddr::try_new(addr), VirtAddr::try_new(last)) else {
+        return false;
+    };
+    Page::range_inclusive(
+        Page::<Size4KiB>::containing_address(first),
+        Page::containing_address(last),
+    )
+    .all(|page| memory::page_flags(page.start_address()).is_some())
+}
+
+/// # Safety
+/// `addr` must be mapped
+unsafe fn read_byte(addr: u64) -> u8 {
+    core::ptr::read_volatile(addr as *const u8)
+}
+
+/// Writes even to read-only pages (to put breakpoints in code)
+///
+/// # Safety
+/// `addr` must be mapped, and whatever is there must be fine with being changed
+unsafe fn write_byte(addr: u64, byte: u8) {
+    let writable = memory::page_flags(VirtAddr::new(addr))
+        .is_some_and(|flags| flags.contains(PageTableFlags::WRITABLE));
+    let flags = Cr0::read();
+    if !writable {
+        Cr0::write(flags - Cr0Flags::WRITE_PROTECT);
+    }
+    core::ptr::write_volatile(addr as *mut u8, byte);
+    Cr0::write(flags);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A packet's reply as a string
+    fn reply_to(state: &mut State, context: &mut SavedContext, packet: &[u8]) -> (Next, String) {
+        let mut reply = Reply::new();
+        let next = state.handle(context, packet, &mut reply);
+        (next, String::from_utf8(reply.as_bytes().to_vec()).unwrap())
+    }
+
+    #[test_case]
+    fn test_packets() {
+        let mut state = State::new();
+        let mut context = SavedContext {
+            rax: 0x1122_3344_5566_7788,
+            rip: 0xffff_8000_0000_1000,
+            rflags: 0x202,
+            ..Default::default()
+        };
+        let (_, registers) = reply_to(&mut state, &mut context, b"g");
+        assert_eq!(registers.len(), (17 * 8 + 7 * 4) * 2);
+        assert!(registers.starts_with("8877665544332211"));
+        assert_eq!(reply_to(&mut state, &mut context, b"p11").1, "02020000");
+        assert_eq!(
+            reply_to(&mut state, &mut context, b"P0=0100000000000000").1,
+            "OK"
+        );
+        assert_eq!(context.rax, 1);
+        // segment registers can't be changed
+        assert_eq!(reply_to(&mut state, &mut context, b"P12=08000000").1, "E01");
+        // fs_base, not in the `g` packet
+        assert_eq!(reply_to(&mut state, &mut context, b"p18").1, "");
+
+        let (next, reply) = reply_to(&mut state, &mut context, b"s");
+        assert_eq!((next, reply.as_str()), (Next::Resume, ""));
+        assert_eq!(context.rflags, 0x302);
+        state.stopped(&mut context, Stop::Step);
+        assert_eq!(context.rflags, 0x202);
+        assert_eq!(reply_to(&mut state, &mut context, b"?").1, "S05");
+        assert_eq!(reply_to(&mut state, &mut context, b"vMustReplyEmpty").1, "");
+    }
+
+    #[test_case]
+    fn test_memory() {
+        let mut state = State::new();
+        let mut context = SavedContext::default();
+        let mut memory = [0x90u8, 0x90, 0xc3];
+        let addr = memory.as_mut_ptr() as u64;
+        let m = format!("m{addr:x},3");
+        assert_eq!(reply_to(&mut state, &mut context, m.as_bytes()).1, "9090c3");
+        let write = format!("M{addr:x},1:f4");
+        assert_eq!(reply_to(&mut state, &mut context, write.as_bytes()).1, "OK");
+        assert_eq!(unsafe { read_byte(addr) }, 0xf4);
+        assert_eq!(reply_to(&mut state, &mut context, b"m0,1").1, "E01");
+
+        let insert = format!("Z0,{:x},1", addr + 1);
+        assert_eq!(
+            reply_to(&mut state, &mut context, insert.as_bytes()).1,
+            "OK"
+        );
+        assert_eq!(unsafe { read_byte(addr + 1) }, INT3);
+        // as if the CPU had run into it
+        context.rip = addr + 2;
+        state.stopped(&mut context, Stop::Breakpoint);
+        assert_eq!(context.rip, addr + 1);
+        assert_eq!(reply_to(&mut state, &mut context, b"?").1, "T05swbreak:;");
+        let (next, reply) = reply_to(&mut state, &mut context, b"D");
+        assert_eq!((next, reply.as_str()), (Next::Resume, "OK"));
+        assert_eq!(unsafe { read_byte(addr + 1) }, 0x90);
+    }
+}
@@ -0,0 +1,142 @@
+//! Framing of the remote serial protocol: packets are `$data#checksum`, with binary data written
+//! as hex. Replies are built in a fixed buffer, since the stub can't count on the heap (it may be
+//! what panicked).
+
+use core::fmt;
+
+/// Largest packet the stub takes or sends, told to GDB in the `qSupported` reply
+pub const PACKET_SIZE: usize = 4096;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Sum of the bytes modulo 256, sent as two hex digits after the '#'
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Number written in hex, most significant digit first (addresses, lengths, register numbers)
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, digit| {
+        Some(value << 4 | hex_digit(*digit)? as u64)
+    })
+}
+
+/// Decodes pairs of hex digits into `bytes`, which must have room for exactly all of them
+pub fn decode_hex(digits: &[u8], bytes: &mut [u8]) -> Option<()> {
+    if digits.len() != bytes.len() * 2 {
+        return None;
+    }
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(())
+}
+
+/// The parts of `data` before and after the first `separator`, None if there is none
+pub fn split(data: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = data.iter().position(|byte| *byte == separator)?;
+    Some((&data[..at], &data[at + 1..]))
+}
+
+/// Reply to a packet. What doesn't fit is dropped, so callers size what they send.
+pub struct Reply {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    pub const fn new() -> Self {
+        Reply {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.data[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Appends `bytes` as hex, two digits each
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.push(HEX_DIGITS[(byte >> 4) as usize]);
+            self.push(HEX_DIGITS[(byte & 0xf) as usize]);
+        }
+    }
+}
+
+impl Default for Reply {
+    fn default() -> Self {
+        Reply::new()
+    }
+}
+
+impl fmt::Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test_case]
+    fn test_framing() {
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(parse_hex(b"ffffffff8000abCD"), Some(0xffff_ffff_8000_abcd));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"12g"), None);
+        assert_eq!(
+            split(b"1000,4:cc", b','),
+            Some((&b"1000"[..], &b"4:cc"[..]))
+        );
+        assert_eq!(split(b"1000", b','), None);
+
+        let mut bytes = [0; 2];
+        assert_eq!(decode_hex(b"cC90", &mut bytes), Some(()));
+        assert_eq!(bytes, [0xcc, 0x90]);
+        assert_eq!(decode_hex(b"cc9", &mut bytes), None);
+
+        let mut reply = Reply::new();
+        reply.push_hex(&[0xcc, 0x0f]);
+        write!(reply, ";{}", 5).unwrap();
+        assert_eq!(reply.as_bytes(), b"cc0f;5");
+        for _ in 0..PACKET_SIZE {
+            reply.push(b'x');
+        }
+        assert_eq!(reply.as_bytes().len(), PACKET_SIZE);
+    }
+}
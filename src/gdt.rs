@@ -1,5 +1,4 @@
-
-use core::ptr::addr_of;
+use core::ptr::{addr_of, addr_of_mut};
 
 use x86_64::{
     structures::{
@@ -17,31 +16,56 @@ use crate::prelude::*;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    pub static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe {addr_of!(STACK)});
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
-        tss
+/// Index of the stack the CPU switches to when an interrupt comes from ring 3
+const KERNEL_PRIVILEGE_STACK_INDEX: usize = 0;
+
+// Not behind a lock: the CPU reads it by itself whenever an interrupt comes from ring 3, and the
+// only thing changed after init is the ring 0 stack (see set_kernel_stack)
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+fn init_tss() {
+    let tss = unsafe { &mut *addr_of_mut!(TSS) };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
+        stack_start + STACK_SIZE as u64
+    };
+    // threads with their own stack replace this when they are switched to
+    tss.privilege_stack_table[KERNEL_PRIVILEGE_STACK_INDEX] = {
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
+        stack_start + STACK_SIZE as u64
     };
 }
 
+/// Sets the stack the CPU switches to on interrupts and syscalls coming from ring 3
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        (*addr_of_mut!(TSS)).privilege_stack_table[KERNEL_PRIVILEGE_STACK_INDEX] = stack_top;
+    }
+}
+
 lazy_static! {
     pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         // this append order is important, kernel_code_segment must come before tss_segment
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+        let data_selector = gdt.append(Descriptor::kernel_data_segment());
+        // user data before user code, in the order sysret expects them
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        let tss_selector = gdt.append(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
         (
             gdt,
             Selectors {
                 code_selector,
+                data_selector,
+                user_code_selector,
+                user_data_selector,
                 tss_selector
             }
         )
@@ -50,18 +74,33 @@ lazy_static! {
 
 pub struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+/// Code segment selector for ring 3 (RPL 3)
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
+
+/// Data (and stack) segment selector for ring 3 (RPL 3)
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
 pub fn init_gdt() {
     use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, SS, Segment};
 
     logf!(Level::Info, "Setting up GDT...");
 
+    init_tss();
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 
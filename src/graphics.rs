@@ -0,0 +1,598 @@
+//! # Graphics
+//! 2D drawing on framebuffers: pixels, lines, rectangles, circles and images. Coordinates are
+//! signed and shapes are clipped, so they can be partly off the canvas.
+//!
+//! `DoubleBuffer` draws off screen and copies only the rectangles that changed to the screen on
+//! `present()`, so a status panel redrawn every frame doesn't flicker. The heap is small, so
+//! it is meant for a part of the screen rather than all of it.
+//!
+//! ## Examples
+//! ```
+//! let mut panel = DoubleBuffer::new(200, 100, PixelFormat::Bgr32);
+//! panel.fill_rect(panel.bounds(), BLACK);
+//! panel.line(Point::new(0, 0), Point::new(199, 99), Rgb::new(255, 0, 0));
+//! panel.circle(Point::new(100, 50), 40, Rgb::new(0, 255, 0));
+//! vga::with_framebuffer(|screen| panel.present(screen, Point::new(10, 10)));
+//! ```
+
+use core::ops::DerefMut;
+
+use crate::framebuffer::{Framebuffer, PixelFormat, Rgb};
+#[allow(unused)]
+use crate::prelude::*;
+
+/// Dirty rectangles kept before they are merged into one
+const MAX_DIRTY_RECTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Point {
+    pub x: isize,
+    pub y: isize,
+}
+
+impl Point {
+    pub const fn new(x: isize, y: isize) -> Self {
+        Point { x, y }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: isize,
+    pub y: isize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: isize, y: isize, width: usize, height: usize) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Smallest rectangle with both corners in it
+    fn from_corners(a: Point, b: Point) -> Self {
+        let (x, y) = (a.x.min(b.x), a.y.min(b.y));
+        Rect::new(x, y, a.x.abs_diff(b.x) + 1, a.y.abs_diff(b.y) + 1)
+    }
+
+    /// One past the last column
+    pub fn right(&self) -> isize {
+        self.x + self.width as isize
+    }
+
+    /// One past the last row
+    pub fn bottom(&self) -> isize {
+        self.y + self.height as isize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn contains(&self, point: Point) -> bool {
+        (self.x..self.right()).contains(&point.x) && (self.y..self.bottom()).contains(&point.y)
+    }
+
+    /// Overlapping part, empty when there's none
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= x || bottom <= y {
+            return Rect::default();
+        }
+        Rect::new(x, y, (right - x) as usize, (bottom - y) as usize)
+    }
+
+    /// Smallest rectangle with both in it
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rect::new(x, y, (right - x) as usize, (bottom - y) as usize)
+    }
+}
+
+/// Pixels in memory, row after row
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    pub width: usize,
+    pub height: usize,
+    pixels: &'a [Rgb],
+}
+
+impl<'a> Image<'a> {
+    pub fn new(width: usize, height: usize, pixels: &'a [Rgb]) -> Self {
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "image size doesn't match its pixels"
+        );
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Rgb {
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// Something to draw on. Only `bounds` and `draw_pixel` are needed, the other shapes are drawn
+/// with them unless the canvas has a faster way.
+pub trait Canvas {
+    fn bounds(&self) -> Rect;
+
+    /// Pixels off the canvas are ignored
+    fn draw_pixel(&mut self, point: Point, color: Rgb);
+
+    fn fill_rect(&mut self, rect: Rect, color: Rgb) {
+        let rect = rect.intersection(&self.bounds());
+        for y in rect.y..rect.bottom() {
+            for x in rect.x..rect.right() {
+                self.draw_pixel(Point::new(x, y), color);
+            }
+        }
+    }
+
+    /// Outline one pixel wide
+    fn rect(&mut self, rect: Rect, color: Rgb) {
+        if rect.is_empty() {
+            return;
+        }
+        let (right, bottom) = (rect.right() - 1, rect.bottom() - 1);
+        self.fill_rect(Rect::new(rect.x, rect.y, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, bottom, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, rect.y, 1, rect.height), color);
+        self.fill_rect(Rect::new(right, rect.y, 1, rect.height), color);
+    }
+
+    /// Line with both ends included (Bresenham's algorithm), clipped to the canvas first so far
+    /// away ends don't cost anything
+    fn line(&mut self, from: Point, to: Point, color: Rgb) {
+        let Some((from, to)) = clip_line(self.bounds(), from, to) else {
+            return;
+        };
+        let (dx, dy) = ((to.x - from.x).abs(), -(to.y - from.y).abs());
+        let (step_x, step_y) = ((to.x - from.x).signum(), (to.y - from.y).signum());
+        let mut error = dx + dy;
+        let mut point = from;
+        loop {
+            self.draw_pixel(point, color);
+            if point == to {
+                return;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                point.x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                point.y += step_y;
+            }
+        }
+    }
+
+    /// Outline (midpoint circle algorithm)
+    fn circle(&mut self, center: Point, radius: usize, color: Rgb) {
+        for (x, y) in circle_octant(radius) {
+            for (x, y) in [
+                (x, y),
+                (y, x),
+                (-y, x),
+                (-x, y),
+                (-x, -y),
+                (-y, -x),
+                (y, -x),
+                (x, -y),
+            ] {
+                self.draw_pixel(Point::new(center.x + x, center.y + y), color);
+            }
+        }
+    }
+
+    fn fill_circle(&mut self, center: Point, radius: usize, color: Rgb) {
+        for (x, y) in circle_octant(radius) {
+            // a row on each side of the center for both halves of the octant
+            for (half_width, dy) in [(x, y), (x, -y), (-y, x), (-y, -x)] {
+                let width = 2 * half_width as usize + 1;
+                self.fill_rect(
+                    Rect::new(center.x - half_width, center.y + dy, width, 1),
+                    color,
+                );
+            }
+        }
+    }
+
+    /// Draws `image` with its top left corner at `at`
+    fn blit(&mut self, image: &Image, at: Point) {
+        let rect = Rect::new(at.x, at.y, image.width, image.height).intersection(&self.bounds());
+        for y in rect.y..rect.bottom() {
+            for x in rect.x..rect.right() {
+                let color = image.pixel((x - at.x) as usize, (y - at.y) as usize);
+                self.draw_pixel(Point::new(x, y), color);
+            }
+        }
+    }
+}
+
+/// Points of the circle from the top (0, -radius) to 45 degrees, as (x, y) offsets with
+/// x <= -y. The other seven octants are their reflections.
+fn circle_octant(radius: usize) -> impl Iterator<Item = (isize, isize)> {
+    let radius = radius as isize;
+    let (mut x, mut y, mut error) = (0, -radius, 1 - radius);
+    core::iter::from_fn(move || {
+        if x > -y {
+            return None;
+        }
+        let point = (x, y);
+        x += 1;
+        if error < 0 {
+            error += 2 * x + 1;
+        } else {
+            y += 1;
+            error += 2 * (x + y) + 1;
+        }
+        Some(point)
+    })
+}
+
+impl<B: DerefMut<Target = [u8]>> Canvas for Framebuffer<B> {
+    fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width(), self.height())
+    }
+
+    fn draw_pixel(&mut self, point: Point, color: Rgb) {
+        if point.x >= 0 && point.y >= 0 {
+            self.set_pixel(point.x as usize, point.y as usize, color);
+        }
+    }
+
+    fn fill_rect(&mut self, rect: Rect, color: Rgb) {
+        let rect = rect.intersection(&self.bounds());
+        if !rect.is_empty() {
+            let (x, y) = (rect.x as usize, rect.y as usize);
+            self.fill(x, y, rect.width, rect.height, color);
+        }
+    }
+}
+
+/// Off-screen canvas that remembers what changed since it was last presented
+pub struct DoubleBuffer {
+    back: Framebuffer<Vec<u8>>,
+    /// Parts of `back` that changed, never overlapping each other
+    dirty: Vec<Rect>,
+}
+
+impl DoubleBuffer {
+    /// A black `width`x`height` canvas. Use the pixel format of the screen, so presenting it is
+    /// a copy of each row.
+    pub fn new(width: usize, height: usize, format: PixelFormat) -> Self {
+        DoubleBuffer {
+            back: Framebuffer::off_screen(width, height, format),
+            dirty: Vec::new(),
+        }
+    }
+
+    /// Pixels that will be presented
+    pub fn back(&self) -> &Framebuffer<Vec<u8>> {
+        &self.back
+    }
+
+    pub fn dirty_rects(&self) -> &[Rect] {
+        &self.dirty
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        let mut rect = rect.intersection(&self.bounds());
+        if rect.is_empty() {
+            return;
+        }
+        // merge with whatever it overlaps until it overlaps nothing
+        while let Some(i) = self
+            .dirty
+            .iter()
+            .position(|dirty| !dirty.intersection(&rect).is_empty())
+        {
+            rect = rect.union(&self.dirty.swap_remove(i));
+        }
+        self.dirty.push(rect);
+        if self.dirty.len() > MAX_DIRTY_RECTS {
+            let all = self
+                .dirty
+                .drain(..)
+                .fold(Rect::default(), |all, rect| all.union(&rect));
+            self.dirty.push(all);
+        }
+    }
+
+    /// Copies what changed to `screen`, with the top left corner of the canvas at `at` (which
+    /// has to be on the screen)
+    pub fn present<B: DerefMut<Target = [u8]>>(&mut self, screen: &mut Framebuffer<B>, at: Point) {
+        let (x, y) = (at.x.max(0) as usize, at.y.max(0) as usize);
+        for rect in self.dirty.drain(..) {
+            let (rect_x, rect_y) = (rect.x as usize, rect.y as usize);
+            let area = (rect_x, rect_y, rect.width, rect.height);
+            screen.copy_rect(&self.back, area, (x + rect_x, y + rect_y));
+        }
+    }
+
+    /// Marks everything as changed, so the next present() copies all of it
+    pub fn invalidate(&mut self) {
+        self.dirty.clear();
+        self.mark_dirty(self.bounds());
+    }
+}
+
+impl Canvas for DoubleBuffer {
+    fn bounds(&self) -> Rect {
+        self.back.bounds()
+    }
+
+    fn draw_pixel(&mut self, point: Point, color: Rgb) {
+        self.back.draw_pixel(point, color);
+        self.mark_dirty(Rect::new(point.x, point.y, 1, 1));
+    }
+
+    // shapes mark their bounding box once instead of each pixel
+
+    fn fill_rect(&mut self, rect: Rect, color: Rgb) {
+        Canvas::fill_rect(&mut self.back, rect, color);
+        self.mark_dirty(rect);
+    }
+
+    fn rect(&mut self, rect: Rect, color: Rgb) {
+        self.back.rect(rect, color);
+        self.mark_dirty(rect);
+    }
+
+    fn line(&mut self, from: Point, to: Point, color: Rgb) {
+        if let Some((from, to)) = clip_line(self.bounds(), from, to) {
+            self.back.line(from, to, color);
+            self.mark_dirty(Rect::from_corners(from, to));
+        }
+    }
+
+    fn circle(&mut self, center: Point, radius: usize, color: Rgb) {
+        self.back.circle(center, radius, color);
+        self.mark_dirty(circle_bounds(center, radius));
+    }
+
+    fn fill_circle(&mut self, center: Point, radius: usize, color: Rgb) {
+        self.back.fill_circle(center, radius, color);
+        self.mark_dirty(circle_bounds(center, radius));
+    }
+
+    fn blit(&mut self, image: &Image, at: Point) {
+        self.back.blit(image, at);
+        self.mark_dirty(Rect::new(at.x, at.y, image.width, image.height));
+    }
+}
+
+// outcodes of Cohen-Sutherland line clipping: which sides of the bounds a point is past
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const ABOVE: u8 = 1 << 2;
+const BELOW: u8 = 1 << 3;
+
+/// Part of the segment inside `bounds` (Cohen-Sutherland), None when it misses them. Ends moved
+/// onto the bounds are rounded to the nearest pixel.
+fn clip_line(bounds: Rect, from: Point, to: Point) -> Option<(Point, Point)> {
+    if bounds.is_empty() {
+        return None;
+    }
+    // products of far away coordinates overflow isize
+    let (left, top) = (bounds.x as i128, bounds.y as i128);
+    let (right, bottom) = (bounds.right() as i128 - 1, bounds.bottom() as i128 - 1);
+    let outcode = |(x, y): (i128, i128)| {
+        let mut code = 0;
+        if x < left {
+            code |= LEFT;
+        } else if x > right {
+            code |= RIGHT;
+        }
+        if y < top {
+            code |= ABOVE;
+        } else if y > bottom {
+            code |= BELOW;
+        }
+        code
+    };
+
+    let mut a = (from.x as i128, from.y as i128);
+    let mut b = (to.x as i128, to.y as i128);
+    loop {
+        let (code_a, code_b) = (outcode(a), outcode(b));
+        if code_a | code_b == 0 {
+            let point = |(x, y): (i128, i128)| Point::new(x as isize, y as isize);
+            return Some((point(a), point(b)));
+        }
+        if code_a & code_b != 0 {
+            // both past the same side
+            return None;
+        }
+        // move an end that is outside onto the side it's past
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let (x, y) = a;
+        let code = if code_a != 0 { code_a } else { code_b };
+        let moved = if code & ABOVE != 0 {
+            (x + div_round(dx * (top - y), dy), top)
+        } else if code & BELOW != 0 {
+            (x + div_round(dx * (bottom - y), dy), bottom)
+        } else if code & LEFT != 0 {
+            (left, y + div_round(dy * (left - x), dx))
+        } else {
+            (right, y + div_round(dy * (right - x), dx))
+        };
+        if code_a != 0 {
+            a = moved;
+        } else {
+            b = moved;
+        }
+    }
+}
+
+/// `n / d` rounded to the nearest integer
+fn div_round(n: i128, d: i128) -> i128 {
+    let (n, d) = if d < 0 { (-n, -d) } else { (n, d) };
+    (2 * n + d).div_euclid(2 * d)
+}
+
+fn circle_bounds(center: Point, radius: usize) -> Rect {
+    let r = radius as isize;
+    Rect::new(center.x - r, center.y - r, 2 * radius + 1, 2 * radius + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgb = Rgb::new(255, 255, 255);
+    const RED: Rgb = Rgb::new(255, 0, 0);
+
+    /// FNV-1a hash of the pixels
+    fn checksum<B: DerefMut<Target = [u8]>>(framebuffer: &Framebuffer<B>) -> u64 {
+        framebuffer
+            .buffer()
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100_0000_01b3)
+            })
+    }
+
+    fn canvas() -> Framebuffer<Vec<u8>> {
+        Framebuffer::off_screen(32, 32, PixelFormat::Bgr24)
+    }
+
+    fn count(framebuffer: &Framebuffer<Vec<u8>>, color: Rgb) -> usize {
+        (0..32)
+            .flat_map(|y| (0..32).map(move |x| (x, y)))
+            .filter(|(x, y)| framebuffer.pixel(*x, *y) == Some(color))
+            .count()
+    }
+
+    #[test_case]
+    fn test_lines() {
+        // diagonal lines are the same pixels whichever way they're drawn
+        let (mut forward, mut backward) = (canvas(), canvas());
+        forward.line(Point::new(2, 3), Point::new(29, 17), WHITE);
+        backward.line(Point::new(29, 17), Point::new(2, 3), WHITE);
+        assert_eq!(count(&forward, WHITE), 28);
+        assert_eq!(checksum(&forward), checksum(&backward));
+
+        // straight ones are thin rectangles, clipped by the canvas
+        let (mut line, mut rect) = (canvas(), canvas());
+        line.line(Point::new(-5, 4), Point::new(40, 4), WHITE);
+        rect.fill_rect(Rect::new(0, 4, 32, 1), WHITE);
+        assert_eq!(checksum(&line), checksum(&rect));
+    }
+
+    #[test_case]
+    fn test_far_off_lines() {
+        // clipped before drawing, or this would take forever
+        let far = isize::MAX / 2;
+        let mut diagonal = canvas();
+        diagonal.line(Point::new(-far, -far), Point::new(far, far), WHITE);
+        assert_eq!(count(&diagonal, WHITE), 32);
+        assert!((0..32).all(|i| diagonal.pixel(i, i) == Some(WHITE)));
+
+        let (mut line, mut rect) = (canvas(), canvas());
+        line.line(Point::new(far, 7), Point::new(-far, 7), WHITE);
+        rect.fill_rect(Rect::new(0, 7, 32, 1), WHITE);
+        assert_eq!(checksum(&line), checksum(&rect));
+
+        // misses the canvas
+        let mut missed = canvas();
+        missed.line(Point::new(-far, 40), Point::new(far, 40), WHITE);
+        missed.line(Point::new(-far, 0), Point::new(0, -far), WHITE);
+        assert_eq!(count(&missed, WHITE), 0);
+        let mut double = DoubleBuffer::new(16, 16, PixelFormat::Bgr24);
+        double.line(Point::new(-far, 3), Point::new(far, 3), WHITE);
+        assert_eq!(double.dirty_rects(), &[Rect::new(0, 3, 16, 1)]);
+    }
+
+    #[test_case]
+    fn test_rects() {
+        let mut outline = canvas();
+        outline.rect(Rect::new(4, 4, 10, 6), WHITE);
+        assert_eq!(count(&outline, WHITE), 2 * 10 + 2 * 4);
+        // the outline of a filled rectangle is what's left after filling its inside
+        let mut filled = canvas();
+        filled.fill_rect(Rect::new(4, 4, 10, 6), WHITE);
+        filled.fill_rect(Rect::new(5, 5, 8, 4), Rgb::default());
+        assert_eq!(checksum(&outline), checksum(&filled));
+    }
+
+    #[test_case]
+    fn test_circles() {
+        let (mut outline, mut filled) = (canvas(), canvas());
+        outline.circle(Point::new(16, 16), 10, WHITE);
+        filled.fill_circle(Point::new(16, 16), 10, WHITE);
+        // symmetric, and the outline is the edge of the filled circle
+        for (x, y) in [(6, 16), (26, 16), (16, 6), (16, 26)] {
+            assert_eq!(outline.pixel(x, y), Some(WHITE));
+        }
+        assert_eq!(outline.pixel(16, 16), Some(Rgb::default()));
+        assert_eq!(filled.pixel(16, 16), Some(WHITE));
+        for y in 0..32 {
+            for x in 0..32 {
+                if outline.pixel(x, y) == Some(WHITE) {
+                    assert_eq!(filled.pixel(x, y), Some(WHITE));
+                }
+            }
+        }
+        assert_eq!(count(&outline, WHITE) % 4, 0);
+        let mut mirrored = canvas();
+        mirrored.fill_circle(Point::new(15, 15), 10, WHITE);
+        assert_eq!(count(&mirrored, WHITE), count(&filled, WHITE));
+    }
+
+    #[test_case]
+    fn test_blit() {
+        let pixels = [RED, WHITE, WHITE, RED];
+        let image = Image::new(2, 2, &pixels);
+        let mut framebuffer = canvas();
+        framebuffer.blit(&image, Point::new(-1, 30));
+        assert_eq!(framebuffer.pixel(0, 30), Some(WHITE));
+        assert_eq!(framebuffer.pixel(0, 31), Some(RED));
+        assert_eq!(count(&framebuffer, WHITE) + count(&framebuffer, RED), 2);
+    }
+
+    #[test_case]
+    fn test_double_buffer() {
+        let mut direct = canvas();
+        let mut screen = canvas();
+        let mut double = DoubleBuffer::new(16, 16, PixelFormat::Bgr24);
+        for canvas in [&mut direct as &mut dyn Canvas, &mut double] {
+            canvas.fill_rect(Rect::new(1, 1, 4, 4), RED);
+            canvas.line(Point::new(0, 10), Point::new(10, 0), WHITE);
+            canvas.draw_pixel(Point::new(14, 14), WHITE);
+        }
+        // the line's box takes in the rectangle, the pixel is apart
+        assert_eq!(double.dirty_rects().len(), 2);
+        double.present(&mut screen, Point::new(0, 0));
+        assert!(double.dirty_rects().is_empty());
+        assert_eq!(checksum(&screen), checksum(&direct));
+
+        // only what changed is copied
+        screen.fill_rect(Rect::new(0, 0, 32, 32), Rgb::default());
+        double.draw_pixel(Point::new(3, 3), WHITE);
+        double.present(&mut screen, Point::new(10, 10));
+        assert_eq!(count(&screen, WHITE), 1);
+        assert_eq!(screen.pixel(13, 13), Some(WHITE));
+    }
+}
@@ -1,20 +1,45 @@
+use crate::gdb::{self, Stop};
 #[allow(unused)]
-use crate::{exit_qemu, gdt::DOUBLE_FAULT_IST_INDEX, hlt_loop, keyboard, prelude::*, QemuExitCode};
+use crate::{exit_qemu, gdt::DOUBLE_FAULT_IST_INDEX, hlt_loop, keyboard, mouse, prelude::*, serial, QemuExitCode};
+use crate::syscall::SYSCALL_VECTOR;
+use crate::thread::{self, context::{self, SavedContext}};
 
+use core::sync::atomic::{AtomicU64, Ordering};
 use pic8259::ChainedPics;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum PICInterrupt {
     Timer = PIC_1_OFFSET,
     Keyboard = PIC_1_OFFSET + 1,
+    /// Also COM4
+    Com2 = PIC_1_OFFSET + 3,
+    /// Also COM3
+    Com1 = PIC_1_OFFSET + 4,
+    Mouse = PIC_1_OFFSET + 12,
 }
 
 const PIC_1_OFFSET: u8 = 32;
 
+/// Frequency the PIT is programmed to fire timer interrupts at
+pub const TIMER_HZ: u64 = 100;
+const PIT_BASE_FREQUENCY: u64 = 1_193_182;
+
+/// Number of timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of timer ticks since boot (there are TIMER_HZ ticks per second)
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
 lazy_static! {
-    pub static ref PICS: Mutex<ChainedPics> = Mutex::new(unsafe {ChainedPics::new_contiguous(PIC_1_OFFSET)}); // this is the same as new(PIC_1_OFFSET, PIC_1_OFFSET + 8);
+    pub static ref PICS: IrqMutex<ChainedPics> = IrqMutex::new(unsafe {ChainedPics::new_contiguous(PIC_1_OFFSET)}); // this is the same as new(PIC_1_OFFSET, PIC_1_OFFSET + 8);
 }
 
 lazy_static! {
@@ -22,41 +47,166 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
 
         // reserved interrupts
-        idt.breakpoint.set_handler_fn(breakpoint);
-        idt.page_fault.set_handler_fn(page_fault);
+        // breakpoints and single steps may stop in the GDB stub, which needs every register
+        unsafe {
+            idt.breakpoint.set_handler_addr(context::breakpoint_entry_addr());
+            idt.debug.set_handler_addr(context::debug_entry_addr());
+        }
+        // page faults from ring 3 kill the faulting thread, so they can switch threads as well
+        unsafe {
+            idt.page_fault.set_handler_addr(context::page_fault_entry_addr());
+        }
         let double_fault_options = idt.double_fault.set_handler_fn(double_fault);
         unsafe {
             double_fault_options.set_stack_index(DOUBLE_FAULT_IST_INDEX);
         }
 
         // PIC interrupts
-        idt[PICInterrupt::Timer as u8].set_handler_fn(timer_interrupt);
+        // the timer goes through an assembly stub that saves the registers so it can switch threads
+        unsafe {
+            idt[PICInterrupt::Timer as u8].set_handler_addr(context::timer_entry_addr());
+            idt[context::YIELD_VECTOR].set_handler_addr(context::yield_entry_addr());
+        }
         idt[PICInterrupt::Keyboard as u8].set_handler_fn(keyboard::keyboard_interrupt);
+        idt[PICInterrupt::Mouse as u8].set_handler_fn(mouse::mouse_interrupt);
+        idt[PICInterrupt::Com1 as u8].set_handler_fn(serial::com1_interrupt);
+        idt[PICInterrupt::Com2 as u8].set_handler_fn(serial::com2_interrupt);
         // TODO: set handler functions to PIC interrupts
+
+        // syscalls (int 0x80) are the only interrupt ring 3 can trigger
+        unsafe {
+            idt[SYSCALL_VECTOR]
+                .set_handler_addr(context::syscall_entry_addr())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
         idt
     };
 }
 
-extern "x86-interrupt" fn timer_interrupt(_stack_frame: InterruptStackFrame) {
+/// Handles a timer interrupt. Called by `context::timer_entry` with the saved context of the
+/// interrupted thread, returns the saved context of the thread to resume.
+pub extern "C" fn timer_interrupt(rsp: u64) -> u64 {
+    TICKS.fetch_add(1, Ordering::Relaxed);
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(PICInterrupt::Timer as u8)
     };
+    if gdb::take_break_request() {
+        // the thread GDB stopped resumes, as it may be stepping
+        gdb::enter(unsafe { &mut *(rsp as *mut SavedContext) }, Stop::Interrupt);
+        return rsp;
+    }
+    // preempt the running thread
+    thread::schedule(rsp)
+}
+
+/// Programs channel 0 of the PIT to fire TIMER_HZ times per second
+fn init_timer() {
+    let divisor = (PIT_BASE_FREQUENCY / TIMER_HZ) as u16;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+    unsafe {
+        command.write(0x36); // channel 0, lobyte/hibyte, square wave mode
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Measures time by reading the PIT's counter, for busy waits with interrupts disabled (when
+/// `ticks` doesn't advance). It must be polled more than twice per tick, time is lost otherwise.
+pub struct PitClock {
+    last: u16,
+    /// Counted down by the PIT since start, 2 per PIT clock in square wave mode
+    counted: u64,
+}
+
+impl PitClock {
+    pub fn start() -> Self {
+        PitClock {
+            last: read_pit_count(),
+            counted: 0,
+        }
+    }
+
+    /// Milliseconds since start
+    pub fn elapsed_ms(&mut self) -> u64 {
+        let count = read_pit_count();
+        // the counter goes down from the divisor to 0 twice per tick
+        self.counted += if count <= self.last {
+            u64::from(self.last - count)
+        } else {
+            u64::from(self.last) + PIT_BASE_FREQUENCY / TIMER_HZ - u64::from(count)
+        };
+        self.last = count;
+        self.counted / 2 * 1000 / PIT_BASE_FREQUENCY
+    }
 }
 
-extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
-    log!(Level::Debug, "Got Breakpoint interrupt: {:#?}", stack_frame);
+/// Current value of the PIT channel 0 counter
+fn read_pit_count() -> u16 {
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+    unsafe {
+        command.write(0x00); // latch channel 0's count
+        let low = channel_0.read();
+        let high = channel_0.read();
+        u16::from_le_bytes([low, high])
+    }
 }
 
-extern "x86-interrupt" fn page_fault(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+/// Handles `int3`. Called by `context::breakpoint_entry` with the saved context of the
+/// interrupted code, which it resumes.
+pub extern "C" fn breakpoint(rsp: u64) -> u64 {
+    let context = unsafe { &mut *(rsp as *mut SavedContext) };
+    match gdb::port() {
+        Some(_) => gdb::enter(context, Stop::Breakpoint),
+        None => log!(Level::Debug, "Got Breakpoint interrupt: {:#x?}", context),
+    }
+    rsp
+}
+
+/// Handles a debug exception, which comes after each instruction while the trap flag is set.
+/// Called by `context::debug_entry` with the saved context of the interrupted code, which it
+/// resumes.
+pub extern "C" fn debug(rsp: u64) -> u64 {
+    let context = unsafe { &mut *(rsp as *mut SavedContext) };
+    match gdb::port() {
+        Some(_) => gdb::enter(context, Stop::Step),
+        None => {
+            log!(Level::Debug, "Got Debug interrupt: {:#x?}", context);
+            context.rflags &= !RFlags::TRAP_FLAG.bits();
+        }
+    }
+    rsp
+}
+
+/// Exit code of threads killed for touching memory they don't have access to
+pub const SEGFAULT_EXIT_CODE: i64 = -11;
+
+/// Handles a page fault. Called by `context::page_fault_entry` with the saved context of the
+/// faulting thread, returns the saved context of the thread to resume.
+pub extern "C" fn page_fault(rsp: u64, error_code: u64) -> u64 {
+    let context = unsafe { &*(rsp as *const SavedContext) };
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    if context.is_user() {
+        // user code can't bring the kernel down, kill the thread instead
+        log!(
+            Level::Warning,
+            "Killing thread {} on page fault at {:#x} ({:?}), rip {:#x}",
+            thread::current(),
+            Cr2::read_raw(),
+            error_code,
+            context.rip
+        );
+        thread::finish_current(SEGFAULT_EXIT_CODE);
+        return thread::schedule(rsp);
+    }
     log!(
         Level::Error,
-        "Got Page Fault interrupt ({:?}: {:#?}",
+        "Got Page Fault interrupt at {:#x} ({:?}): {:#x?}",
+        Cr2::read_raw(),
         error_code,
-        stack_frame
+        context
     );
     hlt_loop();
 }
@@ -71,7 +221,16 @@ extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, error_c
 pub fn init_idt() {
     logf!(Level::Info, "Setting up IDT...");
 
-    unsafe { PICS.lock().initialize() };
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        // the firmware may leave the serial ports (IRQ3 and IRQ4), the mouse (IRQ12) and the
+        // cascade to the second PIC (IRQ2) masked
+        let [mask1, mask2] = pics.read_masks();
+        let mask1 = mask1 & !(1 << 2) & !(1 << 3) & !(1 << 4);
+        pics.write_masks(mask1, mask2 & !(1 << (12 - 8)));
+    }
+    init_timer();
     x86_64::instructions::interrupts::enable();
 
     IDT.load();
@@ -90,5 +249,14 @@ mod tests {
                                                   // fails if this panics instead of successfully returning to execution
     }
 
+    #[test_case]
+    fn test_pit_clock() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut clock = PitClock::start();
+            while clock.elapsed_ms() < 30 {}
+            assert!(clock.elapsed_ms() < 40);
+        });
+    }
+
     // tests that should fail are integration tests (tests/interrupts.rs)
 }
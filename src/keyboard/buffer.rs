@@ -1,9 +1,24 @@
+use crate::keyboard::scancode::KeyEvent;
 use crate::prelude::*;
+use crate::thread;
+use crate::vga::CONSOLE_COUNT;
+
+const CHAR_BUFFER_SIZE: usize = 1024;
+
+/// Characters typed on each virtual console (while it was the active one). The keyboard
+/// interrupt is the only producer, and consumers hold the console's CHAR_CONSUMERS lock while
+/// they pop.
+pub static CHARS: [RingBuffer<char, CHAR_BUFFER_SIZE>; CONSOLE_COUNT] =
+    [const { RingBuffer::new() }; CONSOLE_COUNT];
+/// Makes sure only one consumer pops a console's CHARS at a time. Never taken by the interrupt.
+pub static CHAR_CONSUMERS: [Mutex<()>; CONSOLE_COUNT] = [const { Mutex::new(()) }; CONSOLE_COUNT];
+pub static CHAR_WAKERS: [AtomicWaker; CONSOLE_COUNT] =
+    [const { AtomicWaker::new() }; CONSOLE_COUNT];
+
 lazy_static! {
-    pub static ref PUSH_BUFFER: Mutex<PushBuffer> = Mutex::new(PushBuffer::new());
-    pub static ref POP_BUFFER: Mutex<PopBuffer> = Mutex::new(PopBuffer::new());
+    pub static ref KEY_EVENTS: IrqMutex<KeyEventQueue> = IrqMutex::new(KeyEventQueue::new());
 }
-pub static POP_WAKER: AtomicWaker = AtomicWaker::new();
+pub static KEY_EVENT_WAKER: AtomicWaker = AtomicWaker::new();
 
 use core::{
     pin::Pin,
@@ -11,35 +26,75 @@ use core::{
 };
 use futures::{stream::Stream, task::AtomicWaker};
 
-pub type PopBuffer = ConcurrentDeque<char>;
-pub type PushBuffer = ConcurrentDeque<char>;
+/// Stream of the characters typed on the console of the running thread (see `thread::console`)
+pub struct CharStream;
 
-pub struct PopBufferStream;
-
-impl PopBufferStream {
+impl CharStream {
     pub fn new() -> Self {
         Self {}
     }
 }
 
-impl Stream for PopBufferStream {
+impl Stream for CharStream {
     type Item = char;
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        if let Some(c) = POP_BUFFER.lock().pop() {
+        let console = thread::console();
+        let _consumer = CHAR_CONSUMERS[console].lock();
+        if let Some(c) = CHARS[console].pop() {
             return Poll::Ready(Some(c));
         }
-        log!(Level::Debug, "registering waker");
-        POP_WAKER.register(&cx.waker());
-        match POP_BUFFER.lock().pop() {
+        CHAR_WAKERS[console].register(cx.waker());
+        match CHARS[console].pop() {
             Some(c) => {
-                log!(Level::Debug, "end registering waker (ready)");
-                POP_WAKER.take();
+                CHAR_WAKERS[console].take();
                 Poll::Ready(Some(c))
             }
-            None => {
-                log!(Level::Debug, "end registering waker (pending)");
-                Poll::Pending
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Key event along with what it typed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub event: KeyEvent,
+    /// Character the key typed with the current layout and modifiers (None for key releases,
+    /// dead keys and keys like arrows that don't type anything)
+    pub character: Option<char>,
+}
+
+const KEY_EVENT_QUEUE_SIZE: usize = 64;
+
+pub type KeyEventQueue = EventQueue<Key, KEY_EVENT_QUEUE_SIZE>;
+
+/// Stream of every key press and release, for apps that need more than characters
+pub struct KeyEventStream;
+
+impl KeyEventStream {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for KeyEventStream {
+    fn default() -> Self {
+        KeyEventStream::new()
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = Key;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(key) = KEY_EVENTS.lock().pop() {
+            return Poll::Ready(Some(key));
+        }
+        KEY_EVENT_WAKER.register(cx.waker());
+        match KEY_EVENTS.lock().pop() {
+            Some(key) => {
+                KEY_EVENT_WAKER.take();
+                Poll::Ready(Some(key))
             }
+            None => Poll::Pending,
         }
     }
 }
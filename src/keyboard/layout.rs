@@ -1,123 +1,492 @@
 use crate::prelude::*;
 
-static UNDEFINED_KEY: char = '?'; // this is a block in code page 437
-
-#[derive(Debug, Clone, Copy)]
-pub enum KeyType {
-    ESC,
-    Ctrl,
-    Shift,
-    ShiftReleased,
-    Alt,
-    CapsLock,
-    ArrowUp,
-    ArrowDown,
-    ArrowLeft,
-    ArrowRight,
-    Ascii,
-    Unknown,
-}
+use crate::keyboard::scancode::{KeyCode, Modifiers};
+
+/// Accents typed by ABNT2 dead keys
+const ACUTE: char = '´';
+const GRAVE: char = '`';
+const TILDE: char = '~';
+const CIRCUMFLEX: char = '^';
+const DIAERESIS: char = '¨';
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Layout {
     Moonlander,
+    /// US QWERTY
+    Us,
+    /// Brazilian ABNT2, with dead keys for accents
+    Abnt2,
+    /// US Dvorak
+    Dvorak,
+}
+
+/// What a key press types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutKey {
+    Char(char),
+    /// Accent that combines with the next character typed (see compose())
+    Dead(char),
 }
 
 pub trait Layoutable {
-    fn to_keytype(&self, scancode: u8) -> KeyType;
-    fn to_ascii(&self, scancode: u8) -> char;
+    /// What the key types with `modifiers`, None for keys that don't type anything (e.g. shift)
+    fn to_key(&self, code: KeyCode, modifiers: &Modifiers) -> Option<LayoutKey>;
+    /// Whether right Alt is AltGr (picks a third character on some keys) rather than Alt
+    fn has_alt_gr(&self) -> bool;
 }
 
 impl Layoutable for Layout {
-    fn to_keytype(&self, scancode: u8) -> KeyType {
+    fn to_key(&self, code: KeyCode, modifiers: &Modifiers) -> Option<LayoutKey> {
+        if let Some(c) = common_to_char(code, modifiers) {
+            return Some(LayoutKey::Char(c));
+        }
+        if code == KeyCode::KeypadPeriod && !modifiers.num_lock {
+            return None; // it's Delete
+        }
         match self {
-            Layout::Moonlander => moonlander_to_keytype(scancode),
+            Layout::Moonlander => {
+                let c = moonlander_to_ascii(code)?;
+                Some(LayoutKey::Char(pick(c, c.to_ascii_uppercase(), modifiers)))
+            }
+            Layout::Us => {
+                let (normal, shifted) = us_to_chars(code)?;
+                Some(LayoutKey::Char(pick(normal, shifted, modifiers)))
+            }
+            Layout::Abnt2 => {
+                if let Some(c) = abnt2_alt_gr_to_char(code).filter(|_| modifiers.alt_gr()) {
+                    return Some(LayoutKey::Char(c));
+                }
+                let (normal, shifted) = abnt2_to_chars(code)?;
+                match pick(normal, shifted, modifiers) {
+                    c @ (ACUTE | GRAVE | TILDE | CIRCUMFLEX | DIAERESIS) => {
+                        Some(LayoutKey::Dead(c))
+                    }
+                    c => Some(LayoutKey::Char(c)),
+                }
+            }
+            Layout::Dvorak => {
+                let (normal, shifted) = dvorak_to_chars(code)?;
+                Some(LayoutKey::Char(pick(normal, shifted, modifiers)))
+            }
         }
     }
-    fn to_ascii(&self, scancode: u8) -> char {
+
+    fn has_alt_gr(&self) -> bool {
+        matches!(self, Layout::Abnt2)
+    }
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [
+        Layout::Moonlander,
+        Layout::Us,
+        Layout::Abnt2,
+        Layout::Dvorak,
+    ];
+
+    pub fn name(&self) -> &'static str {
         match self {
-            Layout::Moonlander => moonlander_to_ascii(scancode),
+            Layout::Moonlander => "moonlander",
+            Layout::Us => "us",
+            Layout::Abnt2 => "abnt2",
+            Layout::Dvorak => "dvorak",
         }
     }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.into_iter().find(|layout| layout.name() == name)
+    }
 }
 
-// MOONLANDER Layout
-fn moonlander_to_keytype(scancode: u8) -> KeyType {
-    log!(
-        Level::Debug,
-        "moonlander_to_keytype called with scancode {scancode}"
-    );
-    let ascii = [
-        02, 03, 04, 05, 06, 07, 08, 09, 10, 11, 14, // 14 == backspace
-        30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17,
-        45, 21, 44, 57, // space
-        28, // newline
-    ];
+/// Picks the shifted or normal character of a key. Caps lock only affects letters.
+fn pick(normal: char, shifted: char, modifiers: &Modifiers) -> char {
+    let shift = match normal.is_alphabetic() {
+        true => modifiers.shift() != modifiers.caps_lock,
+        false => modifiers.shift(),
+    };
+    match shift {
+        true => shifted,
+        false => normal,
+    }
+}
+
+/// Combines a dead key accent with the next character, None if they don't combine
+pub fn compose(accent: char, c: char) -> Option<char> {
+    let composed = match (accent, c) {
+        (ACUTE, 'a') => 'á',
+        (ACUTE, 'e') => 'é',
+        (ACUTE, 'i') => 'í',
+        (ACUTE, 'o') => 'ó',
+        (ACUTE, 'u') => 'ú',
+        (ACUTE, 'A') => 'Á',
+        (ACUTE, 'E') => 'É',
+        (ACUTE, 'I') => 'Í',
+        (ACUTE, 'O') => 'Ó',
+        (ACUTE, 'U') => 'Ú',
+        (GRAVE, 'a') => 'à',
+        (GRAVE, 'e') => 'è',
+        (GRAVE, 'i') => 'ì',
+        (GRAVE, 'o') => 'ò',
+        (GRAVE, 'u') => 'ù',
+        (GRAVE, 'A') => 'À',
+        (GRAVE, 'E') => 'È',
+        (GRAVE, 'I') => 'Ì',
+        (GRAVE, 'O') => 'Ò',
+        (GRAVE, 'U') => 'Ù',
+        (TILDE, 'a') => 'ã',
+        (TILDE, 'o') => 'õ',
+        (TILDE, 'n') => 'ñ',
+        (TILDE, 'A') => 'Ã',
+        (TILDE, 'O') => 'Õ',
+        (TILDE, 'N') => 'Ñ',
+        (CIRCUMFLEX, 'a') => 'â',
+        (CIRCUMFLEX, 'e') => 'ê',
+        (CIRCUMFLEX, 'i') => 'î',
+        (CIRCUMFLEX, 'o') => 'ô',
+        (CIRCUMFLEX, 'u') => 'û',
+        (CIRCUMFLEX, 'A') => 'Â',
+        (CIRCUMFLEX, 'E') => 'Ê',
+        (CIRCUMFLEX, 'I') => 'Î',
+        (CIRCUMFLEX, 'O') => 'Ô',
+        (CIRCUMFLEX, 'U') => 'Û',
+        (DIAERESIS, 'a') => 'ä',
+        (DIAERESIS, 'e') => 'ë',
+        (DIAERESIS, 'i') => 'ï',
+        (DIAERESIS, 'o') => 'ö',
+        (DIAERESIS, 'u') => 'ü',
+        (DIAERESIS, 'y') => 'ÿ',
+        (DIAERESIS, 'A') => 'Ä',
+        (DIAERESIS, 'E') => 'Ë',
+        (DIAERESIS, 'I') => 'Ï',
+        (DIAERESIS, 'O') => 'Ö',
+        (DIAERESIS, 'U') => 'Ü',
+        // typing the space bar after a dead key gives the accent itself
+        (accent, ' ') => accent,
+        _ => return None,
+    };
+    Some(composed)
+}
+
+/// Keys that type the same in every layout
+fn common_to_char(code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+    let c = match code {
+        KeyCode::Escape => '\x1b',
+        KeyCode::Space => ' ',
+        KeyCode::Enter | KeyCode::KeypadEnter => '\n',
+        KeyCode::Backspace => 8 as char, // backspace is ascii 8
+        KeyCode::Tab => '\t',
+        KeyCode::KeypadSlash => '/',
+        KeyCode::KeypadStar => '*',
+        KeyCode::KeypadMinus => '-',
+        KeyCode::KeypadPlus => '+',
+        // the other keypad keys are arrows, home, etc. while num lock is off
+        _ if !modifiers.num_lock => return None,
+        KeyCode::Keypad0 => '0',
+        KeyCode::Keypad1 => '1',
+        KeyCode::Keypad2 => '2',
+        KeyCode::Keypad3 => '3',
+        KeyCode::Keypad4 => '4',
+        KeyCode::Keypad5 => '5',
+        KeyCode::Keypad6 => '6',
+        KeyCode::Keypad7 => '7',
+        KeyCode::Keypad8 => '8',
+        KeyCode::Keypad9 => '9',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// Letters of QWERTY layouts
+fn qwerty_letter(code: KeyCode) -> Option<char> {
+    let c = match code {
+        KeyCode::Q => 'q',
+        KeyCode::W => 'w',
+        KeyCode::E => 'e',
+        KeyCode::R => 'r',
+        KeyCode::T => 't',
+        KeyCode::Y => 'y',
+        KeyCode::U => 'u',
+        KeyCode::I => 'i',
+        KeyCode::O => 'o',
+        KeyCode::P => 'p',
+        KeyCode::A => 'a',
+        KeyCode::S => 's',
+        KeyCode::D => 'd',
+        KeyCode::F => 'f',
+        KeyCode::G => 'g',
+        KeyCode::H => 'h',
+        KeyCode::J => 'j',
+        KeyCode::K => 'k',
+        KeyCode::L => 'l',
+        KeyCode::Z => 'z',
+        KeyCode::X => 'x',
+        KeyCode::C => 'c',
+        KeyCode::V => 'v',
+        KeyCode::B => 'b',
+        KeyCode::N => 'n',
+        KeyCode::M => 'm',
+        _ => return None,
+    };
+    Some(c)
+}
 
-    if ascii.contains(&scancode) {
-        return KeyType::Ascii; // also includes space and newline
+/// Number row of US layouts (QWERTY and Dvorak), without the keys after 0
+fn us_number_row(code: KeyCode) -> Option<(char, char)> {
+    let chars = match code {
+        KeyCode::Backtick => ('`', '~'),
+        KeyCode::Key1 => ('1', '!'),
+        KeyCode::Key2 => ('2', '@'),
+        KeyCode::Key3 => ('3', '#'),
+        KeyCode::Key4 => ('4', '$'),
+        KeyCode::Key5 => ('5', '%'),
+        KeyCode::Key6 => ('6', '^'),
+        KeyCode::Key7 => ('7', '&'),
+        KeyCode::Key8 => ('8', '*'),
+        KeyCode::Key9 => ('9', '('),
+        KeyCode::Key0 => ('0', ')'),
+        _ => return None,
+    };
+    Some(chars)
+}
+
+// US QWERTY Layout
+fn us_to_chars(code: KeyCode) -> Option<(char, char)> {
+    if let Some(c) = qwerty_letter(code) {
+        return Some((c, c.to_ascii_uppercase()));
+    }
+    if let Some(chars) = us_number_row(code) {
+        return Some(chars);
+    }
+    let chars = match code {
+        KeyCode::Minus => ('-', '_'),
+        KeyCode::Equals => ('=', '+'),
+        KeyCode::LeftBracket => ('[', '{'),
+        KeyCode::RightBracket => (']', '}'),
+        KeyCode::Backslash => ('\\', '|'),
+        KeyCode::Semicolon => (';', ':'),
+        KeyCode::Quote => ('\'', '"'),
+        KeyCode::Comma => (',', '<'),
+        KeyCode::Period => ('.', '>'),
+        KeyCode::Slash => ('/', '?'),
+        KeyCode::KeypadPeriod => ('.', '.'),
+        _ => return None,
+    };
+    Some(chars)
+}
+
+// ABNT2 Layout
+fn abnt2_to_chars(code: KeyCode) -> Option<(char, char)> {
+    if let Some(c) = qwerty_letter(code) {
+        return Some((c, c.to_ascii_uppercase()));
     }
+    let chars = match code {
+        KeyCode::Backtick => ('\'', '"'),
+        KeyCode::Key1 => ('1', '!'),
+        KeyCode::Key2 => ('2', '@'),
+        KeyCode::Key3 => ('3', '#'),
+        KeyCode::Key4 => ('4', '$'),
+        KeyCode::Key5 => ('5', '%'),
+        KeyCode::Key6 => ('6', DIAERESIS),
+        KeyCode::Key7 => ('7', '&'),
+        KeyCode::Key8 => ('8', '*'),
+        KeyCode::Key9 => ('9', '('),
+        KeyCode::Key0 => ('0', ')'),
+        KeyCode::Minus => ('-', '_'),
+        KeyCode::Equals => ('=', '+'),
+        KeyCode::LeftBracket => (ACUTE, GRAVE),
+        KeyCode::RightBracket => ('[', '{'),
+        KeyCode::Semicolon => ('ç', 'Ç'),
+        KeyCode::Quote => (TILDE, CIRCUMFLEX),
+        KeyCode::Backslash => (']', '}'),
+        KeyCode::IntlBackslash => ('\\', '|'),
+        KeyCode::Comma => (',', '<'),
+        KeyCode::Period => ('.', '>'),
+        KeyCode::Slash => (';', ':'),
+        KeyCode::IntlRo => ('/', '?'),
+        // the keypad has both, the usual period key types the decimal comma
+        KeyCode::KeypadPeriod => (',', ','),
+        KeyCode::KeypadComma => ('.', '.'),
+        _ => return None,
+    };
+    Some(chars)
+}
+
+/// Letters of the Dvorak layout (keys named after their QWERTY position)
+fn dvorak_letter(code: KeyCode) -> Option<char> {
+    let c = match code {
+        KeyCode::R => 'p',
+        KeyCode::T => 'y',
+        KeyCode::Y => 'f',
+        KeyCode::U => 'g',
+        KeyCode::I => 'c',
+        KeyCode::O => 'r',
+        KeyCode::P => 'l',
+        KeyCode::A => 'a',
+        KeyCode::S => 'o',
+        KeyCode::D => 'e',
+        KeyCode::F => 'u',
+        KeyCode::G => 'i',
+        KeyCode::H => 'd',
+        KeyCode::J => 'h',
+        KeyCode::K => 't',
+        KeyCode::L => 'n',
+        KeyCode::Semicolon => 's',
+        KeyCode::X => 'q',
+        KeyCode::C => 'j',
+        KeyCode::V => 'k',
+        KeyCode::B => 'x',
+        KeyCode::N => 'b',
+        KeyCode::M => 'm',
+        KeyCode::Comma => 'w',
+        KeyCode::Period => 'v',
+        KeyCode::Slash => 'z',
+        _ => return None,
+    };
+    Some(c)
+}
 
-    match scancode {
-        1 => KeyType::ESC,
-        // 14 => KeyType::Backspace, (handled above)
-        29 => KeyType::Ctrl,
-        42 => KeyType::Shift,
-        56 => KeyType::Alt,
-        72 => KeyType::ArrowUp,
-        80 => KeyType::ArrowDown,
-        77 => KeyType::ArrowLeft,
-        75 => KeyType::ArrowRight,
-        170 => KeyType::ShiftReleased,
-        58 => KeyType::CapsLock,
-        _ => KeyType::Unknown,
+/// Characters typed with AltGr on ABNT2
+fn abnt2_alt_gr_to_char(code: KeyCode) -> Option<char> {
+    let c = match code {
+        KeyCode::Key1 => '¹',
+        KeyCode::Key2 => '²',
+        KeyCode::Key3 => '³',
+        KeyCode::Key4 => '£',
+        KeyCode::Key5 => '¢',
+        KeyCode::Key6 => '¬',
+        KeyCode::Equals => '§',
+        KeyCode::Q => '/',
+        KeyCode::W => '?',
+        KeyCode::E => '°',
+        KeyCode::RightBracket => 'ª',
+        KeyCode::Backslash => 'º',
+        _ => return None,
+    };
+    Some(c)
+}
+
+// US Dvorak Layout
+fn dvorak_to_chars(code: KeyCode) -> Option<(char, char)> {
+    if let Some(c) = dvorak_letter(code) {
+        return Some((c, c.to_ascii_uppercase()));
+    }
+    if let Some(chars) = us_number_row(code) {
+        return Some(chars);
     }
+    let chars = match code {
+        KeyCode::Minus => ('[', '{'),
+        KeyCode::Equals => (']', '}'),
+        KeyCode::Q => ('\'', '"'),
+        KeyCode::W => (',', '<'),
+        KeyCode::E => ('.', '>'),
+        KeyCode::LeftBracket => ('/', '?'),
+        KeyCode::RightBracket => ('=', '+'),
+        KeyCode::Backslash => ('\\', '|'),
+        KeyCode::Quote => ('-', '_'),
+        KeyCode::Z => (';', ':'),
+        KeyCode::KeypadPeriod => ('.', '.'),
+        _ => return None,
+    };
+    Some(chars)
 }
 
-fn moonlander_to_ascii(scancode: u8) -> char {
-    log!(
-        Level::Debug,
-        "moonlander_to_ascii called with scancode {scancode}"
-    );
-    match scancode {
-        02 => '1',
-        03 => '2',
-        04 => '3',
-        05 => '4',
-        06 => '5',
-        07 => '6',
-        08 => '7',
-        09 => '8',
-        10 => '9',
-        11 => '0',
-        14 => 8 as char, // backspace (backspace is ascii 8)
-        30 => 'a',
-        48 => 'b',
-        46 => 'c',
-        32 => 'd',
-        18 => 'e',
-        33 => 'f',
-        34 => 'g',
-        35 => 'h',
-        23 => 'i',
-        36 => 'j',
-        37 => 'k',
-        38 => 'l',
-        50 => 'm',
-        49 => 'n',
-        24 => 'o',
-        25 => 'p',
-        16 => 'q',
-        19 => 'r',
-        31 => 's',
-        20 => 't',
-        22 => 'u',
-        47 => 'v',
-        17 => 'w',
-        45 => 'x',
-        21 => 'y',
-        44 => 'z',
-        57 => ' ',
-        28 => '\n',
-        _ => UNDEFINED_KEY,
+// MOONLANDER Layout
+fn moonlander_to_ascii(code: KeyCode) -> Option<char> {
+    log!(Level::Debug, "moonlander_to_ascii called with {code:?}");
+    let ascii = match code {
+        KeyCode::Key1 => '1',
+        KeyCode::Key2 => '2',
+        KeyCode::Key3 => '3',
+        KeyCode::Key4 => '4',
+        KeyCode::Key5 => '5',
+        KeyCode::Key6 => '6',
+        KeyCode::Key7 => '7',
+        KeyCode::Key8 => '8',
+        KeyCode::Key9 => '9',
+        KeyCode::Key0 => '0',
+        code => qwerty_letter(code)?,
+    };
+    Some(ascii)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(layout: Layout, code: KeyCode, shift: bool) -> Option<LayoutKey> {
+        let modifiers = Modifiers {
+            left_shift: shift,
+            ..Modifiers::default()
+        };
+        layout.to_key(code, &modifiers)
+    }
+
+    #[test_case]
+    fn test_us_symbols() {
+        let c = |code, shift| typed(Layout::Us, code, shift);
+        assert_eq!(c(KeyCode::Key2, true), Some(LayoutKey::Char('@')));
+        assert_eq!(c(KeyCode::Semicolon, false), Some(LayoutKey::Char(';')));
+        assert_eq!(c(KeyCode::Semicolon, true), Some(LayoutKey::Char(':')));
+        assert_eq!(c(KeyCode::Quote, true), Some(LayoutKey::Char('"')));
+        assert_eq!(c(KeyCode::A, true), Some(LayoutKey::Char('A')));
+        assert_eq!(c(KeyCode::LeftShift, false), None);
+    }
+
+    #[test_case]
+    fn test_caps_lock_only_affects_letters() {
+        let modifiers = Modifiers {
+            caps_lock: true,
+            ..Modifiers::default()
+        };
+        let us = Layout::Us;
+        assert_eq!(
+            us.to_key(KeyCode::Q, &modifiers),
+            Some(LayoutKey::Char('Q'))
+        );
+        assert_eq!(
+            us.to_key(KeyCode::Key1, &modifiers),
+            Some(LayoutKey::Char('1'))
+        );
+        let abnt2 = Layout::Abnt2;
+        assert_eq!(
+            abnt2.to_key(KeyCode::Semicolon, &modifiers),
+            Some(LayoutKey::Char('Ç'))
+        );
+    }
+
+    #[test_case]
+    fn test_dvorak_letters() {
+        let word: String = [KeyCode::J, KeyCode::D, KeyCode::P, KeyCode::P, KeyCode::S]
+            .iter()
+            .filter_map(|code| match typed(Layout::Dvorak, *code, false) {
+                Some(LayoutKey::Char(c)) => Some(c),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(word, "hello");
+        assert_eq!(
+            typed(Layout::Dvorak, KeyCode::Q, false),
+            Some(LayoutKey::Char('\''))
+        );
+    }
+
+    #[test_case]
+    fn test_abnt2_dead_keys() {
+        assert_eq!(
+            typed(Layout::Abnt2, KeyCode::LeftBracket, false),
+            Some(LayoutKey::Dead(ACUTE))
+        );
+        assert_eq!(
+            typed(Layout::Abnt2, KeyCode::Quote, true),
+            Some(LayoutKey::Dead(CIRCUMFLEX))
+        );
+        assert_eq!(
+            typed(Layout::Abnt2, KeyCode::IntlRo, true),
+            Some(LayoutKey::Char('?'))
+        );
+        assert_eq!(compose(TILDE, 'a'), Some('ã'));
+        assert_eq!(compose(ACUTE, 'E'), Some('É'));
+        assert_eq!(compose(ACUTE, ' '), Some(ACUTE));
+        assert_eq!(compose(TILDE, 'x'), None);
     }
 }
@@ -1,106 +1,293 @@
 use crate::{
+    gdb,
     interrupts::{PICInterrupt, PICS},
     keyboard::{
-        buffer::{PopBufferStream, POP_BUFFER, POP_WAKER, PUSH_BUFFER},
-        layout::{KeyType, Layout, Layoutable},
+        buffer::{CharStream, CHARS, CHAR_CONSUMERS, CHAR_WAKERS, KEY_EVENTS, KEY_EVENT_WAKER},
+        layout::{compose, LayoutKey, Layoutable},
+        scancode::{Decoder, KeyCode, KeyEvent, Modifiers},
     },
     prelude::*,
+    ps2::{self, Leds},
+    thread, vga,
 };
 use futures::stream::StreamExt;
-use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
+use x86_64::{
+    instructions::port::Port,
+    structures::idt::InterruptStackFrame,
+};
 
 mod buffer;
-mod layout;
+pub mod layout;
+pub mod scancode;
+
+pub use buffer::{Key, KeyEventStream};
+pub use layout::Layout;
 
 lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new()); // TODO: add layout to keyboard
+    static ref KEYBOARD: IrqMutex<Keyboard> = IrqMutex::new(Keyboard::new(Layout::Us));
 }
 
 struct Keyboard {
-    shift_pressed: bool,
-    caps_lock_on: bool,
+    decoder: Decoder,
     layout: Layout,
+    /// Accent of the last dead key pressed, waiting for the next character
+    dead_key: Option<char>,
+    /// Progress of the LED update sent to the keyboard, driven by its acks (see update_leds)
+    led_update: LedUpdate,
+    /// A lock key toggled while an LED update was in flight
+    leds_outdated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedUpdate {
+    Idle,
+    /// Sent SET_LEDS, the LED byte goes once it is acknowledged
+    SentCommand,
+    SentLeds,
 }
 
 impl Keyboard {
-    fn new() -> Keyboard {
+    fn new(layout: Layout) -> Keyboard {
         Keyboard {
-            caps_lock_on: false,
-            shift_pressed: false,
-            layout: Layout::Moonlander,
+            decoder: Decoder::new(),
+            layout,
+            dead_key: None,
+            led_update: LedUpdate::Idle,
+            leds_outdated: false,
+        }
+    }
+
+    /// Passes the characters typed by a key press to `output`: none, one, or two when a dead key
+    /// is followed by a character it doesn't combine with. Ctrl turns letters into control
+    /// characters (Ctrl+C is 0x03) and Alt sends ESC before the character.
+    fn type_key(&mut self, event: &KeyEvent, mut output: impl FnMut(char)) {
+        if !event.pressed {
+            return;
         }
+        let modifiers = &event.modifiers;
+        let alt = modifiers.left_alt || (modifiers.right_alt && !self.layout.has_alt_gr());
+        let c = match self.layout.to_key(event.code, modifiers) {
+            Some(LayoutKey::Char(c)) => c,
+            Some(LayoutKey::Dead(accent)) => match self.dead_key.take() {
+                // pressing a dead key twice types the accent
+                Some(previous) if previous == accent => accent,
+                Some(previous) => {
+                    output(previous);
+                    self.dead_key = Some(accent);
+                    return;
+                }
+                None => {
+                    self.dead_key = Some(accent);
+                    return;
+                }
+            },
+            None => return,
+        };
+        let c = match self.dead_key.take() {
+            Some(accent) => match compose(accent, c) {
+                Some(composed) => composed,
+                None => {
+                    output(accent);
+                    c
+                }
+            },
+            None => c,
+        };
+        let c = match modifiers.ctrl() {
+            true => to_control_char(c),
+            false => c,
+        };
+        if alt {
+            output(ESC);
+        }
+        output(c);
     }
 
-    fn caps_lock(&mut self) {
-        self.caps_lock_on = !self.caps_lock_on;
+    fn modifiers(&self) -> Modifiers {
+        self.decoder.modifiers()
     }
 
-    fn shift(&mut self) {
-        self.shift_pressed = true;
+    fn leds(&self) -> Leds {
+        let modifiers = self.modifiers();
+        Leds {
+            scroll_lock: modifiers.scroll_lock,
+            num_lock: modifiers.num_lock,
+            caps_lock: modifiers.caps_lock,
+        }
     }
 
-    fn shift_released(&mut self) {
-        self.shift_pressed = false;
+    /// Starts making the LEDs match the lock keys. Runs in interrupt context, so it doesn't wait
+    /// for the keyboard: handle_led_response() sends the rest as the keyboard answers.
+    fn update_leds(&mut self) {
+        if self.led_update != LedUpdate::Idle {
+            self.leds_outdated = true;
+            return;
+        }
+        self.send_led_byte(ps2::SET_LEDS, LedUpdate::SentCommand);
     }
 
-    // see if we need to uppercase letter
-    fn to_ascii(&self, scancode: u8) -> char {
-        log!(Level::Debug, "to_ascii called with scancode {scancode}");
-        let ascii = self.layout.to_ascii(scancode);
+    fn send_led_byte(&mut self, byte: u8, next: LedUpdate) {
+        self.led_update = match ps2::Controller::new().write_data(byte) {
+            Ok(()) => next,
+            Err(_) => LedUpdate::Idle,
+        };
+    }
 
-        match (self.caps_lock_on, self.shift_pressed) {
-            (true, true) => ascii,
-            (true, false) => ascii.to_ascii_uppercase(),
-            (false, true) => ascii.to_ascii_uppercase(),
-            (false, false) => ascii,
+    /// Handles the keyboard's answer to an LED update. Returns false if `byte` is a scancode.
+    fn handle_led_response(&mut self, byte: u8) -> bool {
+        match (self.led_update, byte) {
+            (LedUpdate::Idle, _) => false,
+            (LedUpdate::SentCommand, ps2::ACK) => {
+                let leds = self.leds().to_byte();
+                self.send_led_byte(leds, LedUpdate::SentLeds);
+                true
+            }
+            (LedUpdate::SentLeds, ps2::ACK) => {
+                self.led_update = LedUpdate::Idle;
+                if self.leds_outdated {
+                    self.leds_outdated = false;
+                    self.update_leds();
+                }
+                true
+            }
+            (_, ps2::RESEND) => {
+                self.led_update = LedUpdate::Idle;
+                self.update_leds();
+                true
+            }
+            // a key pressed before the keyboard answered
+            _ => false,
         }
     }
 }
 
-/// Handles an interrupt for a keyboard event (should not lock VGA since it will likely deadlock)
+const ESC: char = '\x1b';
+
+/// Character typed with Ctrl held: letters and `@[\]^_` become control characters
+fn to_control_char(c: char) -> char {
+    match c.to_ascii_uppercase() {
+        c @ ('@'..='_') => (c as u8 & 0x1f) as char,
+        '?' => 0x7f as char, // DEL
+        _ => c,
+    }
+}
+
+/// Changes the keyboard layout used to turn key presses into characters
+pub fn set_layout(layout: Layout) {
+    log!(Level::Info, "keyboard layout set to {}", layout.name());
+    let mut keyboard = KEYBOARD.lock();
+    keyboard.layout = layout;
+    keyboard.dead_key = None;
+}
+
+/// Keyboard layout in use
+pub fn layout() -> Layout {
+    KEYBOARD.lock().layout
+}
+
+/// Modifier keys held and lock keys on right now
+pub fn modifiers() -> Modifiers {
+    KEYBOARD.lock().modifiers()
+}
+
+/// Handles an interrupt for a keyboard event (only try_lock consoles here, print! is fine since it
+/// queues the text when they're busy)
 pub extern "x86-interrupt" fn keyboard_interrupt(_stack_frame: InterruptStackFrame) {
-    // 1. read the pressed caracter into PUSH_BUFFER
     let scancode: u8 = unsafe {
         let mut port = Port::new(0x60);
         port.read()
     };
+    // typing goes to whoever reads the console on screen
+    let console = vga::active_console();
 
-    let keytype = KEYBOARD.lock().layout.to_keytype(scancode);
-    match keytype {
-        KeyType::Shift => KEYBOARD.lock().shift(),
-        KeyType::ShiftReleased => KEYBOARD.lock().shift_released(),
-        KeyType::CapsLock => KEYBOARD.lock().caps_lock(),
-        KeyType::Ascii => {
-            let ascii = KEYBOARD.lock().to_ascii(scancode);
-            // acquire lock for buffer
-            // put char in buffer
-            let _ = PUSH_BUFFER.lock().push(ascii);
+    {
+        let mut keyboard = KEYBOARD.lock();
+        // multi-byte scancodes only produce an event on their last byte
+        let event = match keyboard.handle_led_response(scancode) {
+            true => None,
+            false => keyboard.decoder.advance(scancode),
+        };
+        if let Some(event) = event {
+            let lock_key = matches!(
+                event.code,
+                KeyCode::CapsLock | KeyCode::NumLock | KeyCode::ScrollLock
+            );
+            if event.pressed && lock_key {
+                keyboard.update_leds();
+            }
+            log!(Level::Debug, "key event {event:?}");
+            if event.pressed && console_shortcut(&event) {
+                return end_of_interrupt();
+            }
+            let mut character = None;
+            keyboard.type_key(&event, |c| {
+                // dropped when full, dropped_chars() counts them
+                CHARS[console].push(c);
+                character = Some(c);
+            });
+            KEY_EVENTS.lock().push(Key { event, character });
         }
-        KeyType::ESC => (),
-        KeyType::Ctrl => (),
-        KeyType::Alt => (),
-        KeyType::ArrowUp => (),
-        KeyType::ArrowDown => (),
-        KeyType::ArrowLeft => (),
-        KeyType::ArrowRight => (),
-        KeyType::Unknown => (), // do nothing
-    }
-    // 2. try to sync PUSH_BUFFER and POP_BUFFER (sometimes we can't cuz POP_BUFFER is locked
-    //    somewhere else)
-    if let Some(mut pop) = POP_BUFFER.try_lock() {
-        ConcurrentDeque::sync(&mut PUSH_BUFFER.lock(), &mut pop);
     }
+    end_of_interrupt();
+    CHAR_WAKERS[console].wake();
+    KEY_EVENT_WAKER.wake();
+}
 
+fn end_of_interrupt() {
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(PICInterrupt::Keyboard as u8)
     };
-    POP_WAKER.wake();
 }
 
-/// Reads one character from the keyboard
+/// Handles the keys taken by the consoles: Alt+F1..F6 switch consoles and Shift+PageUp/PageDown
+/// scroll through the active one's history. Alt+SysRq (PrintScreen) stops in the GDB stub when
+/// it's on. Returns whether `event` was one of them.
+/// Gives up if a console is busy, since the interrupted code may be the one holding it.
+fn console_shortcut(event: &KeyEvent) -> bool {
+    let modifiers = &event.modifiers;
+    let page = vga::BUFFER_HEIGHT / 2;
+    let console = || vga::CONSOLES[vga::active_console()].try_lock();
+    match event.code {
+        KeyCode::PageUp if modifiers.shift() => {
+            if let Some(mut vga) = console() {
+                vga.scroll_back(page);
+            }
+        }
+        KeyCode::PageDown if modifiers.shift() => {
+            if let Some(mut vga) = console() {
+                vga.scroll_forward(page);
+            }
+        }
+        KeyCode::F1 if modifiers.alt() => _ = vga::switch_console(0),
+        KeyCode::F2 if modifiers.alt() => _ = vga::switch_console(1),
+        KeyCode::F3 if modifiers.alt() => _ = vga::switch_console(2),
+        KeyCode::F4 if modifiers.alt() => _ = vga::switch_console(3),
+        KeyCode::F5 if modifiers.alt() => _ = vga::switch_console(4),
+        KeyCode::F6 if modifiers.alt() => _ = vga::switch_console(5),
+        KeyCode::PrintScreen if modifiers.alt() => gdb::request_break(),
+        _ => return false,
+    }
+    true
+}
+
+/// Reads one character typed on the console of the running thread if there is one, without
+/// blocking. Doesn't spin when another reader is popping either, so it can be called in interrupt
+/// context.
+pub fn try_getc() -> Option<char> {
+    let console = thread::console();
+    let _consumer = CHAR_CONSUMERS[console].try_lock()?;
+    CHARS[console].pop()
+}
+
+/// Number of typed characters lost because nobody read them and their console's buffer filled up
+pub fn dropped_chars() -> usize {
+    CHARS.iter().map(|chars| chars.dropped()).sum()
+}
+
+/// Reads one character typed on the console of the running thread
 pub async fn getc() -> char {
-    let mut stream = PopBufferStream::new();
+    let mut stream = CharStream::new();
 
     loop {
         match stream.next().await {
@@ -110,12 +297,24 @@ pub async fn getc() -> char {
     }
 }
 
+/// Waits for the next key event (press or release of any key)
+pub async fn get_key() -> Key {
+    let mut stream = KeyEventStream::new();
+
+    loop {
+        match stream.next().await {
+            Some(key) => return key,
+            None => continue,
+        }
+    }
+}
+
 /// Reads characters from the keyboard buffer into string until \n or is reached. Consumes `\n`.
 /// Returns number of read characters.
 pub async fn scanf(string: &mut String) -> usize {
     let mut len = 0;
 
-    let mut stream = PopBufferStream::new();
+    let mut stream = CharStream::new();
 
     while let Some(c) = stream.next().await {
         if c == 8 as char && len != 0 {
@@ -131,3 +330,57 @@ pub async fn scanf(string: &mut String) -> usize {
     }
     len
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Characters typed by feeding `scancodes` to a keyboard with `layout`
+    fn type_scancodes(layout: Layout, scancodes: &[u8]) -> String {
+        let mut keyboard = Keyboard::new(layout);
+        let mut typed = String::new();
+        for scancode in scancodes {
+            if let Some(event) = keyboard.decoder.advance(*scancode) {
+                keyboard.type_key(&event, |c| typed.push(c));
+            }
+        }
+        typed
+    }
+
+    #[test_case]
+    fn test_shifted_symbols() {
+        // shift, 1, ', shift released, 1
+        let typed = type_scancodes(Layout::Us, &[0x2A, 0x02, 0x28, 0xAA, 0x02]);
+        assert_eq!(typed, "!\"1");
+    }
+
+    #[test_case]
+    fn test_dead_keys() {
+        // ~ a, ´ e, ´ x, ´ space
+        let typed = type_scancodes(
+            Layout::Abnt2,
+            &[0x28, 0x1E, 0x1A, 0x12, 0x1A, 0x2D, 0x1A, 0x39],
+        );
+        assert_eq!(typed, "ãé´x´");
+    }
+
+    #[test_case]
+    fn test_control_chars() {
+        // ctrl+c, ctrl+shift+l, right ctrl+d
+        let typed = type_scancodes(Layout::Us, &[0x1D, 0x2E, 0x2A, 0x26, 0xAA, 0x9D, 0xE0, 0x1D, 0x20]);
+        assert_eq!(typed, "\x03\x0c\x04");
+        // ctrl+c is where c is on the layout
+        let typed = type_scancodes(Layout::Dvorak, &[0x1D, 0x17]);
+        assert_eq!(typed, "\x03");
+    }
+
+    #[test_case]
+    fn test_alt_and_alt_gr() {
+        // left alt+x sends ESC x
+        assert_eq!(type_scancodes(Layout::Us, &[0x38, 0x2D]), "\x1bx");
+        // right alt is alt on US, AltGr on ABNT2
+        assert_eq!(type_scancodes(Layout::Us, &[0xE0, 0x38, 0x10]), "\x1bq");
+        assert_eq!(type_scancodes(Layout::Abnt2, &[0xE0, 0x38, 0x10]), "/");
+        assert_eq!(type_scancodes(Layout::Abnt2, &[0xE0, 0x38, 0x2D]), "x");
+    }
+}
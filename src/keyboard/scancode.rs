@@ -0,0 +1,521 @@
+//! PS/2 scancode set 1 decoding.
+//!
+//! Most keys send one byte when pressed (the make code) and the same byte with bit 7 set when
+//! released (the break code). Keys added after the original XT keyboard are prefixed by `0xE0`,
+//! and Pause sends `E1 1D 45 E1 9D C5` with no break code at all.
+
+#[allow(unused)]
+use crate::prelude::*;
+
+/// Every key of a standard 104-key keyboard, plus the extra keys of ABNT2 and ISO keyboards.
+/// Named after their position on a US keyboard, layouts decide what they type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+
+    LeftShift,
+    /// Key between left shift and Z on ISO and ABNT2 keyboards
+    IntlBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    /// Key left of right shift on ABNT2 keyboards
+    IntlRo,
+    RightShift,
+
+    LeftCtrl,
+    LeftMeta,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightMeta,
+    Menu,
+    RightCtrl,
+
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    ArrowUp,
+    ArrowLeft,
+    ArrowDown,
+    ArrowRight,
+
+    NumLock,
+    KeypadSlash,
+    KeypadStar,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    /// Keypad `.` key of ABNT2 keyboards, the other one types a comma
+    KeypadComma,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+impl KeyCode {
+    /// Key with make code `code` (break bit cleared)
+    fn from_scancode(code: u8) -> Option<KeyCode> {
+        use KeyCode::*;
+        let key = match code {
+            0x01 => Escape,
+            0x02 => Key1,
+            0x03 => Key2,
+            0x04 => Key3,
+            0x05 => Key4,
+            0x06 => Key5,
+            0x07 => Key6,
+            0x08 => Key7,
+            0x09 => Key8,
+            0x0A => Key9,
+            0x0B => Key0,
+            0x0C => Minus,
+            0x0D => Equals,
+            0x0E => Backspace,
+            0x0F => Tab,
+            0x10 => Q,
+            0x11 => W,
+            0x12 => E,
+            0x13 => R,
+            0x14 => T,
+            0x15 => Y,
+            0x16 => U,
+            0x17 => I,
+            0x18 => O,
+            0x19 => P,
+            0x1A => LeftBracket,
+            0x1B => RightBracket,
+            0x1C => Enter,
+            0x1D => LeftCtrl,
+            0x1E => A,
+            0x1F => S,
+            0x20 => D,
+            0x21 => F,
+            0x22 => G,
+            0x23 => H,
+            0x24 => J,
+            0x25 => K,
+            0x26 => L,
+            0x27 => Semicolon,
+            0x28 => Quote,
+            0x29 => Backtick,
+            0x2A => LeftShift,
+            0x2B => Backslash,
+            0x2C => Z,
+            0x2D => X,
+            0x2E => C,
+            0x2F => V,
+            0x30 => B,
+            0x31 => N,
+            0x32 => M,
+            0x33 => Comma,
+            0x34 => Period,
+            0x35 => Slash,
+            0x36 => RightShift,
+            0x37 => KeypadStar,
+            0x38 => LeftAlt,
+            0x39 => Space,
+            0x3A => CapsLock,
+            0x3B => F1,
+            0x3C => F2,
+            0x3D => F3,
+            0x3E => F4,
+            0x3F => F5,
+            0x40 => F6,
+            0x41 => F7,
+            0x42 => F8,
+            0x43 => F9,
+            0x44 => F10,
+            0x45 => NumLock,
+            0x46 => ScrollLock,
+            0x47 => Keypad7,
+            0x48 => Keypad8,
+            0x49 => Keypad9,
+            0x4A => KeypadMinus,
+            0x4B => Keypad4,
+            0x4C => Keypad5,
+            0x4D => Keypad6,
+            0x4E => KeypadPlus,
+            0x4F => Keypad1,
+            0x50 => Keypad2,
+            0x51 => Keypad3,
+            0x52 => Keypad0,
+            0x53 => KeypadPeriod,
+            0x56 => IntlBackslash,
+            0x57 => F11,
+            0x58 => F12,
+            0x73 => IntlRo,
+            0x7E => KeypadComma,
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    /// Key with make code `code` after an `0xE0` prefix (break bit cleared)
+    fn from_extended_scancode(code: u8) -> Option<KeyCode> {
+        use KeyCode::*;
+        let key = match code {
+            0x1C => KeypadEnter,
+            0x1D => RightCtrl,
+            0x35 => KeypadSlash,
+            0x37 => PrintScreen,
+            0x38 => RightAlt,
+            0x46 => Pause, // Ctrl+Pause (Break)
+            0x47 => Home,
+            0x48 => ArrowUp,
+            0x49 => PageUp,
+            0x4B => ArrowLeft,
+            0x4D => ArrowRight,
+            0x4F => End,
+            0x50 => ArrowDown,
+            0x51 => PageDown,
+            0x52 => Insert,
+            0x53 => Delete,
+            0x5B => LeftMeta,
+            0x5C => RightMeta,
+            0x5D => Menu,
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+/// Modifier keys held and lock keys toggled on when an event happened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    /// AltGr on layouts that have it
+    pub right_alt: bool,
+    pub left_meta: bool,
+    pub right_meta: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    pub const fn new() -> Self {
+        Modifiers {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            left_alt: false,
+            right_alt: false,
+            left_meta: false,
+            right_meta: false,
+            caps_lock: false,
+            num_lock: false,
+            scroll_lock: false,
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    /// Either Alt key. Layouts with AltGr only treat the left one as Alt (see Layoutable).
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    pub fn alt_gr(&self) -> bool {
+        self.right_alt
+    }
+
+    pub fn meta(&self) -> bool {
+        self.left_meta || self.right_meta
+    }
+
+    /// Updates the modifiers with a key event
+    fn update(&mut self, code: KeyCode, pressed: bool) {
+        match code {
+            KeyCode::LeftShift => self.left_shift = pressed,
+            KeyCode::RightShift => self.right_shift = pressed,
+            KeyCode::LeftCtrl => self.left_ctrl = pressed,
+            KeyCode::RightCtrl => self.right_ctrl = pressed,
+            KeyCode::LeftAlt => self.left_alt = pressed,
+            KeyCode::RightAlt => self.right_alt = pressed,
+            KeyCode::LeftMeta => self.left_meta = pressed,
+            KeyCode::RightMeta => self.right_meta = pressed,
+            KeyCode::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            KeyCode::NumLock if pressed => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if pressed => self.scroll_lock = !self.scroll_lock,
+            _ => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    /// False when the key was released
+    pub pressed: bool,
+    /// Modifiers right after this event (a shift press comes with shift set)
+    pub modifiers: Modifiers,
+}
+
+const EXTENDED_PREFIX: u8 = 0xE0;
+const PAUSE_PREFIX: u8 = 0xE1;
+const BREAK_BIT: u8 = 0x80;
+/// Sent around some extended keys (e.g. Print Screen) to undo a shift the keyboard thinks is held
+const FAKE_LEFT_SHIFT: u8 = 0x2A;
+const FAKE_RIGHT_SHIFT: u8 = 0x36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    /// Got `0xE0`
+    Extended,
+    /// Got `0xE1` and the bytes after it so far
+    Pause(Option<u8>),
+}
+
+/// Turns scancode set 1 bytes into key events, one byte at a time
+#[derive(Debug)]
+pub struct Decoder {
+    state: State,
+    modifiers: Modifiers,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder {
+            state: State::Start,
+            modifiers: Modifiers::new(),
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Feeds the next byte read from the keyboard. Returns the event it completes, if any.
+    pub fn advance(&mut self, byte: u8) -> Option<KeyEvent> {
+        let (code, pressed) = match (self.state, byte) {
+            (State::Start, EXTENDED_PREFIX) => {
+                self.state = State::Extended;
+                return None;
+            }
+            (State::Start, PAUSE_PREFIX) => {
+                self.state = State::Pause(None);
+                return None;
+            }
+            (State::Start, byte) => (
+                KeyCode::from_scancode(byte & !BREAK_BIT)?,
+                byte & BREAK_BIT == 0,
+            ),
+            (State::Extended, byte) => {
+                self.state = State::Start;
+                if byte & !BREAK_BIT == FAKE_LEFT_SHIFT || byte & !BREAK_BIT == FAKE_RIGHT_SHIFT {
+                    return None;
+                }
+                (
+                    KeyCode::from_extended_scancode(byte & !BREAK_BIT)?,
+                    byte & BREAK_BIT == 0,
+                )
+            }
+            (State::Pause(None), byte) => {
+                self.state = State::Pause(Some(byte));
+                return None;
+            }
+            (State::Pause(Some(first)), second) => {
+                self.state = State::Start;
+                // E1 1D 45 is the "press", E1 9D C5 the "release", both sent on press
+                match (first, second) {
+                    (0x1D, 0x45) => (KeyCode::Pause, true),
+                    (0x9D, 0xC5) => (KeyCode::Pause, false),
+                    _ => return None,
+                }
+            }
+        };
+
+        self.modifiers.update(code, pressed);
+        Some(KeyEvent {
+            code,
+            pressed,
+            modifiers: self.modifiers,
+        })
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<KeyEvent> {
+        let mut decoder = Decoder::new();
+        bytes
+            .iter()
+            .filter_map(|byte| decoder.advance(*byte))
+            .collect()
+    }
+
+    fn keys(bytes: &[u8]) -> Vec<(KeyCode, bool)> {
+        decode(bytes).iter().map(|e| (e.code, e.pressed)).collect()
+    }
+
+    #[test_case]
+    fn test_make_and_break_codes() {
+        assert_eq!(
+            keys(&[0x1E, 0x9E, 0x0C, 0x8C, 0x3B, 0xBB]),
+            [
+                (KeyCode::A, true),
+                (KeyCode::A, false),
+                (KeyCode::Minus, true),
+                (KeyCode::Minus, false),
+                (KeyCode::F1, true),
+                (KeyCode::F1, false),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn test_extended_keys() {
+        assert_eq!(
+            keys(&[0xE0, 0x48, 0xE0, 0xC8, 0xE0, 0x53, 0xE0, 0x1C, 0x1C]),
+            [
+                (KeyCode::ArrowUp, true),
+                (KeyCode::ArrowUp, false),
+                (KeyCode::Delete, true),
+                (KeyCode::KeypadEnter, true),
+                (KeyCode::Enter, true),
+            ]
+        );
+        // without the prefix, 0x48 is keypad 8
+        assert_eq!(keys(&[0x48]), [(KeyCode::Keypad8, true)]);
+    }
+
+    #[test_case]
+    fn test_print_screen_and_pause() {
+        assert_eq!(
+            keys(&[0xE0, 0x2A, 0xE0, 0x37, 0xE0, 0xB7, 0xE0, 0xAA]),
+            [(KeyCode::PrintScreen, true), (KeyCode::PrintScreen, false)]
+        );
+        assert_eq!(
+            keys(&[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5]),
+            [(KeyCode::Pause, true), (KeyCode::Pause, false)]
+        );
+    }
+
+    #[test_case]
+    fn test_modifiers() {
+        let events = decode(&[0x2A, 0x1E, 0xAA, 0x1E, 0xE0, 0x1D, 0x3A, 0xBA, 0x2E]);
+        let modifiers: Vec<Modifiers> = events.iter().map(|e| e.modifiers).collect();
+        assert!(modifiers[0].shift() && modifiers[1].shift());
+        assert!(!modifiers[2].shift() && !modifiers[3].shift());
+        assert!(modifiers[4].right_ctrl && !modifiers[4].left_ctrl && !modifiers[4].caps_lock);
+        // caps lock toggles on press, not on release
+        assert!(modifiers[5].caps_lock && modifiers[6].caps_lock);
+        assert!(modifiers[7].ctrl() && modifiers[7].caps_lock);
+    }
+
+    #[test_case]
+    fn test_left_and_right_modifiers() {
+        // both shifts down, left released: still shifted
+        let events = decode(&[0x2A, 0x36, 0xAA, 0x1E, 0xB6, 0x1E]);
+        assert!(events[3].modifiers.shift() && events[3].modifiers.right_shift);
+        assert!(!events[5].modifiers.shift());
+        // right alt is AltGr, left alt isn't
+        let events = decode(&[0xE0, 0x38, 0x38, 0xE0, 0xB8]);
+        assert!(events[0].modifiers.alt_gr());
+        assert!(events[1].modifiers.alt() && events[1].modifiers.alt_gr());
+        assert!(events[2].modifiers.alt() && !events[2].modifiers.alt_gr());
+    }
+
+    #[test_case]
+    fn test_unknown_codes_are_skipped() {
+        assert_eq!(keys(&[0x55, 0xE0, 0x01, 0x1E]), [(KeyCode::A, true)]);
+    }
+}
@@ -18,26 +18,46 @@ extern crate alloc;
 
 pub mod allocator;
 
+pub mod ansi;
 pub mod apps;
+pub mod elf;
+pub mod framebuffer;
+pub mod gdb;
 pub mod gdt;
+pub mod graphics;
 pub mod interrupts;
 pub mod keyboard;
+pub mod lockdep;
 pub mod logging;
 pub mod memory;
+pub mod mouse;
+pub mod pci;
 pub mod prelude;
+pub mod process;
+pub mod ps2;
 pub mod serial;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod usermode;
 pub mod util;
 pub mod vga;
 
-pub fn init(boot_info: &BootInfo) {
+pub fn init(boot_info: &'static BootInfo) {
     x86_64::instructions::interrupts::disable();
 
+    util::init_cpu_id();
     set_logging_level(Level::Info);
+    vga::init();
     interrupts::init_idt();
     gdt::init_gdt();
+    ps2::init();
+    mouse::init();
     memory::init(boot_info);
-    allocator::init(&boot_info.memory_map);
+    allocator::init();
+    serial::init();
+    gdb::init();
+    thread::init();
 
     x86_64::instructions::interrupts::enable();
 }
@@ -46,6 +66,7 @@ pub fn init(boot_info: &BootInfo) {
 pub fn panic_handler(info: &PanicInfo) -> ! {
     log!(Level::Debug, "normal panic_handler called");
     log!(Level::Error, "{info}");
+    gdb::enter_on_panic();
     exit_qemu(QemuExitCode::Failed);
     hlt_loop()
 }
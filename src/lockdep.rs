@@ -0,0 +1,286 @@
+//! # Lockdep
+//! Debug build checks for the kernel's spin locks, so a deadlock is reported instead of looking
+//! like a frozen VM. `DebugMutex` (which `util::IrqMutex` and `util::Locked` are built on)
+//! remembers where it was locked, on which thread and CPU, and when locking it:
+//! - panics right away if the running thread already holds it (it would spin forever)
+//! - panics after `set_spin_limit` spins, naming the holder
+//! - panics if it has a rank (see `rank`) lower than one already held, when order checks are on
+//!   (locks of the same rank may be held together)
+//!
+//! Reports go straight to the log port (or COM1) before panicking, in case the stuck lock is the
+//! serial port's.
+//! Release builds skip all of this.
+//!
+//! ## Examples
+//! ```
+//! static DATA: IrqMutex<u32> = IrqMutex::ranked(0, lockdep::rank::SCHEDULER);
+//! lockdep::set_order_checks(true);
+//! let data = DATA.lock();
+//! let processes = PROCESSES.lock(); // panics: PROCESSES comes before SCHEDULER
+//! ```
+
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+
+#[allow(unused)]
+use crate::prelude::*;
+use crate::serial::{self, uart::Uart, ComPort};
+use crate::thread::{self, ThreadId};
+
+/// Global lock order: a ranked lock may only be locked while the locks held have lower ranks.
+/// Locks without a rank aren't checked.
+pub mod rank {
+    pub const PROCESSES: u8 = 0;
+    pub const SCHEDULER: u8 = 1;
+    pub const CONSOLE: u8 = 2;
+    pub const PRINT_PRODUCER: u8 = 3;
+    pub const SERIAL: u8 = 4;
+    pub const LOG_LEVEL: u8 = 5;
+}
+
+const RANKS: usize = 8;
+const NO_THREAD: ThreadId = ThreadId::MAX;
+const DEFAULT_SPIN_LIMIT: usize = 100_000_000;
+
+static SPIN_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_SPIN_LIMIT);
+static ORDER_CHECKS: AtomicBool = AtomicBool::new(false);
+/// How many locks of each rank are held. Ranked locks are IrqMutexes, which nobody can preempt,
+/// so these are the locks held by whoever runs (there's a single CPU).
+static HELD: [AtomicU32; RANKS] = [const { AtomicU32::new(0) }; RANKS];
+/// Set while a report is on its way, so the panic handler tripping on a lock doesn't loop
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Spins after which waiting for a lock is reported as a deadlock
+pub fn set_spin_limit(spins: usize) {
+    SPIN_LIMIT.store(spins.max(1), Ordering::Relaxed);
+}
+
+/// Turns checking the lock order (see `rank`) on or off. Off by default.
+pub fn set_order_checks(enabled: bool) {
+    ORDER_CHECKS.store(enabled, Ordering::Relaxed);
+}
+
+/// Who holds a lock
+#[derive(Debug, Clone, Copy)]
+pub struct Holder {
+    pub thread: ThreadId,
+    pub cpu: u32,
+    pub location: &'static Location<'static>,
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "thread {} on CPU {} at {}",
+            self.thread, self.cpu, self.location
+        )
+    }
+}
+
+/// Spin lock that keeps track of its holder in debug builds (see the module docs)
+pub struct DebugMutex<T> {
+    inner: Mutex<T>,
+    rank: Option<u8>,
+    holder_thread: AtomicUsize,
+    holder_cpu: AtomicU32,
+    holder_location: AtomicPtr<Location<'static>>,
+}
+
+pub struct DebugMutexGuard<'a, T> {
+    lock: &'a DebugMutex<T>,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T> DebugMutex<T> {
+    pub const fn new(inner: T) -> Self {
+        DebugMutex {
+            inner: Mutex::new(inner),
+            rank: None,
+            holder_thread: AtomicUsize::new(NO_THREAD),
+            holder_cpu: AtomicU32::new(0),
+            holder_location: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// A lock with a place in the global lock order
+    pub const fn ranked(inner: T, rank: u8) -> Self {
+        assert!((rank as usize) < RANKS, "lock rank out of range");
+        let mut lock = DebugMutex::new(inner);
+        lock.rank = Some(rank);
+        lock
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> DebugMutexGuard<'_, T> {
+        if !cfg!(debug_assertions) {
+            return self.acquired(self.inner.lock(), Location::caller());
+        }
+        let location = Location::caller();
+        if let Some(held) = self.rank.and_then(order_violation) {
+            self.report(
+                location,
+                format_args!("lock order violation: rank {held} is held"),
+            );
+        }
+        let mut spins = 0;
+        loop {
+            if let Some(guard) = self.inner.try_lock() {
+                return self.acquired(guard, location);
+            }
+            if spins == 0 && self.holder_thread.load(Ordering::SeqCst) == thread::current() {
+                self.report(location, format_args!("already held by this thread"));
+            }
+            spins += 1;
+            if spins == SPIN_LIMIT.load(Ordering::Relaxed) {
+                self.report(location, format_args!("still locked after {spins} spins"));
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Never spins, so it never reports either
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<DebugMutexGuard<'_, T>> {
+        let location = Location::caller();
+        self.inner
+            .try_lock()
+            .map(|guard| self.acquired(guard, location))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Who holds the lock, None when it's free (or in release builds)
+    pub fn holder(&self) -> Option<Holder> {
+        let location = self.holder_location.load(Ordering::SeqCst);
+        if location.is_null() {
+            return None;
+        }
+        Some(Holder {
+            thread: self.holder_thread.load(Ordering::SeqCst),
+            cpu: self.holder_cpu.load(Ordering::SeqCst),
+            location: unsafe { &*location },
+        })
+    }
+
+    fn acquired<'a>(
+        &'a self,
+        guard: MutexGuard<'a, T>,
+        location: &'static Location<'static>,
+    ) -> DebugMutexGuard<'a, T> {
+        if cfg!(debug_assertions) {
+            self.holder_thread
+                .store(thread::current(), Ordering::SeqCst);
+            self.holder_cpu.store(cpu_id(), Ordering::SeqCst);
+            self.holder_location
+                .store(location as *const _ as *mut _, Ordering::SeqCst);
+            if let Some(rank) = self.rank {
+                HELD[rank as usize].fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        DebugMutexGuard { lock: self, guard }
+    }
+
+    /// Writes what went wrong locking at `location` to the log port and panics
+    fn report(&self, location: &'static Location<'static>, problem: fmt::Arguments) -> ! {
+        if REPORTING.swap(true, Ordering::SeqCst) {
+            // a lock the panic handler needs is stuck too, nothing more to say
+            crate::exit_qemu(crate::QemuExitCode::Failed);
+            crate::hlt_loop();
+        }
+        let name = core::any::type_name::<T>();
+        let holder = self.holder();
+        let port = serial::log_port().unwrap_or(ComPort::Com1);
+        let mut serial = unsafe { Uart::new(port.base()) };
+        let _ = writeln!(serial, "\nlocking {name} at {location}: {problem}");
+        if let Some(holder) = holder {
+            let _ = writeln!(serial, "held by {holder}");
+        }
+        panic!("locking {name} at {location}: {problem}");
+    }
+}
+
+impl<T> Drop for DebugMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // forget the holder before the inner guard unlocks
+        if cfg!(debug_assertions) {
+            let lock = self.lock;
+            if let Some(rank) = lock.rank {
+                HELD[rank as usize].fetch_sub(1, Ordering::SeqCst);
+            }
+            lock.holder_location
+                .store(ptr::null_mut(), Ordering::SeqCst);
+            lock.holder_thread.store(NO_THREAD, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<T> Deref for DebugMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for DebugMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+/// Highest rank held that locking `rank` would break the order with, if order checks are on.
+/// Locks of the same rank (like the consoles) may be held together.
+fn order_violation(rank: u8) -> Option<u8> {
+    if !ORDER_CHECKS.load(Ordering::Relaxed) {
+        return None;
+    }
+    (rank + 1..RANKS as u8)
+        .rev()
+        .find(|held| HELD[*held as usize].load(Ordering::SeqCst) > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_holder() {
+        let lock = DebugMutex::new(0);
+        assert!(lock.holder().is_none());
+        let guard = lock.lock();
+        let line = line!() - 1;
+        let holder = lock.holder().unwrap();
+        assert_eq!(
+            (holder.location.file(), holder.location.line()),
+            (file!(), line)
+        );
+        assert_eq!(holder.thread, thread::current());
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert!(lock.holder().is_none());
+    }
+
+    #[test_case]
+    fn test_lock_order() {
+        // unused ranks, and no interrupt handler locking the real ones while they are held
+        let first = IrqMutex::ranked((), 6);
+        let second = IrqMutex::ranked((), 7);
+        let _first = first.lock();
+        assert_eq!(order_violation(7), None);
+        set_order_checks(true);
+        assert_eq!(order_violation(7), None);
+        assert_eq!(order_violation(6), None);
+        assert_eq!(order_violation(5), Some(6));
+        let second = second.try_lock();
+        assert_eq!(order_violation(6), Some(7));
+        drop(second);
+        set_order_checks(false);
+        assert_eq!(order_violation(6), None);
+    }
+}
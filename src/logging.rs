@@ -1,51 +1,171 @@
+//! # Logging
+//! Kernel log records go to the serial log port and the log console (see `vga::LOG_CONSOLE`).
+//! Each one is a line with its time since boot, level, CPU, module and source location:
+//! ```text
+//! [   1.25] INFO  cpu0 cruzos::serial src/serial/mod.rs:147: COM1: 38400 8N1
+//! ```
+//! Records above the level set with `set_logging_level` are skipped.
+//!
+//! ## Examples
+//! ```
+//! info!("{} ports found", 2);
+//! log!(Level::Debug, "key event {event:?}");
+//! ```
+
+use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
+
+use crate::interrupts::{self, TIMER_HZ};
+use crate::lockdep::rank;
+use crate::util::{cpu_id, IrqMutex};
 
 lazy_static! {
-    static ref LOG_LEVEL: Mutex<Level> = Mutex::new(Level::Debug);
+    static ref LOG_LEVEL: IrqMutex<Level> = IrqMutex::ranked(Level::Debug, rank::LOG_LEVEL);
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq)]
+/// Module of the record `logf!` left its line open after, for the next one from there to finish
+static OPEN_LINE: IrqMutex<Option<&'static str>> = IrqMutex::new(None);
+
+/// Most to least severe: setting a level shows it and the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
 #[repr(usize)]
 pub enum Level {
     Error = 0,
-    Info = 1,
-    Warning = 2,
+    Warning = 1,
+    Info = 2,
     Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warning => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// A log message and where it comes from
+#[derive(Debug, Clone, Copy)]
+pub struct Record<'a> {
+    pub level: Level,
+    /// Timer ticks since boot (see `interrupts::ticks`)
+    pub ticks: u64,
+    pub cpu: u32,
+    pub module: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+    pub args: fmt::Arguments<'a>,
 }
 
-// TODO: add info! debug! error! etc.
+impl<'a> Record<'a> {
+    /// A record made now, on this CPU
+    pub fn new(
+        level: Level,
+        module: &'static str,
+        file: &'static str,
+        line: u32,
+        args: fmt::Arguments<'a>,
+    ) -> Self {
+        Record {
+            level,
+            ticks: interrupts::ticks(),
+            cpu: cpu_id(),
+            module,
+            file,
+            line,
+            args,
+        }
+    }
+}
 
-/// Prints a log message if level is higher or equal than current log level
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.ticks / TIMER_HZ;
+        let hundredths = self.ticks % TIMER_HZ * 100 / TIMER_HZ;
+        write!(
+            f,
+            "[{seconds:4}.{hundredths:02}] {:<5} cpu{} {} {}:{}: {}",
+            self.level, self.cpu, self.module, self.file, self.line, self.args
+        )
+    }
+}
+
+/// Logs a record if its level is enabled
 /// # Examples
 /// ```
-/// log!(LEVEL::Debug, "formated {} logs", 12);
+/// log!(Level::Debug, "formated {} logs", 12);
 /// ```
 #[macro_export]
 macro_rules! log {
-    ($level:path, $($tt:tt)*) => (
-        if $level <= get_logging_level() {
-            $crate::serial_print!("{}\n", format_args!($($tt)*));
-            // $crate::print!("{}\n", format_args!($($tt)*));
+    ($level:expr, $($tt:tt)*) => (
+        if $crate::logging::enabled($level) {
+            $crate::logging::write(
+                &$crate::logging::Record::new(
+                    $level, module_path!(), file!(), line!(), format_args!($($tt)*),
+                ),
+                true,
+            );
         }
     );
 }
 
-/// Like log, but doesn't add newline to the end of log line
+/// Like log, but leaves the line open: the next record from the same module only adds its
+/// message to it
 /// # Examples
 /// ```
-/// logf!(LEVEL::Debug, "formated {} logs", 12);
+/// logf!(Level::Info, "Setting up IDT...");
+/// log!(Level::Info, "OK");
 /// ```
 #[macro_export]
 macro_rules! logf {
-    ($level:path, $($tt:tt)*) => (
-        if $level <= get_logging_level() {
-            $crate::serial_print!("{}", format_args!($($tt)*));
-            // $crate::print!("{}", format_args!($($tt)*));
+    ($level:expr, $($tt:tt)*) => (
+        if $crate::logging::enabled($level) {
+            $crate::logging::write(
+                &$crate::logging::Record::new(
+                    $level, module_path!(), file!(), line!(), format_args!($($tt)*),
+                ),
+                false,
+            );
         }
     );
 }
 
+#[macro_export]
+macro_rules! error {
+    ($($tt:tt)*) => ($crate::log!($crate::logging::Level::Error, $($tt)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($tt:tt)*) => ($crate::log!($crate::logging::Level::Warning, $($tt)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($tt:tt)*) => ($crate::log!($crate::logging::Level::Info, $($tt)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($tt:tt)*) => ($crate::log!($crate::logging::Level::Debug, $($tt)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($tt:tt)*) => ($crate::log!($crate::logging::Level::Trace, $($tt)*));
+}
+
 pub fn set_logging_level(level: Level) {
     *LOG_LEVEL.lock() = level;
 }
@@ -54,3 +174,62 @@ pub fn get_logging_level() -> Level {
     let level = LOG_LEVEL.lock();
     *level
 }
+
+/// Whether records of `level` are logged
+pub fn enabled(level: Level) -> bool {
+    level <= get_logging_level()
+}
+
+/// Writes a record to the serial log port and the log console, ending its line unless `end_line`
+/// is false (see `logf!`)
+pub fn write(record: &Record, end_line: bool) {
+    let open = core::mem::replace(&mut *OPEN_LINE.lock(), (!end_line).then_some(record.module));
+    let end = if end_line { "\n" } else { "" };
+    match open {
+        Some(module) if module == record.module => print(format_args!("{}{end}", record.args)),
+        // something else got in before the open line was finished
+        Some(_) => print(format_args!("\n{record}{end}")),
+        None => print(format_args!("{record}{end}")),
+    }
+}
+
+fn print(args: fmt::Arguments) {
+    crate::serial::print(args);
+    crate::vga::log_print(args);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn test_records() {
+        assert!(Level::Error < Level::Warning);
+        assert!(Level::Warning < Level::Info);
+        assert!(Level::Debug < Level::Trace);
+
+        let record = Record {
+            level: Level::Warning,
+            ticks: 12345,
+            cpu: 0,
+            module: "cruzos::serial",
+            file: "src/serial/mod.rs",
+            line: 42,
+            args: format_args!("COM{} not found", 3),
+        };
+        assert_eq!(
+            format!("{record}"),
+            "[ 123.45] WARN  cpu0 cruzos::serial src/serial/mod.rs:42: COM3 not found"
+        );
+        let record = Record::new(
+            Level::Info,
+            module_path!(),
+            file!(),
+            line!(),
+            format_args!(""),
+        );
+        assert_eq!(record.module, "cruzos::logging::tests");
+        assert_eq!(record.cpu, cpu_id());
+    }
+}
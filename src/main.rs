@@ -11,7 +11,7 @@ extern crate alloc;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 
-use cruzos::apps::gash::Gash;
+use cruzos::apps::gash::{Gash, Terminal};
 use cruzos::task::simple_executor::SimpleExecutor;
 use cruzos::task::Task;
 
@@ -34,6 +34,21 @@ fn panic(info: &PanicInfo) -> ! {
 
 entry_point!(kernel_main);
 
+/// Task running shell sessions on `terminal`, one after the other
+fn shell_task(terminal: Terminal) -> Task {
+    let shell = Arc::new(Mutex::new(Gash::on(terminal)));
+    // shells get the highest priority so they stay responsive with background tasks running
+    Task::with_priority(
+        async move {
+            // Ctrl+D ends a session, start a new one
+            loop {
+                shell.clone().lock().run().await;
+            }
+        },
+        0,
+    )
+}
+
 async fn example_task(num: u64) {
     log!(Level::Info, "Async number is {num}");
 }
@@ -59,15 +74,24 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_main();
     log!(Level::Info, "\nCruzOS Running!");
 
-    let shell = Arc::new(Mutex::new(Gash::new()));
-
     // show off async capabilities
     let mut executor = SimpleExecutor::new(50);
     // let future1 = example_task(42);
     // let future2 = example_task(43);
-    executor.spawn(Task::new(async move {
-        shell.clone().lock().run().await;
-    }));
+    // a shell on each terminal picked at build time (CRUZOS_SHELL=console|serial|both)
+    for terminal in Terminal::configured() {
+        match terminal {
+            // one on each console but the log one, reading what's typed while it's on screen
+            Terminal::Console => {
+                for console in 0..cruzos::vga::LOG_CONSOLE {
+                    executor.spawn(shell_task(*terminal).on_console(console));
+                }
+            }
+            Terminal::Serial => executor.spawn(shell_task(*terminal)),
+        }
+    }
+    // writes out what was printed while a console was busy
+    executor.spawn(Task::new(cruzos::vga::print_daemon()));
     // executor.spawn(Task::new(future1));
     executor.run();
 
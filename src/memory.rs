@@ -1,24 +1,51 @@
 use crate::prelude::*;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use bootloader::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::instructions::tlb;
-use x86_64::registers::control::Cr3;
+use x86_64::registers::control::{Cr3, Efer, EferFlags};
 use x86_64::structures::paging::{
     frame::PhysFrame,
-    page_table::{FrameError, PageTableEntry, PageTableFlags},
+    page_table::{FrameError, PageTableEntry, PageTableFlags, PageTableIndex},
     Page, PageTable,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::usermode::{USER_SPACE_END, USER_SPACE_START};
+
 lazy_static! {
     // needs to be initialized with BootInfo (see init() fn on this file)
     pub static ref PHYSICAL_MEMORY_OFFSET: Mutex<VirtAddr> = Mutex::new(VirtAddr::new(0));
+    // also initialized in init(), shared by everyone that maps pages so frames are never handed out twice
+    static ref FRAME_ALLOCATOR: Mutex<Option<FrameAllocator<'static>>> = Mutex::new(None);
 }
 
-const PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 4096;
+
+/// Physical address of the layer 4 page table the kernel booted with (set in init()).
+/// Atomic instead of locked since the scheduler reads it in interrupt context.
+static KERNEL_L4_TABLE: AtomicU64 = AtomicU64::new(0);
 
-pub fn init(boot_info: &BootInfo) {
+pub fn init(boot_info: &'static BootInfo) {
     *PHYSICAL_MEMORY_OFFSET.lock() = VirtAddr::new(boot_info.physical_memory_offset);
+    *FRAME_ALLOCATOR.lock() = Some(unsafe { FrameAllocator::new(&boot_info.memory_map) });
+    let (l4_frame, _) = Cr3::read();
+    KERNEL_L4_TABLE.store(l4_frame.start_address().as_u64(), Ordering::SeqCst);
+    // allow PageTableFlags::NO_EXECUTE (otherwise it's a reserved bit)
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+}
+
+/// Frame of the layer 4 page table the kernel booted with
+pub fn kernel_l4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_L4_TABLE.load(Ordering::SeqCst)))
+}
+
+/// Runs `f` with the global frame allocator
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut FrameAllocator<'static>) -> R) -> R {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    f(frame_allocator
+        .as_mut()
+        .expect("memory::init was not called"))
 }
 
 /// Returns the address of the layer 4 page table in virtual memory
@@ -44,6 +71,13 @@ pub unsafe fn active_layer_4_page_table() -> &'static mut PageTable {
 /// Converts an virtual address to a physical one by
 /// traversing the 4 layer page tables
 pub unsafe fn virt2phys(addr: VirtAddr) -> Option<PhysAddr> {
+    // get l4 page table frame
+    let (l4_frame, _) = Cr3::read();
+    translate(l4_frame, addr)
+}
+
+/// Like virt2phys, but in the page tables rooted at `l4_frame` (which need not be active)
+unsafe fn translate(l4_frame: PhysFrame, addr: VirtAddr) -> Option<PhysAddr> {
     let page_table_indexes = [
         addr.p4_index(),
         addr.p3_index(),
@@ -51,8 +85,7 @@ pub unsafe fn virt2phys(addr: VirtAddr) -> Option<PhysAddr> {
         addr.p1_index(),
     ];
 
-    // get l4 page table frame
-    let (mut entry_table_frame, _) = Cr3::read();
+    let mut entry_table_frame = l4_frame;
     let mut page_table;
 
     for idx in page_table_indexes {
@@ -71,6 +104,58 @@ pub unsafe fn virt2phys(addr: VirtAddr) -> Option<PhysAddr> {
     Some(entry_table_frame.start_address() + addr.page_offset().into())
 }
 
+/// Returns the flags of the page containing `addr`, or None if it is not mapped.
+/// Access flags (WRITABLE, USER_ACCESSIBLE) are only returned if every level of the page tables
+/// has them, since that's what the CPU checks.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let page_table_indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    let (mut entry_table_frame, _) = Cr3::read();
+    let mut flags = PageTableFlags::all();
+
+    for idx in page_table_indexes {
+        let page_table = unsafe { frame_to_page_table(entry_table_frame) }; // unsafe: creating reference to raw pointer
+        let entry = &page_table[idx];
+
+        entry_table_frame = match entry.frame() {
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => return None, // we never map huge frames ourselves
+            Ok(frame) => frame,
+        };
+        flags &= entry.flags();
+    }
+
+    Some(flags)
+}
+
+/// Returns true if ring 3 code may access every byte in `[addr, addr + len)`
+pub fn is_user_accessible(addr: VirtAddr, len: u64, writable: bool) -> bool {
+    let end = match addr.as_u64().checked_add(len) {
+        Some(end) if len > 0 => end - 1,
+        Some(_) => return true, // empty range
+        None => return false,
+    };
+    let end = match VirtAddr::try_new(end) {
+        Ok(end) => end,
+        Err(_) => return false,
+    };
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if writable {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let first_page: Page = Page::containing_address(addr);
+    let last_page: Page = Page::containing_address(end);
+    Page::range_inclusive(first_page, last_page)
+        .all(|page| page_flags(page.start_address()).is_some_and(|f| f.contains(required)))
+}
+
 fn to_mapped_mem(phys: PhysAddr) -> VirtAddr {
     *PHYSICAL_MEMORY_OFFSET.lock() + phys.as_u64()
 }
@@ -87,6 +172,9 @@ unsafe fn frame_to_page_table(frame: PhysFrame) -> &'static mut PageTable {
 pub struct FrameAllocator<'frame_life> {
     memory_map: &'frame_life MemoryMap,
     next: usize,
+    /// Last frame given back. Each free frame keeps the address of the one freed before it in its
+    /// first 8 bytes, so the list needs no heap.
+    free: Option<PhysFrame>,
 }
 
 impl<'frame_life> FrameAllocator<'frame_life> {
@@ -94,6 +182,7 @@ impl<'frame_life> FrameAllocator<'frame_life> {
         FrameAllocator {
             memory_map,
             next: 0,
+            free: None,
         }
     }
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + 'frame_life {
@@ -105,30 +194,83 @@ impl<'frame_life> FrameAllocator<'frame_life> {
     }
 
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free {
+            let next: *const u64 = to_mapped_mem(frame.start_address()).as_ptr();
+            let next = unsafe { next.read() };
+            self.free = (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
+
+    /// Gives `frame` back to be allocated again
+    ///
+    /// # Safety
+    /// The frame must come from this allocator and nothing may use it anymore.
+    unsafe fn free_frame(&mut self, frame: PhysFrame) {
+        let next = self.free.map_or(0, |next| next.start_address().as_u64());
+        let link: *mut u64 = to_mapped_mem(frame.start_address()).as_mut_ptr();
+        unsafe { link.write(next) };
+        self.free = Some(frame);
+    }
 }
 
 /// Maps a page (in virtual memory space) to a usable frame (in physical memory space).
 /// Frame to be mapped to page is any usable frame we can find.
 /// We use allocator if we need to create new pages.
 pub fn map_virt(page: Page, flags: PageTableFlags, frame_allocator: &mut FrameAllocator) {
+    let l4 = unsafe { active_layer_4_page_table() };
+    map_in_table(l4, page, flags, frame_allocator);
+    // ensure we're using the newest mapping
+    tlb::flush(page.start_address());
+}
+
+/// Maps a page to a given frame, for memory that isn't RAM (like a framebuffer)
+pub fn map_phys(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut FrameAllocator,
+) {
+    let l4 = unsafe { active_layer_4_page_table() };
+    map_frame_in_table(l4, page, frame, flags, frame_allocator);
+    tlb::flush(page.start_address());
+}
+
+/// Maps `page` to a new frame in the page tables rooted at `l4` and returns the frame
+fn map_in_table(
+    l4: &mut PageTable,
+    page: Page,
+    flags: PageTableFlags,
+    frame_allocator: &mut FrameAllocator,
+) -> PhysFrame {
     let frame = match frame_allocator.allocate_frame() {
         Some(frame) => frame,
         None => panic!("Could not allocate frame"),
     };
-    let l4 = unsafe { active_layer_4_page_table() };
-    let l3 = create_page_table(&mut l4[page.p4_index()], frame_allocator, flags);
-    let l2 = create_page_table(&mut l3[page.p3_index()], frame_allocator, flags);
-    let l1 = create_page_table(&mut l2[page.p2_index()], frame_allocator, flags);
+    map_frame_in_table(l4, page, frame, flags, frame_allocator);
+    frame
+}
+
+fn map_frame_in_table(
+    l4: &mut PageTable,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut FrameAllocator,
+) {
+    // NO_EXECUTE on a higher level table would apply to every page under it
+    let table_flags = flags
+        & (PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+    let l3 = create_page_table(&mut l4[page.p4_index()], frame_allocator, table_flags);
+    let l2 = create_page_table(&mut l3[page.p3_index()], frame_allocator, table_flags);
+    let l1 = create_page_table(&mut l2[page.p2_index()], frame_allocator, table_flags);
     if !l1[page.p1_index()].is_unused() {
         panic!("Page already mapped");
     }
     l1[page.p1_index()].set_frame(frame, flags);
-    // ensure we're using the newest mapping
-    tlb::flush(page.start_address());
 }
 
 /// Unmaps a page (in virtual memory space) back to an unused frame (in physical memory space).
@@ -199,6 +341,149 @@ fn create_page_table(
     page_table
 }
 
+/// Range of layer 4 entries that belong to user space, every other entry is shared by all
+/// address spaces
+fn user_l4_entries() -> core::ops::Range<usize> {
+    let start = VirtAddr::new(USER_SPACE_START).p4_index();
+    let end = VirtAddr::new(USER_SPACE_END).p4_index();
+    usize::from(start)..usize::from(end)
+}
+
+/// Set of page tables with its own user space and the kernel mapped everywhere else.
+///
+/// The kernel half is shared by copying the layer 4 entries of the kernel's page tables, so
+/// kernel mappings made later are only seen if they go under an entry that already existed.
+/// When it's dropped, its user pages and page tables are given back to the frame allocator.
+#[derive(Debug)]
+pub struct AddressSpace {
+    l4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    pub fn new() -> AddressSpace {
+        let l4_frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())
+            .expect("Could not allocate frame");
+        let l4 = unsafe { frame_to_page_table(l4_frame) };
+        l4.zero();
+
+        let kernel_l4 = unsafe { frame_to_page_table(kernel_l4_frame()) };
+        let user_entries = user_l4_entries();
+        for (i, entry) in kernel_l4.iter().enumerate() {
+            if !user_entries.contains(&i) {
+                l4[PageTableIndex::new(i as u16)] = entry.clone();
+            }
+        }
+        AddressSpace { l4_frame }
+    }
+
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.l4_frame
+    }
+
+    fn l4(&self) -> &'static mut PageTable {
+        unsafe { frame_to_page_table(self.l4_frame) }
+    }
+
+    /// Maps `page` to a new zeroed frame. Only user space pages can be mapped.
+    pub fn map(&self, page: Page, flags: PageTableFlags) -> Result<()> {
+        let addr = page.start_address().as_u64();
+        if !(USER_SPACE_START..USER_SPACE_END).contains(&addr) {
+            return err!("{:?} is outside user space", page.start_address());
+        }
+        if self.translate(page.start_address()).is_some() {
+            return err!("{:?} is already mapped", page.start_address());
+        }
+        let frame = with_frame_allocator(|frame_allocator| {
+            map_in_table(self.l4(), page, flags, frame_allocator)
+        });
+        let frame_ptr: *mut u8 = to_mapped_mem(frame.start_address()).as_mut_ptr();
+        unsafe { core::ptr::write_bytes(frame_ptr, 0, PAGE_SIZE) };
+        Ok(())
+    }
+
+    /// Translates `addr` to a physical address in this address space
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        unsafe { translate(self.l4_frame, addr) }
+    }
+
+    /// Copies `bytes` to `addr` in this address space (which need not be active).
+    /// Writes through the physical memory mapping, so page flags don't matter.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> Result<()> {
+        let mut written = 0;
+        while written < bytes.len() {
+            let dest = addr + written as u64;
+            let phys = match self.translate(dest) {
+                Some(phys) => phys,
+                None => return err!("{:?} is not mapped", dest),
+            };
+            // copy up to the end of the page
+            let len = (PAGE_SIZE - usize::from(dest.page_offset())).min(bytes.len() - written);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[written..].as_ptr(),
+                    to_mapped_mem(phys).as_mut_ptr(),
+                    len,
+                );
+            }
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Switches to this address space
+    ///
+    /// # Safety
+    /// The running code and its stack must be mapped in this address space (the kernel always is).
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.l4_frame, flags);
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        AddressSpace::new()
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if Cr3::read().0 == self.l4_frame {
+            // only when the last reference goes away on a thread still in it
+            let (_, flags) = Cr3::read();
+            unsafe { Cr3::write(kernel_l4_frame(), flags) };
+        }
+        // every frame under the user entries was allocated by map, the rest belong to the kernel
+        let l4 = self.l4();
+        with_frame_allocator(|frame_allocator| {
+            for l4_entry in user_l4_entries().map(|i| &l4[PageTableIndex::new(i as u16)]) {
+                let Ok(l3_frame) = l4_entry.frame() else {
+                    continue;
+                };
+                for l3_entry in unsafe { frame_to_page_table(l3_frame) }.iter() {
+                    let Ok(l2_frame) = l3_entry.frame() else {
+                        continue;
+                    };
+                    for l2_entry in unsafe { frame_to_page_table(l2_frame) }.iter() {
+                        let Ok(l1_frame) = l2_entry.frame() else {
+                            continue;
+                        };
+                        for l1_entry in unsafe { frame_to_page_table(l1_frame) }.iter() {
+                            if let Ok(frame) = l1_entry.frame() {
+                                unsafe { frame_allocator.free_frame(frame) };
+                            }
+                        }
+                        unsafe { frame_allocator.free_frame(l1_frame) };
+                    }
+                    unsafe { frame_allocator.free_frame(l2_frame) };
+                }
+                unsafe { frame_allocator.free_frame(l3_frame) };
+            }
+            unsafe { frame_allocator.free_frame(self.l4_frame) };
+        });
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -210,4 +495,19 @@ mod tests {
         let actual = unsafe { virt2phys(VirtAddr::new(0xb8000)).unwrap() };
         assert_eq!(actual, expected);
     }
+
+    #[test_case]
+    fn test_address_space_frames_reused() {
+        let address_space = AddressSpace::new();
+        let page = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        address_space.map(page, flags).unwrap();
+        let l4_frame = address_space.l4_frame();
+        drop(address_space);
+        // the layer 4 table is given back last, so it's the first one allocated again
+        let address_space = AddressSpace::new();
+        assert_eq!(address_space.l4_frame(), l4_frame);
+        address_space.map(page, flags).unwrap();
+        assert!(address_space.translate(page.start_address()).is_some());
+    }
 }
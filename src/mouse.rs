@@ -0,0 +1,272 @@
+//! # Mouse
+//! PS/2 mouse driver. The mouse sits on the second port of the PS/2 controller (see `ps2`) and
+//! sends a packet on IRQ12 whenever it moves or a button changes: 3 bytes, or 4 when it has a
+//! scroll wheel (IntelliMouse extension).
+//!
+//! ## Examples
+//! ```
+//! let mut events = mouse::MouseEventStream::new();
+//! while let Some(event) = events.next().await {
+//!     log!(Level::Info, "moved {} {}", event.dx, event.dy);
+//! }
+//! ```
+
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures::{stream::Stream, task::AtomicWaker};
+use x86_64::{
+    instructions::{interrupts::without_interrupts, port::Port},
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::interrupts::{PICInterrupt, PICS};
+#[allow(unused)]
+use crate::prelude::*;
+use crate::ps2::{self, Controller};
+
+// mouse commands
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const GET_ID: u8 = 0xF2;
+const SET_DEFAULTS: u8 = 0xF6;
+const ENABLE_REPORTING: u8 = 0xF4;
+
+/// Id the mouse reports once the wheel is enabled
+const WHEEL_MOUSE_ID: u8 = 3;
+/// Setting these sample rates in a row enables the wheel on IntelliMouse compatible mice
+const WHEEL_KNOCK: [u8; 3] = [200, 100, 80];
+
+// first byte of a packet
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+/// Always set, used to find the start of a packet
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+const MOUSE_EVENT_QUEUE_SIZE: usize = 64;
+
+/// Set by init() once the mouse sends packets
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref DECODER: IrqMutex<PacketDecoder> = IrqMutex::new(PacketDecoder::new(false));
+    static ref EVENTS: IrqMutex<EventQueue<MouseEvent, MOUSE_EVENT_QUEUE_SIZE>> =
+        IrqMutex::new(EventQueue::new());
+}
+static EVENT_WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Movement since the last event and the buttons held
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Positive to the right
+    pub dx: i16,
+    /// Positive upwards
+    pub dy: i16,
+    /// Positive when scrolling down, always 0 on mice without a wheel
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+/// Assembles mouse packets one byte at a time
+#[derive(Debug)]
+struct PacketDecoder {
+    bytes: [u8; 4],
+    received: usize,
+    has_wheel: bool,
+}
+
+impl PacketDecoder {
+    const fn new(has_wheel: bool) -> Self {
+        PacketDecoder {
+            bytes: [0; 4],
+            received: 0,
+            has_wheel,
+        }
+    }
+
+    fn packet_size(&self) -> usize {
+        match self.has_wheel {
+            true => 4,
+            false => 3,
+        }
+    }
+
+    /// Feeds the next byte from the mouse. Returns the event it completes, if any.
+    fn advance(&mut self, byte: u8) -> Option<MouseEvent> {
+        // a lost byte would shift every packet after it, so wait for a valid first byte
+        if self.received == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_size() {
+            return None;
+        }
+        self.received = 0;
+
+        let flags = self.bytes[0];
+        // movement is a 9 bit two's complement number, the sign bit being in the flags
+        let movement = |value: u8, sign: u8, overflow: u8| -> i16 {
+            if flags & overflow != 0 {
+                return 0; // garbage
+            }
+            match flags & sign != 0 {
+                true => value as i16 - 0x100,
+                false => value as i16,
+            }
+        };
+        let wheel = match self.has_wheel {
+            // 4 bit two's complement
+            true => ((self.bytes[3] << 4) as i8) >> 4,
+            false => 0,
+        };
+        Some(MouseEvent {
+            dx: movement(self.bytes[1], X_SIGN, X_OVERFLOW),
+            dy: movement(self.bytes[2], Y_SIGN, Y_OVERFLOW),
+            wheel,
+            buttons: MouseButtons {
+                left: flags & LEFT_BUTTON != 0,
+                right: flags & RIGHT_BUTTON != 0,
+                middle: flags & MIDDLE_BUTTON != 0,
+            },
+        })
+    }
+}
+
+/// Enables the wheel if the mouse has one. Returns whether it does.
+fn enable_wheel(controller: &mut Controller) -> Result<bool> {
+    for rate in WHEEL_KNOCK {
+        controller.send_mouse(SET_SAMPLE_RATE)?;
+        controller.send_mouse(rate)?;
+    }
+    controller.send_mouse(GET_ID)?;
+    Ok(controller.read_data()? == WHEEL_MOUSE_ID)
+}
+
+fn enable() -> Result<bool> {
+    let mut controller = Controller::new();
+    controller.send_mouse(SET_DEFAULTS)?;
+    let has_wheel = enable_wheel(&mut controller)?;
+    controller.send_mouse(ENABLE_REPORTING)?;
+    Ok(has_wheel)
+}
+
+/// Starts receiving mouse packets. Call after ps2::init().
+pub fn init() {
+    if !ps2::has_second_port() {
+        return;
+    }
+    logf!(Level::Info, "Setting up mouse...");
+    // the mouse interrupt would eat the answers otherwise
+    match without_interrupts(enable) {
+        Ok(has_wheel) => {
+            *DECODER.lock() = PacketDecoder::new(has_wheel);
+            ENABLED.store(true, Ordering::SeqCst);
+            log!(Level::Info, "OK (wheel: {has_wheel})");
+        }
+        Err(e) => log!(Level::Error, "failed: {e}"),
+    }
+}
+
+/// Whether a mouse was found and sends events
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Handles IRQ12, one byte of a mouse packet
+pub extern "x86-interrupt" fn mouse_interrupt(_stack_frame: InterruptStackFrame) {
+    let byte: u8 = unsafe { Port::new(0x60).read() };
+
+    if let Some(event) = DECODER.lock().advance(byte) {
+        EVENTS.lock().push(event);
+    }
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PICInterrupt::Mouse as u8)
+    };
+    EVENT_WAKER.wake();
+}
+
+/// Stream of mouse movements and button changes
+pub struct MouseEventStream;
+
+impl MouseEventStream {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for MouseEventStream {
+    fn default() -> Self {
+        MouseEventStream::new()
+    }
+}
+
+impl Stream for MouseEventStream {
+    type Item = MouseEvent;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(event) = EVENTS.lock().pop() {
+            return Poll::Ready(Some(event));
+        }
+        EVENT_WAKER.register(cx.waker());
+        match EVENTS.lock().pop() {
+            Some(event) => {
+                EVENT_WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(has_wheel: bool, bytes: &[u8]) -> Vec<MouseEvent> {
+        let mut decoder = PacketDecoder::new(has_wheel);
+        bytes.iter().filter_map(|byte| decoder.advance(*byte)).collect()
+    }
+
+    #[test_case]
+    fn test_three_byte_packets() {
+        let events = decode(false, &[0x09, 5, 3, 0x38, 0xFE, 0xF0]);
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].dx, events[0].dy), (5, 3));
+        assert!(events[0].buttons.left && !events[0].buttons.right);
+        // both sign bits set
+        assert_eq!((events[1].dx, events[1].dy), (-2, -16));
+        assert_eq!(events[1].buttons, MouseButtons::default());
+    }
+
+    #[test_case]
+    fn test_wheel_packets() {
+        let events = decode(true, &[0x0C, 0, 0, 0x01, 0x08, 0, 0, 0x0F]);
+        assert_eq!(events.len(), 2);
+        assert!(events[0].buttons.middle);
+        assert_eq!(events[0].wheel, 1);
+        assert_eq!(events[1].wheel, -1);
+    }
+
+    #[test_case]
+    fn test_resync_and_overflow() {
+        // a stray byte without bit 3 is skipped, overflowed movement is dropped
+        let events = decode(false, &[0x00, 0x48, 0xFF, 7]);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].dx, events[0].dy), (0, 7));
+    }
+}
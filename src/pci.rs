@@ -0,0 +1,125 @@
+//! # PCI
+//! Reads the configuration space of PCI devices through the legacy configuration ports
+//! (mechanism #1), enough to find a device and where its memory is.
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+#[allow(unused)]
+use crate::prelude::*;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// configuration space registers (offsets)
+const VENDOR_ID: u8 = 0x00;
+const HEADER_TYPE: u8 = 0x0C;
+const BAR0: u8 = 0x10;
+
+/// Vendor id read from slots without a device
+const NO_DEVICE: u16 = 0xFFFF;
+/// Set in the header type when the device has more than one function
+const MULTI_FUNCTION: u8 = 1 << 7;
+/// Low bits of a memory BAR, which aren't part of the address
+const BAR_FLAGS_MASK: u32 = 0xF;
+const BAR_IO_SPACE: u32 = 1 << 0;
+/// Bits 2:1 of a memory BAR, where it may be placed
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+/// The BAR is 64 bits wide, the next one has the high half of the address
+const BAR_TYPE_64: u32 = 0b10 << 1;
+
+/// Bus, device and function of a PCI function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Location {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Location {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Reads the 32 bit register at `offset` (rounded down to a multiple of 4)
+    pub fn read(&self, offset: u8) -> u32 {
+        let address = CONFIG_ENABLE
+            | (self.bus as u32) << 16
+            | (self.device as u32 & 0x1F) << 11
+            | (self.function as u32 & 0x7) << 8
+            | (offset as u32 & 0xFC);
+        // the address and data ports have to be used in a row
+        without_interrupts(|| unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS_PORT).write(address);
+            Port::<u32>::new(CONFIG_DATA_PORT).read()
+        })
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read(VENDOR_ID) as u16
+    }
+
+    pub fn device_id(&self) -> u16 {
+        (self.read(VENDOR_ID) >> 16) as u16
+    }
+
+    fn header_type(&self) -> u8 {
+        (self.read(HEADER_TYPE) >> 16) as u8
+    }
+
+    /// Physical address of a memory Base Address Register, None for I/O space BARs. A 64-bit BAR
+    /// takes `index` and the one after it.
+    pub fn memory_bar(&self, index: u8) -> Option<u64> {
+        let bar = self.read(BAR0 + 4 * index);
+        if bar & BAR_IO_SPACE != 0 {
+            return None;
+        }
+        let low = (bar & !BAR_FLAGS_MASK) as u64;
+        match bar & BAR_TYPE_MASK {
+            BAR_TYPE_64 => Some(low | (self.read(BAR0 + 4 * (index + 1)) as u64) << 32),
+            _ => Some(low),
+        }
+    }
+}
+
+/// Every function that answers, in bus order
+pub fn devices() -> impl Iterator<Item = Location> {
+    (0..=255u8).flat_map(|bus| {
+        (0..32u8).flat_map(move |device| {
+            let first = Location::new(bus, device, 0);
+            let functions = match first.vendor_id() {
+                NO_DEVICE => 0,
+                _ if first.header_type() & MULTI_FUNCTION != 0 => 8,
+                _ => 1,
+            };
+            (0..functions)
+                .map(move |function| Location::new(bus, device, function))
+                .filter(|location| location.vendor_id() != NO_DEVICE)
+        })
+    })
+}
+
+/// First function with the given vendor and device ids
+pub fn find(vendor_id: u16, device_id: u16) -> Option<Location> {
+    devices()
+        .find(|location| location.vendor_id() == vendor_id && location.device_id() == device_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_host_bridge() {
+        // every PC has a host bridge at 0:0.0
+        let host_bridge = devices().next().unwrap();
+        assert_eq!(host_bridge, Location::new(0, 0, 0));
+        let found = find(host_bridge.vendor_id(), host_bridge.device_id());
+        assert_eq!(found, Some(host_bridge));
+    }
+}
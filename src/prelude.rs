@@ -2,7 +2,10 @@ pub use crate::err;
 pub use crate::keyboard::scanf;
 pub use crate::logging::{get_logging_level, set_logging_level, Level};
 pub use crate::vga::stdout;
-pub use crate::{log, logf, print, println, serial_print, serial_println, util::*};
+pub use crate::{
+    debug, error, info, log, logf, print, println, serial_print, serial_println, trace, util::*,
+    warn,
+};
 pub use alloc::{boxed::Box, format, string::String, vec::Vec};
 pub use core::{error::Error, fmt::Write, result};
 pub use lazy_static::lazy_static;
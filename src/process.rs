@@ -0,0 +1,312 @@
+//! # Processes
+//! A process is an ELF program running in ring 3 on its own thread and in its own address space.
+//! The process table keeps every process until its parent (or anyone else) waits for it, so a
+//! finished process stays around as a zombie holding its exit code.
+//!
+//! ## Examples
+//! ```
+//! let pid = process::spawn("hello", embedded::find("hello").unwrap(), &["hello"], &[])?;
+//! let exit_code = process::wait(pid)?;
+//! ```
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::elf::{loader, Elf};
+use crate::lockdep::rank;
+use crate::memory::AddressSpace;
+#[allow(unused)]
+use crate::prelude::*;
+use crate::task;
+use crate::thread::{self, JoinHandle, State, ThreadId};
+use crate::usermode;
+
+pub type Pid = usize;
+
+/// Pid of the kernel, the parent of processes spawned by kernel code and of orphans
+pub const KERNEL_PID: Pid = 0;
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(KERNEL_PID + 1);
+
+/// Exit code of processes ended with kill()
+pub const KILLED_EXIT_CODE: i64 = -9;
+
+/// Index in a process' handle table, what read and write syscalls take
+pub type Fd = usize;
+
+pub const STDIN: Fd = 0;
+pub const STDOUT: Fd = 1;
+pub const STDERR: Fd = 2;
+
+/// Maximum number of handles a process can have open
+pub const MAX_HANDLES: usize = 16;
+
+/// Something a process can read from or write to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handle {
+    Keyboard,
+    Console,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// Sleeping or waiting for another thread
+    Blocked,
+    /// Finished with this exit code, but nobody waited for it yet
+    Zombie(i64),
+}
+
+impl fmt::Display for ProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessState::Running => write!(f, "running"),
+            ProcessState::Blocked => write!(f, "blocked"),
+            ProcessState::Zombie(exit_code) => write!(f, "zombie ({exit_code})"),
+        }
+    }
+}
+
+struct Process {
+    parent: Pid,
+    name: String,
+    /// None until spawn() starts the thread
+    thread: Option<ThreadId>,
+    /// Taken by wait() while it waits for the thread
+    join_handle: Option<JoinHandle>,
+    /// Keeps the address space alive as long as the process, its frames are given back when the
+    /// thread's reference is dropped too
+    #[allow(unused)]
+    address_space: Arc<AddressSpace>,
+    handles: [Option<Handle>; MAX_HANDLES],
+}
+
+impl Process {
+    fn state(&self) -> ProcessState {
+        let thread = match self.thread {
+            Some(thread) => thread,
+            None => return ProcessState::Running,
+        };
+        match thread::state(thread) {
+            Some(State::Ready) => ProcessState::Running,
+            Some(State::Sleeping(_)) | Some(State::Joining(_)) => ProcessState::Blocked,
+            Some(State::Finished) | None => {
+                ProcessState::Zombie(thread::exit_code(thread).unwrap_or(0))
+            }
+        }
+    }
+}
+
+/// Snapshot of a process, as listed by list()
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: String,
+    pub state: ProcessState,
+}
+
+lazy_static! {
+    // an IrqMutex so syscalls can use it too
+    static ref PROCESSES: IrqMutex<BTreeMap<Pid, Process>> =
+        IrqMutex::ranked(BTreeMap::new(), rank::PROCESSES);
+}
+
+/// Handles every process starts with
+fn default_handle(fd: Fd) -> Option<Handle> {
+    match fd {
+        STDIN => Some(Handle::Keyboard),
+        STDOUT | STDERR => Some(Handle::Console),
+        _ => None,
+    }
+}
+
+/// Loads an ELF executable and runs it as a new process, child of the calling one.
+/// `argv` and `envp` are put on its stack, `name` is what list() shows.
+pub fn spawn(name: &str, bytes: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid> {
+    let elf = Elf::parse(bytes)?;
+    let program = loader::load(&elf, argv, envp)?;
+    let (entry, stack_pointer) = (program.entry, program.stack_pointer);
+    let address_space = Arc::new(program.address_space);
+
+    let pid = NEXT_PID.fetch_add(1, Ordering::SeqCst);
+    let parent = current();
+    let mut handles = [None; MAX_HANDLES];
+    for (fd, handle) in handles.iter_mut().enumerate() {
+        *handle = default_handle(fd);
+    }
+    // in the table before the thread starts, so its syscalls always find it
+    PROCESSES.lock().insert(
+        pid,
+        Process {
+            parent,
+            name: String::from(name),
+            thread: None,
+            join_handle: None,
+            address_space: address_space.clone(),
+            handles,
+        },
+    );
+
+    log!(Level::Debug, "spawning process {pid} ({name})");
+    let join_handle = thread::spawn_in(address_space, move || {
+        // the thread may start before spawn() gets to store its id
+        if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+            process.thread = Some(thread::current());
+        }
+        unsafe { usermode::enter_user_mode(entry, stack_pointer, 0) }
+    });
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&pid).unwrap();
+    process.thread = Some(join_handle.id());
+    process.join_handle = Some(join_handle);
+    Ok(pid)
+}
+
+/// Blocks until process `pid` finishes, removes it from the table and returns its exit code
+pub fn wait(pid: Pid) -> Result<i64> {
+    let join_handle = take_join_handle(pid)?;
+    Ok(reap(pid, join_handle))
+}
+
+/// Like wait, but for async tasks: yields to the executor until the process finishes instead of
+/// blocking the thread that runs every other task
+pub async fn wait_async(pid: Pid) -> Result<i64> {
+    let join_handle = take_join_handle(pid)?;
+    while !join_handle.is_finished() {
+        task::yield_now().await;
+    }
+    Ok(reap(pid, join_handle))
+}
+
+fn take_join_handle(pid: Pid) -> Result<JoinHandle> {
+    let join_handle = match PROCESSES.lock().get_mut(&pid) {
+        Some(process) => process.join_handle.take(),
+        None => return err!("no process with pid {pid}"),
+    };
+    match join_handle {
+        Some(join_handle) => Ok(join_handle),
+        None => err!("process {pid} is already being waited for"),
+    }
+}
+
+/// Joins the thread of process `pid`, removes it from the table and returns its exit code
+fn reap(pid: Pid, join_handle: JoinHandle) -> i64 {
+    let exit_code = join_handle.join();
+    let mut processes = PROCESSES.lock();
+    for child in processes.values_mut().filter(|p| p.parent == pid) {
+        child.parent = KERNEL_PID;
+    }
+    let process = processes.remove(&pid);
+    drop(processes);
+    // dropped here rather than under the lock
+    drop(process);
+    exit_code
+}
+
+/// Ends process `pid` with KILLED_EXIT_CODE. It stays a zombie until someone waits for it.
+pub fn kill(pid: Pid) -> Result<()> {
+    let thread = PROCESSES.lock().get(&pid).map(|p| p.thread);
+    match thread {
+        None => err!("no process with pid {pid}"),
+        Some(None) => err!("process {pid} is still starting"),
+        Some(Some(thread)) => match thread::kill(thread, KILLED_EXIT_CODE) {
+            true => Ok(()),
+            false => err!("process {pid} already exited"),
+        },
+    }
+}
+
+/// Pid of the process the running thread belongs to, KERNEL_PID for kernel threads
+pub fn current() -> Pid {
+    let thread = thread::current();
+    PROCESSES
+        .lock()
+        .iter()
+        .find(|(_, p)| p.thread == Some(thread))
+        .map_or(KERNEL_PID, |(pid, _)| *pid)
+}
+
+/// Parent of process `pid`
+pub fn parent(pid: Pid) -> Option<Pid> {
+    PROCESSES.lock().get(&pid).map(|p| p.parent)
+}
+
+/// Handle `fd` of the running process. Threads that aren't processes get the default handles.
+pub fn handle(fd: Fd) -> Option<Handle> {
+    let thread = thread::current();
+    match PROCESSES.lock().values().find(|p| p.thread == Some(thread)) {
+        Some(process) => process.handles.get(fd).copied().flatten(),
+        None => default_handle(fd),
+    }
+}
+
+/// Closes handle `fd` of the running process
+pub fn close(fd: Fd) -> Result<()> {
+    let thread = thread::current();
+    let mut processes = PROCESSES.lock();
+    let process = match processes.values_mut().find(|p| p.thread == Some(thread)) {
+        Some(process) => process,
+        None => return err!("thread {thread} is not a process"),
+    };
+    match process.handles.get_mut(fd).and_then(|handle| handle.take()) {
+        Some(_) => Ok(()),
+        None => err!("bad file descriptor {fd}"),
+    }
+}
+
+/// Lists every process in the table, zombies included
+pub fn list() -> Vec<ProcessInfo> {
+    PROCESSES
+        .lock()
+        .iter()
+        .map(|(pid, process)| ProcessInfo {
+            pid: *pid,
+            parent: process.parent,
+            name: process.name.clone(),
+            state: process.state(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apps::embedded;
+
+    fn spawn_hello(argv: &[&str]) -> Pid {
+        spawn("hello", embedded::find("hello").unwrap(), argv, &[]).unwrap()
+    }
+
+    #[test_case]
+    fn test_spawn_wait() {
+        let pid = spawn_hello(&["hello", "world"]);
+        let info = list().into_iter().find(|p| p.pid == pid).unwrap();
+        assert_eq!(info.parent, KERNEL_PID);
+        assert_eq!(info.name, "hello");
+        assert_eq!(wait(pid).unwrap(), 2);
+        // reaped
+        assert!(list().iter().all(|p| p.pid != pid));
+        assert!(wait(pid).is_err());
+    }
+
+    #[test_case]
+    fn test_zombie_keeps_exit_code() {
+        let pid = spawn_hello(&["hello"]);
+        while list().iter().any(|p| p.pid == pid && p.state != ProcessState::Zombie(1)) {
+            thread::yield_now();
+        }
+        assert_eq!(wait(pid).unwrap(), 1);
+    }
+
+    #[test_case]
+    fn test_kill() {
+        // waits for input that never comes
+        let pid = spawn("cat", embedded::find("cat").unwrap(), &["cat"], &[]).unwrap();
+        thread::sleep(20);
+        kill(pid).unwrap();
+        assert_eq!(wait(pid).unwrap(), KILLED_EXIT_CODE);
+    }
+}
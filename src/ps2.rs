@@ -0,0 +1,359 @@
+//! # PS/2
+//! Driver for the 8042 PS/2 controller, which the keyboard (first port) and the mouse (second
+//! port) are plugged into.
+//!
+//! `init()` resets the controller instead of trusting the firmware: it runs the self-tests,
+//! enables both ports and their interrupts, and sets the keyboard's scancode set and typematic
+//! rate. The keyboard sends scancode set 2, which the controller translates to set 1 for
+//! `keyboard::scancode`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::PitClock;
+#[allow(unused)]
+use crate::prelude::*;
+
+const DATA_PORT: u16 = 0x60;
+/// Status register when read, command register when written
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xA7;
+const ENABLE_SECOND_PORT: u8 = 0xA8;
+const TEST_SECOND_PORT: u8 = 0xA9;
+const SELF_TEST: u8 = 0xAA;
+const TEST_FIRST_PORT: u8 = 0xAB;
+const DISABLE_FIRST_PORT: u8 = 0xAD;
+const ENABLE_FIRST_PORT: u8 = 0xAE;
+/// Sends the next data byte to the device on the second port
+const WRITE_SECOND_PORT: u8 = 0xD4;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// configuration byte
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// device commands and responses
+pub const SET_LEDS: u8 = 0xED;
+const SET_SCANCODE_SET: u8 = 0xF0;
+const SET_TYPEMATIC: u8 = 0xF3;
+const ENABLE_SCANNING: u8 = 0xF4;
+const DISABLE_SCANNING: u8 = 0xF5;
+const RESET: u8 = 0xFF;
+pub const ACK: u8 = 0xFA;
+pub const RESEND: u8 = 0xFE;
+const RESET_PASSED: u8 = 0xAA;
+
+/// Scancode set asked from the keyboard (translated to set 1 by the controller)
+const SCANCODE_SET: u8 = 2;
+
+/// Milliseconds before giving up on the controller or a device
+const TIMEOUT_MS: u64 = 50;
+/// Milliseconds a device may take to reset and run its self-test
+const RESET_TIMEOUT_MS: u64 = 1000;
+/// Times a device command is sent again when the device asks for it
+const RETRIES: usize = 3;
+
+/// Set by init() when a device (the mouse) answered on the second port
+static SECOND_PORT: AtomicBool = AtomicBool::new(false);
+
+/// How fast a held key repeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    /// 0 (30 repeats per second) to 31 (2 per second)
+    pub rate: u8,
+    /// 0 (250ms before repeating) to 3 (1s)
+    pub delay: u8,
+}
+
+impl Typematic {
+    fn to_byte(self) -> u8 {
+        (self.delay & 0b11) << 5 | (self.rate & 0b1_1111)
+    }
+}
+
+impl Default for Typematic {
+    /// 10.9 repeats per second after 500ms
+    fn default() -> Self {
+        Typematic { rate: 0x0B, delay: 1 }
+    }
+}
+
+/// Keyboard lock LEDs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    pub fn to_byte(self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+/// Ports of the 8042 controller. Any function that waits for a response must run with
+/// interrupts disabled, or the keyboard and mouse interrupts would read it first.
+pub struct Controller {
+    data: Port<u8>,
+    command: Port<u8>,
+}
+
+impl Controller {
+    pub const fn new() -> Self {
+        Controller {
+            data: Port::new(DATA_PORT),
+            command: Port::new(COMMAND_PORT),
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.command.read() }
+    }
+
+    fn wait_for_input_empty(&mut self) -> Result<()> {
+        let mut clock = PitClock::start();
+        while clock.elapsed_ms() < TIMEOUT_MS {
+            if self.status() & STATUS_INPUT_FULL == 0 {
+                return Ok(());
+            }
+        }
+        err!("timed out writing to the PS/2 controller")
+    }
+
+    fn wait_for_output_full(&mut self, timeout_ms: u64) -> Result<()> {
+        let mut clock = PitClock::start();
+        while clock.elapsed_ms() < timeout_ms {
+            if self.status() & STATUS_OUTPUT_FULL != 0 {
+                return Ok(());
+            }
+        }
+        err!("timed out reading from the PS/2 controller")
+    }
+
+    fn write_command(&mut self, command: u8) -> Result<()> {
+        self.wait_for_input_empty()?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    /// Writes a byte to the data port, which goes to the keyboard unless a command said otherwise
+    pub fn write_data(&mut self, byte: u8) -> Result<()> {
+        self.wait_for_input_empty()?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    pub fn read_data(&mut self) -> Result<u8> {
+        self.read_data_within(TIMEOUT_MS)
+    }
+
+    fn read_data_within(&mut self, timeout_ms: u64) -> Result<u8> {
+        self.wait_for_output_full(timeout_ms)?;
+        Ok(unsafe { self.data.read() })
+    }
+
+    /// Drops whatever is waiting in the output buffer
+    fn flush(&mut self) {
+        while self.status() & STATUS_OUTPUT_FULL != 0 {
+            unsafe { self.data.read() };
+        }
+    }
+
+    fn command_with_response(&mut self, command: u8) -> Result<u8> {
+        self.write_command(command)?;
+        self.read_data()
+    }
+
+    fn read_config(&mut self) -> Result<u8> {
+        self.command_with_response(READ_CONFIG)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<()> {
+        self.write_command(WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    /// Sends a byte to a device and waits for it to be acknowledged
+    fn send(&mut self, second_port: bool, byte: u8) -> Result<()> {
+        for _ in 0..RETRIES {
+            if second_port {
+                self.write_command(WRITE_SECOND_PORT)?;
+            }
+            self.write_data(byte)?;
+            match self.read_data()? {
+                ACK => return Ok(()),
+                RESEND => continue,
+                response => return err!("PS/2 device answered {byte:#x} with {response:#x}"),
+            }
+        }
+        err!("PS/2 device kept asking to resend {byte:#x}")
+    }
+
+    /// Sends a byte to the keyboard and waits for it to be acknowledged
+    pub fn send_keyboard(&mut self, byte: u8) -> Result<()> {
+        self.send(false, byte)
+    }
+
+    /// Sends a byte to the mouse and waits for it to be acknowledged
+    pub fn send_mouse(&mut self, byte: u8) -> Result<()> {
+        self.send(true, byte)
+    }
+
+    /// Resets a device and waits for it to pass its self-test
+    fn reset_device(&mut self, second_port: bool) -> Result<()> {
+        self.send(second_port, RESET)?;
+        match self.read_data_within(RESET_TIMEOUT_MS)? {
+            RESET_PASSED => {}
+            response => return err!("PS/2 device failed its self-test ({response:#x})"),
+        }
+        // mice send their id after passing
+        if second_port {
+            let _ = self.read_data();
+        }
+        Ok(())
+    }
+
+    pub fn set_typematic(&mut self, typematic: Typematic) -> Result<()> {
+        self.send_keyboard(SET_TYPEMATIC)?;
+        self.send_keyboard(typematic.to_byte())
+    }
+
+    pub fn set_leds(&mut self, leds: Leds) -> Result<()> {
+        self.send_keyboard(SET_LEDS)?;
+        self.send_keyboard(leds.to_byte())
+    }
+
+    fn init(&mut self) -> Result<bool> {
+        // no interrupts nor scancodes get in the way while we set things up
+        self.write_command(DISABLE_FIRST_PORT)?;
+        let result = self.set_up();
+        if result.is_err() {
+            self.restore_keyboard();
+        }
+        result
+    }
+
+    /// Gives the keyboard back after a failed init, so typing may still work
+    fn restore_keyboard(&mut self) {
+        let config = self.read_config().unwrap_or(0);
+        let _ = self.write_config(config | CONFIG_FIRST_IRQ | CONFIG_TRANSLATION);
+        let _ = self.write_command(ENABLE_FIRST_PORT);
+    }
+
+    /// Resets the controller and devices with the first port disabled
+    fn set_up(&mut self) -> Result<bool> {
+        self.write_command(DISABLE_SECOND_PORT)?;
+        self.flush();
+
+        let mut config = self.read_config()?;
+        config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+        self.write_config(config)?;
+
+        match self.command_with_response(SELF_TEST)? {
+            SELF_TEST_PASSED => {}
+            response => return err!("PS/2 controller failed its self-test ({response:#x})"),
+        }
+        // the self-test may reset the controller
+        self.write_config(config)?;
+
+        // the second port exists if enabling it starts its clock
+        self.write_command(ENABLE_SECOND_PORT)?;
+        let mut second_port = self.read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        self.write_command(DISABLE_SECOND_PORT)?;
+
+        match self.command_with_response(TEST_FIRST_PORT)? {
+            PORT_TEST_PASSED => {}
+            response => return err!("PS/2 keyboard port failed its test ({response:#x})"),
+        }
+        if second_port && self.command_with_response(TEST_SECOND_PORT)? != PORT_TEST_PASSED {
+            log!(Level::Warning, "PS/2 mouse port failed its test, not using it");
+            second_port = false;
+        }
+
+        self.write_command(ENABLE_FIRST_PORT)?;
+        if second_port {
+            self.write_command(ENABLE_SECOND_PORT)?;
+        }
+
+        self.reset_device(false)?;
+        self.send_keyboard(DISABLE_SCANNING)?;
+        self.send_keyboard(SET_SCANCODE_SET)?;
+        self.send_keyboard(SCANCODE_SET)?;
+        self.set_typematic(Typematic::default())?;
+        self.set_leds(Leds::default())?;
+        if second_port && self.reset_device(true).is_err() {
+            log!(Level::Warning, "no PS/2 mouse found");
+            second_port = false;
+        }
+        self.send_keyboard(ENABLE_SCANNING)?;
+
+        config |= CONFIG_FIRST_IRQ | CONFIG_TRANSLATION;
+        if second_port {
+            config |= CONFIG_SECOND_IRQ;
+        }
+        self.write_config(config)?;
+        Ok(second_port)
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Controller::new()
+    }
+}
+
+/// Sets up the PS/2 controller, keyboard and (when there is one) mouse
+pub fn init() {
+    logf!(Level::Info, "Setting up PS/2 controller...");
+    match without_interrupts(|| Controller::new().init()) {
+        Ok(second_port) => {
+            SECOND_PORT.store(second_port, Ordering::SeqCst);
+            log!(Level::Info, "OK");
+        }
+        Err(e) => log!(Level::Error, "failed: {e}"),
+    }
+}
+
+/// Whether a device (the mouse) was found on the second port
+pub fn has_second_port() -> bool {
+    SECOND_PORT.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_typematic_byte() {
+        assert_eq!(Typematic { rate: 0x1F, delay: 3 }.to_byte(), 0x7F);
+        assert_eq!(Typematic::default().to_byte(), 0x2B);
+    }
+
+    #[test_case]
+    fn test_keyboard_acknowledges_commands() {
+        // QEMU's i8042 answers like a real keyboard
+        without_interrupts(|| {
+            let mut controller = Controller::new();
+            controller.set_typematic(Typematic::default()).unwrap();
+            let leds = Leds {
+                caps_lock: true,
+                ..Leds::default()
+            };
+            controller.set_leds(leds).unwrap();
+            controller.set_leds(Leds::default()).unwrap();
+        });
+    }
+}
@@ -0,0 +1,351 @@
+//! # Serial
+//! The COM1-COM4 16550 UARTs (see `uart`). A port is only used if it passes a loopback
+//! self-test, which runs the first time any port is touched. Logs (`serial_print!`) go to the
+//! log port and the serial shell talks on the console port: COM1 for both, unless
+//! CRUZOS_SERIAL_LOG or CRUZOS_SERIAL_CONSOLE name another port (or `none` for logs) at build
+//! time. Bytes received on each port are buffered by its interrupt (IRQ4 for COM1 and COM3, IRQ3
+//! for COM2 and COM4).
+//!
+//! ## Examples
+//! ```
+//! serial::configure(ComPort::Com2, Config::from_frame(115200, "8N1")?)?;
+//! serial::set_log_port(Some(ComPort::Com2))?;
+//! let byte = serial::read_byte(serial::console_port()).await;
+//! ```
+
+pub mod uart;
+
+use lazy_static::lazy_static;
+
+use core::{
+    fmt::{self, Write},
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use futures::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::gdb;
+use crate::interrupts::{PICInterrupt, PICS};
+use crate::lockdep::rank;
+#[allow(unused)]
+use crate::prelude::*;
+use crate::util::{IrqMutex, IrqMutexGuard, RingBuffer};
+use spin::Mutex;
+use uart::{Config, Uart};
+
+const PORT_COUNT: usize = 4;
+const INPUT_BUFFER_SIZE: usize = 1024;
+/// Stored in LOG_PORT when logs don't go anywhere
+const NO_PORT: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; PORT_COUNT] =
+        [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// Standard I/O port base
+    pub fn base(self) -> u16 {
+        [0x3F8, 0x2F8, 0x3E8, 0x2E8][self.index()]
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        ["COM1", "COM2", "COM3", "COM4"][self.index()]
+    }
+
+    /// Port named like "COM2" or "com2"
+    pub fn from_name(name: &str) -> Option<ComPort> {
+        ComPort::ALL
+            .into_iter()
+            .find(|port| port.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for ComPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+lazy_static! {
+    /// UARTs that passed the self-test, None for the other ports
+    static ref PORTS: [IrqMutex<Option<Uart>>; PORT_COUNT] =
+        ComPort::ALL.map(|port| IrqMutex::ranked(detect(port), rank::SERIAL));
+}
+
+static LOG_PORT: AtomicUsize = AtomicUsize::new(0);
+static CONSOLE_PORT: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes received on each port. The serial interrupts are the only producers, and consumers
+/// hold the port's INPUT_CONSUMERS lock while they pop.
+static INPUT: [RingBuffer<u8, INPUT_BUFFER_SIZE>; PORT_COUNT] =
+    [const { RingBuffer::new() }; PORT_COUNT];
+/// Makes sure only one consumer pops a port's INPUT at a time. Never taken by the interrupts.
+static INPUT_CONSUMERS: [Mutex<()>; PORT_COUNT] = [const { Mutex::new(()) }; PORT_COUNT];
+static INPUT_WAKERS: [AtomicWaker; PORT_COUNT] = [const { AtomicWaker::new() }; PORT_COUNT];
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($tt:tt)*) => ($crate::serial::print(format_args!($($tt)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    ($($tt:tt)*) => ($crate::serial_print!("{}\n", format_args!($($tt)*)));
+}
+
+/// The UART of `port` with the default settings, if it passes the self-test
+fn detect(port: ComPort) -> Option<Uart> {
+    let mut uart = unsafe { Uart::new(port.base()) };
+    if !uart.self_test() {
+        return None;
+    }
+    uart.configure(Config::default()).ok()?;
+    Some(uart)
+}
+
+/// Routes logs and the console as set at build time and logs the ports found
+pub fn init() {
+    if let Some(name) = option_env!("CRUZOS_SERIAL_LOG") {
+        let result = match ComPort::from_name(name) {
+            Some(port) => set_log_port(Some(port)),
+            None if name == "none" => set_log_port(None),
+            None => err!("no such port"),
+        };
+        if let Err(e) = result {
+            log!(Level::Warning, "Can't log to {name}: {e}");
+        }
+    }
+    if let Some(name) = option_env!("CRUZOS_SERIAL_CONSOLE") {
+        let result = match ComPort::from_name(name) {
+            Some(port) => set_console_port(port),
+            None => err!("no such port"),
+        };
+        if let Err(e) = result {
+            log!(
+                Level::Warning,
+                "Can't use {name} as the serial console: {e}"
+            );
+        }
+    }
+    for port in ComPort::ALL {
+        if let Some(config) = config(port) {
+            log!(Level::Info, "{port}: {config}");
+        }
+    }
+    let log_port = log_port().map_or("none", ComPort::name);
+    log!(
+        Level::Info,
+        "Logging to {log_port}, serial console on {}",
+        console_port()
+    );
+}
+
+/// Line settings of `port`, None if it wasn't found
+pub fn config(port: ComPort) -> Option<Config> {
+    PORTS[port.index()].lock().as_ref().map(Uart::config)
+}
+
+pub fn configure(port: ComPort, config: Config) -> Result<()> {
+    match lock(port).as_mut() {
+        Some(uart) => uart.configure(config),
+        None => err!("{port} not found"),
+    }
+}
+
+/// Locks the UART of `port`, which is None if it wasn't found
+pub fn lock(port: ComPort) -> IrqMutexGuard<'static, Option<Uart>> {
+    PORTS[port.index()].lock()
+}
+
+/// Port logs go to, None when they only go to the log console
+pub fn log_port() -> Option<ComPort> {
+    ComPort::ALL.get(LOG_PORT.load(Ordering::Relaxed)).copied()
+}
+
+pub fn set_log_port(port: Option<ComPort>) -> Result<()> {
+    if let Some(port) = port {
+        if config(port).is_none() {
+            return err!("{port} not found");
+        }
+    }
+    LOG_PORT.store(port.map_or(NO_PORT, ComPort::index), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Port the serial shell runs on
+pub fn console_port() -> ComPort {
+    ComPort::ALL[CONSOLE_PORT.load(Ordering::Relaxed)]
+}
+
+pub fn set_console_port(port: ComPort) -> Result<()> {
+    if config(port).is_none() {
+        return err!("{port} not found");
+    }
+    CONSOLE_PORT.store(port.index(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Writes to `port` if it was found. The port is locked for one byte at a time, since each one
+/// takes a while to go out (260us at 38400 baud) and interrupts are off while it's locked.
+pub fn write(port: ComPort, args: fmt::Arguments) {
+    let _ = PortWriter(port).write_fmt(args);
+}
+
+/// Sends to a port byte by byte, locking it for each
+struct PortWriter(ComPort);
+
+impl fmt::Write for PortWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match lock(self.0).as_mut() {
+                Some(uart) => uart.send(byte),
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes to the log port, what `serial_print!` does
+pub fn print(args: fmt::Arguments) {
+    if let Some(port) = log_port() {
+        write(port, args);
+    }
+}
+
+/// Handles the "data received" interrupt of COM1 and COM3 (IRQ4)
+pub extern "x86-interrupt" fn com1_interrupt(_stack_frame: InterruptStackFrame) {
+    receive([ComPort::Com1, ComPort::Com3]);
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PICInterrupt::Com1 as u8)
+    };
+}
+
+/// Handles the "data received" interrupt of COM2 and COM4 (IRQ3)
+pub extern "x86-interrupt" fn com2_interrupt(_stack_frame: InterruptStackFrame) {
+    receive([ComPort::Com2, ComPort::Com4]);
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PICInterrupt::Com2 as u8)
+    };
+}
+
+/// Moves what the ports sharing an interrupt received into their buffers
+fn receive(ports: [ComPort; 2]) {
+    for port in ports {
+        let index = port.index();
+        if let Some(uart) = PORTS[index].lock().as_mut() {
+            // the FIFO holds up to 16 bytes, the interrupt only comes once for all of them
+            while let Some(byte) = uart.try_receive() {
+                // dropped when full, dropped_bytes() counts them
+                INPUT[index].push(byte);
+                if gdb::wants_break(port, byte) {
+                    gdb::request_break();
+                }
+            }
+        }
+        INPUT_WAKERS[index].wake();
+    }
+}
+
+/// Stream of bytes received on a port
+pub struct SerialStream {
+    port: ComPort,
+}
+
+impl SerialStream {
+    pub fn new(port: ComPort) -> Self {
+        Self { port }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let index = self.port.index();
+        let _consumer = INPUT_CONSUMERS[index].lock();
+        if let Some(byte) = INPUT[index].pop() {
+            return Poll::Ready(Some(byte));
+        }
+        INPUT_WAKERS[index].register(cx.waker());
+        match INPUT[index].pop() {
+            Some(byte) => {
+                INPUT_WAKERS[index].take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Reads one byte from `port`
+pub async fn read_byte(port: ComPort) -> u8 {
+    let mut stream = SerialStream::new(port);
+
+    loop {
+        if let Some(byte) = stream.next().await {
+            return byte;
+        }
+    }
+}
+
+/// Reads one byte from `port` if there is one, without blocking
+pub fn try_read_byte(port: ComPort) -> Option<u8> {
+    let _consumer = INPUT_CONSUMERS[port.index()].try_lock()?;
+    INPUT[port.index()].pop()
+}
+
+/// Number of bytes received on `port` and lost because nobody read them and the buffer filled up
+pub fn dropped_bytes(port: ComPort) -> usize {
+    INPUT[port.index()].dropped()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_ports() {
+        // the test runner gives QEMU two serial ports
+        assert!(config(ComPort::Com1).is_some());
+        let config_115200 = Config::from_frame(115200, "8E1").unwrap();
+        configure(ComPort::Com2, config_115200).unwrap();
+        assert_eq!(config(ComPort::Com2), Some(config_115200));
+        assert!(config(ComPort::Com4).is_none());
+        assert!(set_console_port(ComPort::Com4).is_err());
+        assert_eq!(ComPort::from_name("com3"), Some(ComPort::Com3));
+        assert_eq!(ComPort::from_name("com5"), None);
+        assert_eq!(ComPort::Com2.base(), 0x2F8);
+    }
+
+    #[test_case]
+    fn test_serial_input() {
+        let port = ComPort::Com1;
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            // whatever the host sent first
+            while try_read_byte(port).is_some() {}
+            INPUT[port.index()].push(b'l');
+            INPUT[port.index()].push(b's');
+            assert_eq!(try_read_byte(port), Some(b'l'));
+            assert_eq!(try_read_byte(port), Some(b's'));
+            assert_eq!(try_read_byte(port), None);
+        });
+    }
+}
@@ -0,0 +1,275 @@
+//! 16550 UART driver: line settings, the loopback self-test and byte I/O through its I/O ports.
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+#[allow(unused)]
+use crate::prelude::*;
+
+// registers (offsets from the base port)
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+/// Divisor latch, low and high byte, while LINE_CONTROL has DLAB set
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+// LINE_CONTROL bits
+const TWO_STOP_BITS: u8 = 1 << 2;
+const PARITY_ENABLE: u8 = 1 << 3;
+const EVEN_PARITY: u8 = 1 << 4;
+const STICK_PARITY: u8 = 1 << 5;
+const DLAB: u8 = 1 << 7;
+
+/// Enable and clear both FIFOs, interrupt when 14 bytes are in
+const FIFO_ENABLE_CLEAR_14: u8 = 0xC7;
+// MODEM_CONTROL bits
+const DTR: u8 = 1 << 0;
+const RTS: u8 = 1 << 1;
+/// Connects the interrupt line to the PIC
+const OUT2: u8 = 1 << 3;
+const LOOPBACK: u8 = 1 << 4;
+const RECEIVED_DATA_INTERRUPT: u8 = 1 << 0;
+// LINE_STATUS bits
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// Baud rate at divisor 1
+const MAX_BAUD: u32 = 115_200;
+/// Sent to itself by the loopback self-test
+const SELF_TEST_BYTE: u8 = 0xAE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Parity bit always 1
+    Mark,
+    /// Parity bit always 0
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// 1.5 with 5 data bits
+    Two,
+}
+
+/// Line settings of a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub baud: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+/// 38400 8N1
+const DEFAULT_CONFIG: Config = Config {
+    baud: 38400,
+    data_bits: 8,
+    parity: Parity::None,
+    stop_bits: StopBits::One,
+};
+
+impl Default for Config {
+    fn default() -> Self {
+        DEFAULT_CONFIG
+    }
+}
+
+impl Config {
+    /// Settings from a baud rate and a frame written like "8N1" (data bits, parity N/O/E/M/S
+    /// and stop bits)
+    pub fn from_frame(baud: u32, frame: &str) -> Result<Self> {
+        let &[data_bits, parity, stop_bits] = frame.as_bytes() else {
+            return err!("bad frame {frame}, expected something like 8N1");
+        };
+        let parity = match parity.to_ascii_uppercase() {
+            b'N' => Parity::None,
+            b'O' => Parity::Odd,
+            b'E' => Parity::Even,
+            b'M' => Parity::Mark,
+            b'S' => Parity::Space,
+            _ => return err!("bad parity in {frame}, expected N, O, E, M or S"),
+        };
+        let stop_bits = match stop_bits {
+            b'1' => StopBits::One,
+            b'2' => StopBits::Two,
+            _ => return err!("bad stop bits in {frame}, expected 1 or 2"),
+        };
+        let config = Config {
+            baud,
+            data_bits: data_bits.wrapping_sub(b'0'),
+            parity,
+            stop_bits,
+        };
+        config.divisor()?;
+        config.line_control()?;
+        Ok(config)
+    }
+
+    /// Divides the UART clock down to the baud rate
+    fn divisor(&self) -> Result<u16> {
+        let baud = self.baud;
+        if baud == 0 || baud > MAX_BAUD || !MAX_BAUD.is_multiple_of(baud) {
+            return err!("unsupported baud rate {baud}, it must divide {MAX_BAUD}");
+        }
+        Ok((MAX_BAUD / baud) as u16)
+    }
+
+    /// LINE_CONTROL value for the frame
+    fn line_control(&self) -> Result<u8> {
+        if !(5..=8).contains(&self.data_bits) {
+            return err!("unsupported data bits {}, expected 5 to 8", self.data_bits);
+        }
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => PARITY_ENABLE,
+            Parity::Even => PARITY_ENABLE | EVEN_PARITY,
+            Parity::Mark => PARITY_ENABLE | STICK_PARITY,
+            Parity::Space => PARITY_ENABLE | EVEN_PARITY | STICK_PARITY,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => TWO_STOP_BITS,
+        };
+        Ok((self.data_bits - 5) | parity | stop_bits)
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {}{parity}{stop_bits}", self.baud, self.data_bits)
+    }
+}
+
+/// A 16550 at an I/O port base
+#[derive(Debug)]
+pub struct Uart {
+    base: u16,
+    config: Config,
+}
+
+impl Uart {
+    /// # Safety
+    /// `base` must be the base port of a UART (or of nothing at all)
+    pub const unsafe fn new(base: u16) -> Self {
+        Uart {
+            base,
+            config: DEFAULT_CONFIG,
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// Whether a working UART is there: in loopback mode, what it sends has to come back. Leaves
+    /// it unconfigured, with its interrupts off.
+    pub fn self_test(&mut self) -> bool {
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, DLAB);
+        self.write(DIVISOR_LOW, 1);
+        self.write(DIVISOR_HIGH, 0);
+        self.write(LINE_CONTROL, 0x03);
+        self.write(FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+        self.write(MODEM_CONTROL, LOOPBACK | RTS);
+        self.write(DATA, SELF_TEST_BYTE);
+        // nothing answers on a port without a UART, reads give 0xFF
+        let mut passed = false;
+        for _ in 0..1000 {
+            if self.read(LINE_STATUS) & DATA_READY != 0 {
+                passed = self.read(DATA) == SELF_TEST_BYTE;
+                break;
+            }
+        }
+        self.write(MODEM_CONTROL, 0);
+        passed
+    }
+
+    /// Applies `config` and turns on the received data interrupt
+    pub fn configure(&mut self, config: Config) -> Result<()> {
+        let [low, high] = config.divisor()?.to_le_bytes();
+        let line_control = config.line_control()?;
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, DLAB);
+        self.write(DIVISOR_LOW, low);
+        self.write(DIVISOR_HIGH, high);
+        self.write(LINE_CONTROL, line_control);
+        self.write(FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+        self.write(MODEM_CONTROL, DTR | RTS | OUT2);
+        self.write(INTERRUPT_ENABLE, RECEIVED_DATA_INTERRUPT);
+        self.config = config;
+        Ok(())
+    }
+
+    /// Sends a byte as is, waiting for room in the transmitter
+    pub fn send(&mut self, byte: u8) {
+        while self.read(LINE_STATUS) & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+
+    pub fn try_receive(&mut self) -> Option<u8> {
+        match self.read(LINE_STATUS) & DATA_READY {
+            0 => None,
+            _ => Some(self.read(DATA)),
+        }
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_frames() {
+        let config = Config::from_frame(9600, "7e2").unwrap();
+        assert_eq!(config.divisor().unwrap(), 12);
+        assert_eq!(config.line_control().unwrap(), 0b0001_1110);
+        assert_eq!(format!("{config}"), "9600 7E2");
+        assert_eq!(Config::default().line_control().unwrap(), 0x03);
+
+        assert!(Config::from_frame(9600, "9N1").is_err());
+        assert!(Config::from_frame(9600, "8X1").is_err());
+        assert!(Config::from_frame(9600, "8N").is_err());
+        // 115200 isn't a multiple of it
+        assert!(Config::from_frame(7000, "8N1").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! # Syscalls
+//! Ring 3 code calls into the kernel with `int 0x80`. The syscall number goes in `rax`, the
+//! arguments in `rdi`, `rsi` and `rdx`, and the result comes back in `rax` (a negated error code
+//! on failure).
+//!
+//! | rax | syscall | arguments      | returns                       |
+//! |-----|---------|----------------|-------------------------------|
+//! | 0   | read    | fd, buf, len   | bytes read (blocks until > 0) |
+//! | 1   | write   | fd, buf, len   | bytes written                 |
+//! | 3   | close   | fd             | 0                             |
+//! | 35  | sleep   | milliseconds   | 0                             |
+//! | 39  | getpid  |                | pid of the calling process    |
+//! | 60  | exit    | exit code      | doesn't return                |
+//! | 110 | getppid |                | pid of its parent             |
+//!
+//! File descriptors index the process' handle table (see `process`): 0 reads from the keyboard,
+//! 1 and 2 write to the console.
+
+use x86_64::VirtAddr;
+
+use crate::keyboard;
+use crate::memory;
+#[allow(unused)]
+use crate::prelude::*;
+use crate::process::{self, Handle};
+use crate::thread::{self, context::SavedContext};
+use crate::vga;
+
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Size of the `int 0x80` instruction, to make the thread run it again
+const SYSCALL_INSTRUCTION_SIZE: u64 = 2;
+
+/// Bad file descriptor
+pub const EBADF: i64 = 9;
+/// Bad address
+pub const EFAULT: i64 = 14;
+/// Invalid argument
+pub const EINVAL: i64 = 22;
+/// Unknown syscall
+pub const ENOSYS: i64 = 38;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Syscall {
+    Read = 0,
+    Write = 1,
+    Close = 3,
+    Sleep = 35,
+    GetPid = 39,
+    Exit = 60,
+    GetPpid = 110,
+}
+
+impl TryFrom<u64> for Syscall {
+    type Error = u64;
+    fn try_from(number: u64) -> result::Result<Self, Self::Error> {
+        match number {
+            0 => Ok(Syscall::Read),
+            1 => Ok(Syscall::Write),
+            3 => Ok(Syscall::Close),
+            35 => Ok(Syscall::Sleep),
+            39 => Ok(Syscall::GetPid),
+            60 => Ok(Syscall::Exit),
+            110 => Ok(Syscall::GetPpid),
+            _ => Err(number),
+        }
+    }
+}
+
+/// What to do with the calling thread once the syscall is handled
+enum Action {
+    /// Return this value in rax
+    Return(i64),
+    /// Run the syscall again later (e.g. waiting for input or for a lock)
+    Retry,
+    /// The thread blocked or finished, switch to another one
+    Switch,
+}
+
+/// Handles a syscall. Called by `context::syscall_entry` with the saved context of the calling
+/// thread, returns the saved context of the thread to resume.
+pub extern "C" fn syscall_interrupt(rsp: u64) -> u64 {
+    let context = unsafe { &mut *(rsp as *mut SavedContext) };
+    let (arg0, arg1, arg2) = (context.rdi, context.rsi, context.rdx);
+
+    let action = match Syscall::try_from(context.rax) {
+        Ok(Syscall::Read) => sys_read(arg0, arg1, arg2),
+        Ok(Syscall::Write) => sys_write(arg0, arg1, arg2),
+        Ok(Syscall::Close) => match process::close(arg0 as usize) {
+            Ok(()) => Action::Return(0),
+            Err(_) => Action::Return(-EBADF),
+        },
+        Ok(Syscall::Sleep) => {
+            context.rax = 0;
+            thread::sleep_current(arg0);
+            Action::Switch
+        }
+        Ok(Syscall::GetPid) => Action::Return(process::current() as i64),
+        Ok(Syscall::GetPpid) => {
+            let parent = process::parent(process::current()).unwrap_or(process::KERNEL_PID);
+            Action::Return(parent as i64)
+        }
+        Ok(Syscall::Exit) => {
+            thread::finish_current(arg0 as i64);
+            Action::Switch
+        }
+        Err(number) => {
+            log!(Level::Debug, "unknown syscall {number}");
+            Action::Return(-ENOSYS)
+        }
+    };
+
+    match action {
+        Action::Return(value) => {
+            context.rax = value as u64;
+            rsp
+        }
+        Action::Retry => {
+            context.rip -= SYSCALL_INSTRUCTION_SIZE;
+            thread::schedule(rsp)
+        }
+        Action::Switch => thread::schedule(rsp),
+    }
+}
+
+/// Returns the user buffer `[ptr, ptr + len)` if ring 3 is allowed to access it
+fn user_buffer(ptr: u64, len: u64, writable: bool) -> Option<&'static mut [u8]> {
+    let addr = VirtAddr::try_new(ptr).ok()?;
+    if !memory::is_user_accessible(addr, len, writable) {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr(), len as usize) })
+}
+
+fn sys_write(fd: u64, ptr: u64, len: u64) -> Action {
+    if process::handle(fd as usize) != Some(Handle::Console) {
+        return Action::Return(-EBADF);
+    }
+    let bytes = match user_buffer(ptr, len, false) {
+        Some(bytes) => bytes,
+        None => return Action::Return(-EFAULT),
+    };
+    let string = match core::str::from_utf8(bytes) {
+        Ok(string) => string,
+        Err(_) => return Action::Return(-EINVAL),
+    };
+    // whoever holds the console lock can't run until we switch threads
+    match vga::CONSOLES[thread::console()].try_lock() {
+        Some(mut vga) => {
+            let _ = vga.write_str(string);
+            Action::Return(len as i64)
+        }
+        None => Action::Retry,
+    }
+}
+
+fn sys_read(fd: u64, ptr: u64, len: u64) -> Action {
+    if process::handle(fd as usize) != Some(Handle::Keyboard) {
+        return Action::Return(-EBADF);
+    }
+    let buffer = match user_buffer(ptr, len, true) {
+        Some(buffer) => buffer,
+        None => return Action::Return(-EFAULT),
+    };
+    if buffer.is_empty() {
+        return Action::Return(0);
+    }
+
+    let mut read = 0;
+    // stop while a whole UTF-8 character still fits, unless nothing was read yet
+    while read == 0 || buffer.len() - read >= 4 {
+        let c = match keyboard::try_getc() {
+            Some(c) => c,
+            None => break,
+        };
+        let mut utf8 = [0; 4];
+        let encoded = c.encode_utf8(&mut utf8).as_bytes();
+        // a tiny buffer gets as much of the character as fits
+        let len = encoded.len().min(buffer.len() - read);
+        buffer[read..read + len].copy_from_slice(&encoded[..len]);
+        read += len;
+    }
+
+    match read {
+        0 => Action::Retry, // block until there's input
+        _ => Action::Return(read as i64),
+    }
+}
@@ -11,39 +11,81 @@ use crate::prelude::*;
 
 pub mod simple_executor;
 
-pub static PID: AtomicUsize = AtomicUsize::new(0);
+/// Ids of async tasks, unrelated to process ids (see `process`)
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Priority given to tasks created with `Task::new` (0 is the highest priority, kept for
+/// interactive tasks like the shells)
+pub const DEFAULT_PRIORITY: usize = 1;
 
 pub struct Task {
     future: Pin<Box<dyn Future<Output = ()>>>,
     id: usize,
     ready: AtomicBool,
     waker: Arc<TaskWaker>,
+    /// Base priority, the level the task goes back to when priorities are boosted
+    priority: usize,
+    /// Current MLFQ level (0 is the highest priority)
+    level: usize,
+    /// Number of times the task was polled on its current level
+    polls: usize,
+    /// Virtual console the task prints to and reads the keyboard from, None for the one of the
+    /// executor's thread
+    console: Option<usize>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+        Task::with_priority(future, DEFAULT_PRIORITY)
+    }
+
+    /// Creates a task that starts at (and gets boosted back to) `priority`
+    pub fn with_priority(future: impl Future<Output = ()> + 'static, priority: usize) -> Self {
         Task {
             future: Box::pin(future),
-            id: PID.fetch_add(1, Ordering::SeqCst),
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
             ready: AtomicBool::new(false),
             waker: TaskWaker::new(false),
+            priority,
+            level: priority,
+            polls: 0,
+            console: None,
         }
     }
 
+    /// Makes the task print to and read the keyboard from virtual console `console` (see vga)
+    pub fn on_console(mut self, console: usize) -> Self {
+        self.console = Some(console);
+        self
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn priority(&self) -> usize {
+        self.priority
+    }
+
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
     pub fn ready(&mut self) {
         self.ready.store(true, Ordering::Relaxed);
     }
 
+    fn is_blocked(&self) -> bool {
+        self.waker.blocked.load(Ordering::SeqCst)
+    }
+
     pub fn poll(&mut self, cx: &mut Context) -> Poll<()> {
+        // block task before polling, to be unblocked by Waker. If we did it after polling, a
+        // future that wakes itself while being polled (e.g. yield_now) would never run again
+        self.waker.blocked.store(true, Ordering::SeqCst);
         let poll_result = self.future.as_mut().poll(cx);
-        match poll_result {
-            Poll::Ready(_) => {
-                self.ready.store(true, Ordering::Relaxed);
-            }
-            Poll::Pending => {
-                // reblock task, to be unblocked by Waker
-                self.waker.blocked.store(true, Ordering::Relaxed);
-            }
+        if poll_result.is_ready() {
+            self.ready.store(true, Ordering::Relaxed);
         }
         poll_result
     }
@@ -63,6 +105,27 @@ impl TaskWaker {
 
 impl Wake for TaskWaker {
     fn wake(self: Arc<Self>) {
-        self.blocked.store(false, Ordering::Relaxed);
+        self.blocked.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Gives control back to the executor, the task is polled again on the next round
+pub async fn yield_now() {
+    YieldNow { yielded: false }.await
+}
+
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
     }
 }
@@ -8,23 +8,65 @@ use core::task::Waker;
 use super::Task;
 
 use crate::prelude::*;
+use crate::thread;
 
+/// Parameters of the multi-level feedback queue (MLFQ) scheduling policy
+#[derive(Debug, Clone, Copy)]
+pub struct MlfqParams {
+    /// Number of priority levels (level 0 has the highest priority)
+    pub levels: usize,
+    /// Number of polls a task gets on a level before being demoted to the next one
+    pub quantum: usize,
+    /// Every `boost_interval` rounds all tasks go back to their base priority (0 disables boosts)
+    pub boost_interval: usize,
+}
+
+impl Default for MlfqParams {
+    fn default() -> Self {
+        MlfqParams {
+            levels: 3,
+            quantum: 8,
+            boost_interval: 100,
+        }
+    }
+}
+
+/// Executor that schedules tasks with a multi-level feedback queue.
+/// Each round only the ready tasks on the highest non-empty level are polled, so tasks that keep
+/// getting woken (CPU-heavy ones) sink to lower levels and can't starve interactive ones.
 pub struct SimpleExecutor {
     tasks: BTreeMap<usize, Task>,
     new_tasks: BTreeMap<usize, Task>,
+    params: MlfqParams,
+    rounds: usize,
 }
 
 impl SimpleExecutor {
-    pub fn new(_capacity: usize) -> Self {
+    pub fn new(capacity: usize) -> Self {
+        SimpleExecutor::with_params(capacity, MlfqParams::default())
+    }
+
+    pub fn with_params(_capacity: usize, params: MlfqParams) -> Self {
         // TODO: set capacity
+        assert!(params.levels > 0, "MLFQ needs at least one level");
+        assert!(params.quantum > 0, "MLFQ quantum must be at least one poll");
         SimpleExecutor {
             tasks: BTreeMap::new(),
             new_tasks: BTreeMap::new(),
+            params,
+            rounds: 0,
         }
     }
 
+    pub fn params(&self) -> MlfqParams {
+        self.params
+    }
+
     /// Spawns a new task, can be called after executor started running
-    pub fn spawn(&mut self, task: Task) {
+    pub fn spawn(&mut self, mut task: Task) {
+        let lowest = self.params.levels - 1;
+        task.priority = task.priority.min(lowest);
+        task.level = task.priority;
         self.new_tasks.insert(task.id, task);
     }
 
@@ -35,30 +77,81 @@ impl SimpleExecutor {
         }
     }
 
-    pub fn run(&mut self) -> ! {
-        loop {
-            // Add new_tasks to tasks and clear new_tasks
-            self.update_tasks();
-
-            assert!(self.new_tasks.is_empty());
-
-            // Filter for unblocked tasks and poll them
-            // Unblocked tasks were unblocked by the waker
-            for (i, task) in self
-                .tasks
-                .values_mut()
-                .filter(|t| !t.waker.blocked.load(Ordering::SeqCst))
-                .enumerate()
-            {
-                log!(Level::Debug, "polling task {i}");
-                let waker = Waker::from(Arc::clone(&task.waker));
-                let mut cx = Context::from_waker(&waker);
-                let _poll_result = task.poll(&mut cx);
-                log!(Level::Debug, "finished polling task {i}");
+    /// Moves every task back to its base priority
+    fn boost(&mut self) {
+        log!(Level::Trace, "boosting task priorities");
+        for task in self.tasks.values_mut() {
+            task.level = task.priority;
+            task.polls = 0;
+        }
+    }
+
+    /// Runs one scheduling round: polls the unblocked tasks on the highest priority level that has any
+    fn tick(&mut self) {
+        // Add new_tasks to tasks and clear new_tasks
+        self.update_tasks();
+
+        assert!(self.new_tasks.is_empty());
+
+        // Unblocked tasks were unblocked by the waker
+        if self.tasks.values().all(|t| t.is_blocked()) {
+            return;
+        }
+
+        // only rounds that poll something count, an idle executor spins through tick()
+        self.rounds = self.rounds.wrapping_add(1);
+        if self.params.boost_interval != 0 && self.rounds.is_multiple_of(self.params.boost_interval)
+        {
+            self.boost();
+        }
+
+        let level = self
+            .tasks
+            .values()
+            .filter(|t| !t.is_blocked())
+            .map(|t| t.level)
+            .min()
+            .expect("some task is unblocked");
+
+        let lowest = self.params.levels - 1;
+        for (pid, task) in self
+            .tasks
+            .iter_mut()
+            .filter(|(_, t)| !t.is_blocked() && t.level == level)
+        {
+            log!(Level::Debug, "polling task {pid} (level {level})");
+            let waker = Waker::from(Arc::clone(&task.waker));
+            let mut cx = Context::from_waker(&waker);
+            // print! and the keyboard go by the console of the running thread, lend it the task's
+            let thread_console = thread::console();
+            if let Some(console) = task.console {
+                if let Err(e) = thread::set_console(console) {
+                    log!(Level::Warning, "task {pid}: {e}");
+                }
+            }
+            // only tasks that woke themselves (kept the CPU busy) use up their quantum, not the
+            // ones woken from outside, like a shell by a keystroke
+            if task.poll(&mut cx).is_pending() && !task.is_blocked() {
+                // task used up its quantum on this level, demote it
+                task.polls += 1;
+                if task.polls >= self.params.quantum {
+                    task.polls = 0;
+                    task.level = (task.level + 1).min(lowest);
+                }
+            }
+            if task.console.is_some() {
+                let _ = thread::set_console(thread_console);
             }
+            log!(Level::Debug, "finished polling task {pid}");
+        }
 
-            self.tasks
-                .retain(|_pid, task| !task.ready.load(Ordering::SeqCst)); // retain tasks that are not ready
+        self.tasks
+            .retain(|_pid, task| !task.ready.load(Ordering::SeqCst)); // retain tasks that are not ready
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.tick();
 
             // TODO: hlt CPU if no tasks are pending
             // let pending_tasks = self.tasks.len();
@@ -71,3 +164,111 @@ impl SimpleExecutor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::yield_now;
+    use core::future::poll_fn;
+    use core::sync::atomic::{AtomicBool, AtomicUsize};
+    use core::task::Poll;
+    use futures::task::AtomicWaker;
+
+    async fn spin_forever() {
+        loop {
+            yield_now().await;
+        }
+    }
+
+    #[test_case]
+    fn test_demotion_and_boost() {
+        let params = MlfqParams {
+            levels: 3,
+            quantum: 2,
+            boost_interval: 10,
+        };
+        let mut executor = SimpleExecutor::with_params(1, params);
+        let task = Task::with_priority(spin_forever(), 0);
+        let pid = task.id();
+        executor.spawn(task);
+
+        for _ in 0..2 {
+            executor.tick();
+        }
+        assert_eq!(executor.tasks[&pid].level(), 1);
+
+        for _ in 0..4 {
+            executor.tick();
+        }
+        assert_eq!(executor.tasks[&pid].level(), 2); // lowest level, can't go further
+
+        for _ in 0..4 {
+            executor.tick();
+        }
+        // 10th round boosted the task before polling it once
+        assert_eq!(executor.tasks[&pid].level(), 0);
+    }
+
+    #[test_case]
+    fn test_higher_priority_runs_first() {
+        static LOW_RAN: AtomicBool = AtomicBool::new(false);
+        static HIGH_POLLS: AtomicUsize = AtomicUsize::new(0);
+
+        let params = MlfqParams {
+            levels: 2,
+            quantum: 4,
+            boost_interval: 0,
+        };
+        let mut executor = SimpleExecutor::with_params(2, params);
+        executor.spawn(Task::with_priority(
+            async {
+                LOW_RAN.store(true, Ordering::SeqCst);
+            },
+            1,
+        ));
+        executor.spawn(Task::with_priority(
+            async {
+                loop {
+                    HIGH_POLLS.fetch_add(1, Ordering::SeqCst);
+                    yield_now().await;
+                }
+            },
+            0,
+        ));
+
+        // high priority task is alone on level 0 until it uses its quantum
+        for _ in 0..3 {
+            executor.tick();
+        }
+        assert!(!LOW_RAN.load(Ordering::SeqCst));
+
+        // now both are on level 1 and get polled in the same round
+        executor.tick();
+        executor.tick();
+        assert!(LOW_RAN.load(Ordering::SeqCst));
+        assert_eq!(HIGH_POLLS.load(Ordering::SeqCst), 5);
+    }
+
+    #[test_case]
+    fn test_woken_from_outside_keeps_level() {
+        static WAKER: AtomicWaker = AtomicWaker::new();
+
+        let mut executor = SimpleExecutor::new(1);
+        let task = Task::with_priority(
+            poll_fn(|cx| {
+                WAKER.register(cx.waker());
+                Poll::<()>::Pending
+            }),
+            0,
+        );
+        let pid = task.id();
+        executor.spawn(task);
+        let quantum = executor.params().quantum;
+        for _ in 0..quantum * 2 {
+            executor.tick();
+            // like an interrupt handler waking it
+            WAKER.wake();
+        }
+        assert_eq!(executor.tasks[&pid].level(), 0);
+    }
+}
@@ -0,0 +1,214 @@
+//! Saving and restoring thread contexts.
+//!
+//! The timer, yield, syscall, page fault, breakpoint and debug interrupts enter through the assembly stubs below,
+//! which push every general purpose register on top of the interrupt stack frame the CPU already
+//! pushed. The stack pointer after that is the thread's saved context: the stubs hand it to a Rust
+//! function that returns the context of the thread to run next, then pop its registers and `iretq`
+//! into it.
+
+use core::arch::global_asm;
+use core::mem::size_of;
+
+use x86_64::instructions::segmentation::{Segment, CS, SS};
+use x86_64::VirtAddr;
+
+/// Interrupt vector used by `thread::yield_now` to switch threads without waiting for the timer
+pub const YIELD_VECTOR: u8 = 0x81;
+
+/// RFLAGS of a new thread (only the interrupt flag set, so it can be preempted)
+const INITIAL_RFLAGS: u64 = 0x202;
+
+/// Registers saved on a thread's stack when it is switched out (lowest address first)
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SavedContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl SavedContext {
+    /// Returns true if the context was interrupted while running in ring 3
+    pub fn is_user(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+extern "C" {
+    fn timer_entry();
+    fn yield_entry();
+    fn syscall_entry();
+    fn page_fault_entry();
+    fn breakpoint_entry();
+    fn debug_entry();
+}
+
+global_asm!(
+    r#"
+.macro push_context
+    push rax
+    push_registers
+.endm
+
+.macro push_registers
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+.endm
+
+.global timer_entry
+timer_entry:
+    push_context
+    mov rdi, rsp
+    call {timer_handler}
+    jmp restore_context
+
+.global yield_entry
+yield_entry:
+    push_context
+    mov rdi, rsp
+    call {yield_handler}
+    jmp restore_context
+
+.global syscall_entry
+syscall_entry:
+    push_context
+    mov rdi, rsp
+    call {syscall_handler}
+    jmp restore_context
+
+.global breakpoint_entry
+breakpoint_entry:
+    push_context
+    mov rdi, rsp
+    call {breakpoint_handler}
+    jmp restore_context
+
+.global debug_entry
+debug_entry:
+    push_context
+    mov rdi, rsp
+    call {debug_handler}
+    jmp restore_context
+
+/* the CPU pushes an error code for page faults: swap it with rax so the saved context has the
+   same layout as the other interrupts */
+.global page_fault_entry
+page_fault_entry:
+    xchg rax, [rsp]
+    push_registers
+    mov rdi, rsp
+    mov rsi, rax
+    call {page_fault_handler}
+
+restore_context:
+    mov rsp, rax
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+"#,
+    timer_handler = sym crate::interrupts::timer_interrupt,
+    yield_handler = sym yield_interrupt,
+    syscall_handler = sym crate::syscall::syscall_interrupt,
+    page_fault_handler = sym crate::interrupts::page_fault,
+    breakpoint_handler = sym crate::interrupts::breakpoint,
+    debug_handler = sym crate::interrupts::debug,
+);
+
+/// Address of the timer interrupt entry stub (to be put in the IDT)
+pub fn timer_entry_addr() -> VirtAddr {
+    VirtAddr::new(timer_entry as *const () as u64)
+}
+
+/// Address of the yield interrupt entry stub (to be put in the IDT)
+pub fn yield_entry_addr() -> VirtAddr {
+    VirtAddr::new(yield_entry as *const () as u64)
+}
+
+/// Address of the syscall interrupt entry stub (to be put in the IDT)
+pub fn syscall_entry_addr() -> VirtAddr {
+    VirtAddr::new(syscall_entry as *const () as u64)
+}
+
+/// Address of the page fault entry stub (to be put in the IDT)
+pub fn page_fault_entry_addr() -> VirtAddr {
+    VirtAddr::new(page_fault_entry as *const () as u64)
+}
+
+/// Address of the breakpoint (`int3`) entry stub (to be put in the IDT)
+pub fn breakpoint_entry_addr() -> VirtAddr {
+    VirtAddr::new(breakpoint_entry as *const () as u64)
+}
+
+/// Address of the debug exception entry stub (to be put in the IDT)
+pub fn debug_entry_addr() -> VirtAddr {
+    VirtAddr::new(debug_entry as *const () as u64)
+}
+
+extern "C" fn yield_interrupt(rsp: u64) -> u64 {
+    super::schedule(rsp)
+}
+
+/// Writes the initial context of a new thread at the top of its stack and returns the saved stack
+/// pointer for it. When switched to, the thread starts running `entry(arg)`.
+///
+/// # Safety
+/// `stack_top` must be the 16 byte aligned end of a mapped stack not used by anyone else.
+pub unsafe fn init_context(stack_top: VirtAddr, entry: extern "C" fn(u64) -> !, arg: u64) -> u64 {
+    let context_addr = stack_top - size_of::<SavedContext>() as u64;
+    let context = SavedContext {
+        rdi: arg,
+        rip: entry as usize as u64,
+        cs: CS::get_reg().0 as u64,
+        rflags: INITIAL_RFLAGS,
+        // as if entry had been called: rsp + 8 is 16 byte aligned
+        rsp: (stack_top - 8u64).as_u64(),
+        ss: SS::get_reg().0 as u64,
+        ..Default::default()
+    };
+    let context_ptr: *mut SavedContext = context_addr.as_mut_ptr();
+    context_ptr.write(context);
+    context_addr.as_u64()
+}
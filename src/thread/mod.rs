@@ -0,0 +1,505 @@
+//! # Threads
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own guarded stack and its registers are saved on it when it is switched
+//! out. The timer interrupt preempts the running thread and the next ready one is picked in
+//! round-robin order, so a thread that never yields can't starve the others.
+//!
+//! ## Examples
+//! ```
+//! let handle = thread::spawn(|| log!(Level::Info, "hello from a thread"));
+//! thread::sleep(100);
+//! handle.join();
+//! ```
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::control::Cr3;
+
+use crate::gdt;
+use crate::interrupts::{ticks, TIMER_HZ};
+use crate::lockdep::rank;
+use crate::memory::{self, AddressSpace};
+#[allow(unused)]
+use crate::prelude::*;
+use crate::vga;
+
+pub mod context;
+pub mod stack;
+
+use context::SavedContext;
+use stack::Stack;
+
+pub type ThreadId = usize;
+
+/// Id of the thread that booted the kernel (runs on the boot stack)
+pub const BOOT_THREAD_ID: ThreadId = 0;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(BOOT_THREAD_ID + 1);
+
+/// Preemption only starts after init(), before that the timer interrupt just returns
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Id of the running thread, readable without the scheduler lock
+static CURRENT: AtomicUsize = AtomicUsize::new(BOOT_THREAD_ID);
+
+lazy_static! {
+    static ref SCHEDULER: IrqMutex<Scheduler> =
+        IrqMutex::ranked(Scheduler::new(), rank::SCHEDULER);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    /// Sleeping until the timer reaches this tick
+    Sleeping(u64),
+    /// Waiting for another thread to finish
+    Joining(ThreadId),
+    Finished,
+}
+
+struct Thread {
+    state: State,
+    /// Stack pointer to the thread's SavedContext (only valid while it is not running)
+    rsp: u64,
+    /// None for the boot thread
+    stack: Option<Stack>,
+    /// Set when the JoinHandle was dropped, nobody will join so it can be reaped once finished
+    detached: bool,
+    /// Only meaningful once the thread is finished
+    exit_code: i64,
+    /// None for threads that run in the kernel's address space
+    address_space: Option<Arc<AddressSpace>>,
+    /// Exit code to finish with once the thread is about to go back to ring 3 (see kill())
+    killed: Option<i64>,
+    /// Virtual console print! writes to, inherited by the threads it spawns
+    console: usize,
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack.take() {
+            stack.free();
+        }
+    }
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Thread>,
+    current: ThreadId,
+    /// Thread that runs when nobody else can (None until init() spawns it)
+    idle: Option<ThreadId>,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        let mut threads = BTreeMap::new();
+        threads.insert(
+            BOOT_THREAD_ID,
+            Thread {
+                state: State::Ready,
+                rsp: 0,
+                stack: None,
+                detached: true,
+                exit_code: 0,
+                address_space: None,
+                killed: None,
+                console: 0,
+            },
+        );
+        Scheduler {
+            threads,
+            current: BOOT_THREAD_ID,
+            idle: None,
+        }
+    }
+
+    fn is_finished(&self, id: ThreadId) -> bool {
+        // reaped threads are finished too
+        self.threads
+            .get(&id)
+            .is_none_or(|t| t.state == State::Finished)
+    }
+
+    fn is_runnable(&self, thread: &Thread, now: u64) -> bool {
+        match thread.state {
+            State::Ready => true,
+            State::Sleeping(until) => until <= now,
+            State::Joining(id) => self.is_finished(id),
+            State::Finished => false,
+        }
+    }
+
+    /// Saves the current thread's context and returns the context of the next thread to run.
+    /// Does not allocate nor free memory, since it runs in interrupt context.
+    fn switch(&mut self, rsp: u64) -> u64 {
+        let now = ticks();
+        if let Some(current) = self.threads.get_mut(&self.current) {
+            current.rsp = rsp;
+        }
+        self.finish_killed();
+
+        // round-robin: first runnable thread after the current one, wrapping around
+        let next = self
+            .threads
+            .range(self.current + 1..)
+            .chain(self.threads.range(..=self.current))
+            .find(|(id, t)| Some(**id) != self.idle && self.is_runnable(t, now))
+            .map(|(id, _)| *id)
+            .or(self.idle);
+
+        match next {
+            Some(id) => {
+                self.current = id;
+                CURRENT.store(id, Ordering::SeqCst);
+                let thread = self.threads.get_mut(&id).unwrap();
+                thread.state = State::Ready;
+                // interrupts coming from ring 3 must land on this thread's kernel stack
+                if let Some(stack) = &thread.stack {
+                    gdt::set_kernel_stack(stack.top());
+                }
+                let l4_frame = match &thread.address_space {
+                    Some(address_space) => address_space.l4_frame(),
+                    None => memory::kernel_l4_frame(),
+                };
+                let (active_l4_frame, cr3_flags) = Cr3::read();
+                if active_l4_frame != l4_frame {
+                    // the kernel is mapped the same way in every address space, so this is safe
+                    unsafe { Cr3::write(l4_frame, cr3_flags) };
+                }
+                thread.rsp
+            }
+            None => rsp, // nobody can run, keep going with the current thread
+        }
+    }
+
+    /// Finishes the killed threads that would resume in ring 3. Threads interrupted in the kernel
+    /// keep running until they get back to user code, so they never die holding a kernel lock.
+    fn finish_killed(&mut self) {
+        for thread in self.threads.values_mut() {
+            let exit_code = match thread.killed {
+                Some(exit_code) if thread.state != State::Finished && thread.rsp != 0 => exit_code,
+                _ => continue,
+            };
+            // every thread but the running one has its context saved at rsp
+            let context = unsafe { &*(thread.rsp as *const SavedContext) };
+            if context.is_user() {
+                thread.state = State::Finished;
+                thread.exit_code = exit_code;
+            }
+        }
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        self.threads
+            .get_mut(&self.current)
+            .expect("current thread is not in the thread table")
+    }
+
+    /// Removes a finished thread from the table. The thread is returned to be dropped after the
+    /// lock is released, since giving its stack and address space back takes other locks.
+    fn reap(&mut self, id: ThreadId) -> Option<Thread> {
+        if id == self.current || !self.is_finished(id) {
+            return None;
+        }
+        self.threads.remove(&id)
+    }
+
+    fn reap_detached(&mut self) -> Vec<Thread> {
+        let finished: Vec<ThreadId> = self
+            .threads
+            .iter()
+            .filter(|(_, t)| t.detached && t.state == State::Finished)
+            .map(|(id, _)| *id)
+            .collect();
+        finished
+            .into_iter()
+            .filter_map(|id| self.reap(id))
+            .collect()
+    }
+}
+
+/// Starts preempting threads on timer interrupts
+pub fn init() {
+    logf!(Level::Info, "Setting up threads...");
+    // initialize scheduler now, it can't allocate inside the timer interrupt
+    lazy_static::initialize(&SCHEDULER);
+    let idle = spawn(|| loop {
+        x86_64::instructions::hlt();
+    });
+    SCHEDULER.lock().idle = Some(idle.id());
+    ENABLED.store(true, Ordering::SeqCst);
+    log!(Level::Info, "OK");
+}
+
+/// Called by the timer and yield interrupts with the saved context of the running thread,
+/// returns the saved context of the thread to switch to
+pub(crate) fn schedule(rsp: u64) -> u64 {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return rsp;
+    }
+    // if the interrupted code holds the scheduler lock, don't switch this time
+    match SCHEDULER.try_lock() {
+        Some(mut scheduler) => scheduler.switch(rsp),
+        None => rsp,
+    }
+}
+
+/// Handle to a spawned thread
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        SCHEDULER.lock().is_finished(self.id)
+    }
+
+    /// Blocks the current thread until the thread finishes, returns its exit code
+    pub fn join(self) -> i64 {
+        SCHEDULER.lock().current_mut().state = State::Joining(self.id);
+        // the scheduler won't pick us until the thread is finished
+        while !self.is_finished() {
+            yield_now();
+        }
+        let mut scheduler = SCHEDULER.lock();
+        let exit_code = scheduler.threads.get(&self.id).map_or(0, |t| t.exit_code);
+        let thread = scheduler.reap(self.id);
+        drop(scheduler);
+        drop(thread);
+        exit_code
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        if let Some(thread) = SCHEDULER.lock().threads.get_mut(&self.id) {
+            thread.detached = true;
+        }
+    }
+}
+
+/// Spawns a new kernel thread running `f`. It starts running on the next context switch.
+pub fn spawn<F>(f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_thread(None, f)
+}
+
+/// Like spawn, but the thread runs with `address_space` active
+pub fn spawn_in<F>(address_space: Arc<AddressSpace>, f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_thread(Some(address_space), f)
+}
+
+fn spawn_thread<F>(address_space: Option<Arc<AddressSpace>>, f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    let reaped = SCHEDULER.lock().reap_detached();
+    drop(reaped);
+
+    let stack = Stack::alloc();
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+
+    // double box so we can pass a thin pointer to thread_entry
+    let f: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(f));
+    let arg = Box::into_raw(f) as u64;
+    let rsp = unsafe { context::init_context(stack.top(), thread_entry, arg) };
+
+    log!(Level::Debug, "spawning thread {id}");
+    let mut scheduler = SCHEDULER.lock();
+    let console = scheduler.current_mut().console;
+    scheduler.threads.insert(
+        id,
+        Thread {
+            state: State::Ready,
+            rsp,
+            stack: Some(stack),
+            detached: false,
+            exit_code: 0,
+            address_space,
+            killed: None,
+            console,
+        },
+    );
+    JoinHandle { id }
+}
+
+extern "C" fn thread_entry(arg: u64) -> ! {
+    let f = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce() + Send>) };
+    f();
+    exit()
+}
+
+/// Finishes the current thread
+pub fn exit() -> ! {
+    finish_current(0);
+    loop {
+        // scheduler never picks finished threads
+        yield_now();
+    }
+}
+
+/// Marks the current thread as finished with `exit_code`. Safe to call from interrupt context,
+/// where the caller must switch to another thread afterwards (see schedule())
+pub(crate) fn finish_current(exit_code: i64) {
+    let mut scheduler = SCHEDULER.lock();
+    let id = scheduler.current;
+    if id == BOOT_THREAD_ID {
+        panic!("boot thread can't exit");
+    }
+    log!(Level::Debug, "thread {id} finished with exit code {exit_code}");
+    let current = scheduler.current_mut();
+    current.state = State::Finished;
+    current.exit_code = exit_code;
+}
+
+/// Kills thread `id`, which finishes with `exit_code` the next time it would resume in ring 3.
+/// Threads that only run kernel code are never killed. Returns false if the thread already
+/// finished.
+pub fn kill(id: ThreadId, exit_code: i64) -> bool {
+    let mut scheduler = SCHEDULER.lock();
+    match scheduler.threads.get_mut(&id) {
+        Some(thread) if thread.state != State::Finished => {
+            log!(Level::Debug, "killing thread {id}");
+            thread.killed = Some(exit_code);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// State of thread `id`, None once it was reaped
+pub fn state(id: ThreadId) -> Option<State> {
+    SCHEDULER.lock().threads.get(&id).map(|t| t.state)
+}
+
+/// Exit code of thread `id`, None until it finishes (or once it was reaped)
+pub fn exit_code(id: ThreadId) -> Option<i64> {
+    SCHEDULER
+        .lock()
+        .threads
+        .get(&id)
+        .filter(|t| t.state == State::Finished)
+        .map(|t| t.exit_code)
+}
+
+/// Puts the current thread to sleep for at least `ms` milliseconds. Safe to call from interrupt
+/// context, where the caller must switch to another thread afterwards (see schedule())
+pub(crate) fn sleep_current(ms: u64) {
+    let until = ticks() + ms_to_ticks(ms);
+    SCHEDULER.lock().current_mut().state = State::Sleeping(until);
+}
+
+fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TIMER_HZ).div_ceil(1000).max(1)
+}
+
+/// Id of the running thread
+pub fn current() -> ThreadId {
+    CURRENT.load(Ordering::SeqCst)
+}
+
+/// Virtual console (see vga) the running thread prints to
+pub fn console() -> usize {
+    // print! works before init(), when there is only the boot thread
+    if !ENABLED.load(Ordering::SeqCst) {
+        return 0;
+    }
+    SCHEDULER.lock().current_mut().console
+}
+
+/// Makes the running thread, and the threads it spawns from now on, print to `console`
+pub fn set_console(console: usize) -> Result<()> {
+    if console >= vga::CONSOLE_COUNT {
+        return err!("no console {console}");
+    }
+    SCHEDULER.lock().current_mut().console = console;
+    Ok(())
+}
+
+/// Gives the CPU to the next ready thread
+pub fn yield_now() {
+    // keep in sync with YIELD_VECTOR
+    unsafe { asm!("int 0x81") };
+}
+
+/// Blocks the current thread for at least `ms` milliseconds
+pub fn sleep(ms: u64) {
+    let until = ticks() + ms_to_ticks(ms);
+    SCHEDULER.lock().current_mut().state = State::Sleeping(until);
+    // the scheduler won't pick us until we wake up
+    while ticks() < until {
+        yield_now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicU64;
+
+    #[test_case]
+    fn test_spawn_join() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<JoinHandle> = (0..4)
+            .map(|_| {
+                let counter = counter.clone();
+                spawn(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join();
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 4);
+    }
+
+    #[test_case]
+    fn test_set_console() {
+        let console = console();
+        assert!(set_console(vga::CONSOLE_COUNT).is_err());
+        assert_eq!(super::console(), console);
+        set_console(1).unwrap();
+        assert_eq!(super::console(), 1);
+        set_console(console).unwrap();
+    }
+
+    #[test_case]
+    fn test_sleep() {
+        let start = ticks();
+        sleep(50);
+        assert!(ticks() - start >= 50 * TIMER_HZ / 1000);
+    }
+
+    #[test_case]
+    fn test_spinning_thread_is_preempted() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        static SPINS: AtomicU64 = AtomicU64::new(0);
+
+        let spinner = spawn(|| {
+            while !STOP.load(Ordering::SeqCst) {
+                SPINS.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        // never yields, so we only get back here if the timer preempts it
+        let start = ticks();
+        while ticks() - start < 5 {
+            core::hint::spin_loop();
+        }
+        STOP.store(true, Ordering::SeqCst);
+        spinner.join();
+        assert!(SPINS.load(Ordering::Relaxed) > 0);
+    }
+}
@@ -0,0 +1,70 @@
+use alloc::vec::Vec;
+use x86_64::structures::paging::{page_table::PageTableFlags, Page};
+use x86_64::VirtAddr;
+
+use crate::memory::{self, PAGE_SIZE};
+#[allow(unused)]
+use crate::prelude::*;
+
+/// Virtual address where thread stacks start being mapped
+pub const STACKS_START: u64 = 0x_5555_0000_0000;
+/// Number of pages in each stack
+pub const STACK_PAGES: u64 = 4; // 16 KiB
+
+lazy_static! {
+    /// Stacks of threads that already finished, reused before mapping new ones
+    static ref FREE_STACKS: Mutex<Vec<Stack>> = Mutex::new(Vec::new());
+    static ref NEXT_SLOT: Mutex<u64> = Mutex::new(0);
+}
+
+/// Kernel stack with an unmapped guard page right below it, so overflowing it page faults
+/// instead of silently overwriting whatever is mapped below.
+#[derive(Debug)]
+pub struct Stack {
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl Stack {
+    /// Takes a free stack or maps a new one
+    pub fn alloc() -> Stack {
+        if let Some(stack) = FREE_STACKS.lock().pop() {
+            return stack;
+        }
+
+        let slot = {
+            let mut next_slot = NEXT_SLOT.lock();
+            *next_slot += 1;
+            *next_slot - 1
+        };
+        // each slot is a guard page followed by the stack pages
+        let slot_size = (STACK_PAGES + 1) * PAGE_SIZE as u64;
+        let guard_page = VirtAddr::new(STACKS_START + slot * slot_size);
+        let bottom = guard_page + PAGE_SIZE as u64;
+        let top = bottom + STACK_PAGES * PAGE_SIZE as u64;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let first_page = Page::containing_address(bottom);
+        let last_page = Page::containing_address(top - 1u64);
+        memory::with_frame_allocator(|frame_allocator| {
+            for page in Page::range_inclusive(first_page, last_page) {
+                memory::map_virt(page, flags, frame_allocator);
+            }
+        });
+
+        Stack { bottom, top }
+    }
+
+    /// Gives the stack back to be reused by another thread
+    pub fn free(self) {
+        FREE_STACKS.lock().push(self);
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+}
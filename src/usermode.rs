@@ -0,0 +1,205 @@
+//! # User mode
+//! Running programs in ring 3.
+//!
+//! Each program gets a slot of user accessible memory: its code is copied to the bottom of the
+//! slot and its stack sits at the top, with an unmapped guard page in between. The program runs
+//! on its own kernel thread, which drops to ring 3 and only comes back to the kernel through
+//! interrupts and syscalls (see `syscall`).
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{page_table::PageTableFlags, Page};
+use x86_64::VirtAddr;
+
+use crate::gdt;
+use crate::memory::{self, PAGE_SIZE};
+#[allow(unused)]
+use crate::prelude::*;
+use crate::thread::{self, JoinHandle};
+
+/// Start of user space, where user program slots start being mapped
+pub const USER_SPACE_START: u64 = 0x_1000_0000_0000;
+/// End of user space (exclusive). Each address space has its own mappings in this range.
+pub const USER_SPACE_END: u64 = 0x_2000_0000_0000;
+/// Size of the memory slot of each program
+pub const USER_SLOT_SIZE: u64 = 0x10_0000; // 1 MiB
+/// Number of pages in a user stack
+pub const USER_STACK_PAGES: u64 = 4;
+
+/// RFLAGS user code starts with (only the interrupt flag set, so it can be preempted)
+const USER_RFLAGS: u64 = 0x202;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+
+/// Copies `code` (position independent machine code) to user memory and runs it in ring 3 on a
+/// new thread, starting at its first byte with `arg` in `rdi`.
+/// The thread's exit code is the one passed to the exit syscall.
+pub fn spawn(code: &[u8], arg: u64) -> Result<JoinHandle> {
+    let stack_size = USER_STACK_PAGES * PAGE_SIZE as u64;
+    // leave a guard page between code and stack
+    let max_code_size = USER_SLOT_SIZE - stack_size - PAGE_SIZE as u64;
+    if code.len() as u64 > max_code_size {
+        return err!(
+            "program too big ({} bytes, max is {max_code_size})",
+            code.len()
+        );
+    }
+
+    let slot = NEXT_SLOT.fetch_add(1, Ordering::SeqCst);
+    let entry = VirtAddr::new(USER_SPACE_START + slot * USER_SLOT_SIZE);
+    let stack_top = entry + USER_SLOT_SIZE;
+
+    map_user_pages(entry, code.len() as u64);
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), entry.as_mut_ptr(), code.len());
+    }
+    map_user_pages(stack_top - stack_size, stack_size);
+
+    log!(
+        Level::Debug,
+        "spawning user program at {:?} ({} bytes)",
+        entry,
+        code.len()
+    );
+    Ok(thread::spawn(move || unsafe {
+        enter_user_mode(entry, stack_top, arg)
+    }))
+}
+
+/// Maps zeroed, user accessible pages covering `[start, start + len)`
+pub fn map_user_pages(start: VirtAddr, len: u64) {
+    if len == 0 {
+        return;
+    }
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let first_page: Page = Page::containing_address(start);
+    let last_page: Page = Page::containing_address(start + (len - 1));
+    memory::with_frame_allocator(|frame_allocator| {
+        for page in Page::range_inclusive(first_page, last_page) {
+            memory::map_virt(page, flags, frame_allocator);
+            // don't leak whatever the frame had before
+            unsafe {
+                core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+            }
+        }
+    });
+}
+
+/// Drops the current thread to ring 3, jumping to `entry` with `stack_top` as stack pointer and
+/// `arg` in `rdi`. Never returns: the thread only comes back to the kernel on interrupts, which
+/// start over at the top of its kernel stack.
+///
+/// # Safety
+/// `entry` and the stack must be mapped user accessible, and the current thread must have its own
+/// kernel stack (interrupts from ring 3 land on it, see `gdt::set_kernel_stack`).
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr, arg: u64) -> ! {
+    let code_selector = gdt::user_code_selector().0 as u64;
+    let data_selector = gdt::user_data_selector().0 as u64;
+    // iretq pops rip, cs, rflags, rsp and ss, switching to the privilege level of cs
+    asm!(
+        "push {ss}",
+        "push {user_rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "iretq",
+        ss = in(reg) data_selector,
+        user_rsp = in(reg) stack_top.as_u64(),
+        rflags = in(reg) USER_RFLAGS,
+        cs = in(reg) code_selector,
+        rip = in(reg) entry.as_u64(),
+        in("rdi") arg,
+        options(noreturn),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::SEGFAULT_EXIT_CODE;
+    use crate::syscall::EFAULT;
+    use core::arch::global_asm;
+
+    // tiny user programs, copied to user memory by the tests
+    global_asm!(
+        r#"
+.section .rodata
+.global user_hello_start
+user_hello_start:
+    mov rax, 1
+    mov rdi, 1
+    lea rsi, [rip + .Lhello_msg]
+    mov rdx, 14
+    int 0x80
+    mov rax, 60
+    mov rdi, 42
+    int 0x80
+.Lhello_msg:
+    .ascii "hello, ring 3\n"
+.global user_hello_end
+user_hello_end:
+
+.global user_read_kernel_start
+user_read_kernel_start:
+    mov rax, [rdi]
+    mov rax, 60
+    mov rdi, 0
+    int 0x80
+.global user_read_kernel_end
+user_read_kernel_end:
+
+.global user_write_kernel_start
+user_write_kernel_start:
+    mov rsi, rdi
+    mov rax, 1
+    mov rdi, 1
+    mov rdx, 4
+    int 0x80
+    mov rdi, rax
+    mov rax, 60
+    int 0x80
+.global user_write_kernel_end
+user_write_kernel_end:
+.text
+"#
+    );
+
+    extern "C" {
+        static user_hello_start: u8;
+        static user_hello_end: u8;
+        static user_read_kernel_start: u8;
+        static user_read_kernel_end: u8;
+        static user_write_kernel_start: u8;
+        static user_write_kernel_end: u8;
+    }
+
+    fn program(start: &'static u8, end: &'static u8) -> &'static [u8] {
+        let start = start as *const u8;
+        let len = end as *const u8 as usize - start as usize;
+        unsafe { core::slice::from_raw_parts(start, len) }
+    }
+
+    static KERNEL_SECRET: u64 = 0xdead_beef;
+
+    #[test_case]
+    fn test_user_hello() {
+        let code = unsafe { program(&user_hello_start, &user_hello_end) };
+        let handle = spawn(code, 0).unwrap();
+        assert_eq!(handle.join(), 42);
+    }
+
+    #[test_case]
+    fn test_user_cannot_read_kernel_memory() {
+        let code = unsafe { program(&user_read_kernel_start, &user_read_kernel_end) };
+        let handle = spawn(code, &KERNEL_SECRET as *const u64 as u64).unwrap();
+        assert_eq!(handle.join(), SEGFAULT_EXIT_CODE);
+    }
+
+    #[test_case]
+    fn test_user_cannot_pass_kernel_memory_to_syscalls() {
+        let code = unsafe { program(&user_write_kernel_start, &user_write_kernel_end) };
+        let handle = spawn(code, &KERNEL_SECRET as *const u64 as u64).unwrap();
+        assert_eq!(handle.join(), -EFAULT);
+    }
+}
@@ -9,135 +9,384 @@ macro_rules! err {
 
 // TODO: error trait
 
-use spin::{Mutex, MutexGuard};
+use core::cell::UnsafeCell;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+use crate::lockdep::{DebugMutex, DebugMutexGuard, Holder};
 
 pub struct Locked<T> {
-    inner: Mutex<T>,
+    inner: DebugMutex<T>,
 }
 
 impl<T> Locked<T> {
     pub const fn new(inner: T) -> Self {
         Locked {
-            inner: Mutex::new(inner),
+            inner: DebugMutex::new(inner),
         }
     }
-    pub fn lock(&self) -> MutexGuard<T> {
-        // log!(Level::Debug, "locking {}", core::any::type_name::<T>());
+    #[track_caller]
+    pub fn lock(&self) -> DebugMutexGuard<'_, T> {
         self.inner.lock()
     }
-    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
-        // log!(Level::Debug, "locking {}", core::any::type_name::<T>());
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<DebugMutexGuard<'_, T>> {
         self.inner.try_lock()
     }
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
 }
 
-/// Blocks until both locks are acquired, returns the two acquired locks
-pub fn lock_both<'m1_life, 'm2_life, T1, T2>(
-    m1: &'m1_life Mutex<T1>,
-    m2: &'m2_life Mutex<T2>,
-) -> (MutexGuard<'m1_life, T1>, MutexGuard<'m2_life, T2>) {
-    loop {
-        match (m1.try_lock(), m2.try_lock()) {
-            (Some(g1), Some(g2)) => return (g1, g2),
-            _ => continue,
-        };
+/// Spin lock that keeps interrupts disabled while it is held, for data that interrupt handlers
+/// use too: a handler can't run (and spin forever) while the code it interrupted holds the lock.
+///
+/// Guards nest: each one enables interrupts again on drop only if they were enabled when it was
+/// locked, so drop them in the reverse order they were locked (as scopes do).
+pub struct IrqMutex<T> {
+    inner: DebugMutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<DebugMutexGuard<'a, T>>,
+    enable_interrupts: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(inner: T) -> Self {
+        IrqMutex {
+            inner: DebugMutex::new(inner),
+        }
+    }
+
+    /// A lock with a place in the global lock order (see `lockdep::rank`)
+    pub const fn ranked(inner: T, rank: u8) -> Self {
+        IrqMutex {
+            inner: DebugMutex::ranked(inner, rank),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enable_interrupts = interrupts::are_enabled();
+        interrupts::disable();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            enable_interrupts,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let enable_interrupts = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                enable_interrupts,
+            }),
+            None => {
+                if enable_interrupts {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn holder(&self) -> Option<Holder> {
+        self.inner.holder()
     }
 }
 
-const CONCURRENT_DEQUE_SIZE: usize = 4096;
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
 
-/// Blocking, concurrent circular FIFO queue
-/// Pushing appends to beginning of queue
-/// Popping removes from end of queue
-pub struct ConcurrentDeque<T> {
-    items: [T; CONCURRENT_DEQUE_SIZE],
-    end: Mutex<usize>,
-    start: Mutex<usize>,
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
 }
 
-impl<T: Copy + Default> ConcurrentDeque<T> {
-    pub fn new() -> Self {
-        ConcurrentDeque {
-            items: [T::default(); CONCURRENT_DEQUE_SIZE],
-            start: Mutex::new(0),
-            end: Mutex::new(0),
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock before an interrupt can come in
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enable_interrupts {
+            interrupts::enable();
         }
     }
+}
 
-    pub fn is_empty(&self) -> bool {
-        let (start, end) = lock_both(&self.start, &self.end);
+/// Lock-free single-producer/single-consumer FIFO queue holding up to N items (a power of two).
+/// Pushing never blocks, so an interrupt handler can be the producer: when the queue is full the
+/// new item is dropped and counted (see dropped()).
+///
+/// Only one thread (or interrupt handler) may push and only one may pop at a time.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    items: UnsafeCell<[MaybeUninit<T>; N]>,
+    /// Total number of items popped, only written by the consumer
+    head: AtomicUsize,
+    /// Total number of items pushed, only written by the producer
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
 
-        *start == *end
+// the atomics hand each slot over between the producer and the consumer
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        // the counters wrap around usize::MAX, which keeps them in step with the slots only then
+        assert!(N.is_power_of_two());
+        RingBuffer {
+            // an array of MaybeUninit needs no initialization
+            items: UnsafeCell::new(unsafe { MaybeUninit::uninit().assume_init() }),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
     }
 
-    pub fn is_full(&self) -> bool {
-        let (start, end) = lock_both(&self.start, &self.end);
+    /// Appends an item. Returns false (and drops it) when the queue is full.
+    pub fn push(&self, item: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        // the consumer doesn't read this slot until tail moves past it
+        unsafe { (*self.items.get())[tail % N] = MaybeUninit::new(item) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
 
-        if *start == 0 && *end == CONCURRENT_DEQUE_SIZE - 1 || (*start == *end + 1) {
-            return true;
+    /// Removes the oldest item
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
         }
-        false
+        // written by push() before it moved tail
+        let item = unsafe { (*self.items.get())[head % N].assume_init() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(item)
     }
 
-    pub fn push(&mut self, item: T) -> Result<()> {
-        if self.is_full() {
-            return err!("Queue is full");
+    /// Returns the oldest item without removing it (only the consumer may call this)
+    pub fn peek(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
         }
+        Some(unsafe { (*self.items.get())[head % N].assume_init() })
+    }
 
-        let mut end = self.end.lock();
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
 
-        // put at end position
-        self.items[*end] = item;
-        let old_end = *end;
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        // end goes to beginning of queue when it reaches the end
-        *end = (old_end + 1) % CONCURRENT_DEQUE_SIZE;
-        Ok(())
+    pub fn is_full(&self) -> bool {
+        self.len() == N
     }
 
-    /// Pops item from end of queue
-    pub fn pop_end(&mut self) -> Option<T> {
-        if self.is_empty() {
-            return None;
-        }
+    /// Number of items push() dropped because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
 
-        let mut end = self.end.lock();
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        RingBuffer::new()
+    }
+}
 
-        let old_end = *end;
+/// Fixed size FIFO queue for input events. When full, the oldest events are dropped, since
+/// nobody is reading them and the newest ones are the interesting ones.
+pub struct EventQueue<T: Copy, const N: usize> {
+    events: [Option<T>; N],
+    start: usize,
+    len: usize,
+}
 
-        // end goes to end of queue when it reaches the beginning (just as self.start)
-        *end = (old_end - 1 + CONCURRENT_DEQUE_SIZE) % CONCURRENT_DEQUE_SIZE;
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        EventQueue {
+            events: [None; N],
+            start: 0,
+            len: 0,
+        }
+    }
 
-        Some(self.items[old_end])
+    pub fn push(&mut self, event: T) {
+        if self.len == N {
+            self.start = (self.start + 1) % N;
+            self.len -= 1;
+        }
+        self.events[(self.start + self.len) % N] = Some(event);
+        self.len += 1;
     }
 
-    /// Pops item from start of the queue
     pub fn pop(&mut self) -> Option<T> {
-        if self.is_empty() {
+        if self.len == 0 {
             return None;
         }
-        let mut start = self.start.lock();
-        let old_start = *start;
+        let event = self.events[self.start].take();
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        event
+    }
+}
 
-        // start goes to beginning of queue when it reaches the end (just as self.end)
-        *start = (old_start + 1) % CONCURRENT_DEQUE_SIZE;
+impl<T: Copy, const N: usize> Default for EventQueue<T, N> {
+    fn default() -> Self {
+        EventQueue::new()
+    }
+}
 
-        Some(self.items[old_start])
+/// Local APIC id of the CPU, read by `init_cpu_id` (there's a single CPU)
+static CPU_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Reads the CPU's local APIC id once, so `cpu_id` (called on every lock) doesn't run CPUID
+pub fn init_cpu_id() {
+    // __cpuid is only safe on newer compilers
+    #[allow(unused_unsafe)]
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    CPU_ID.store(cpuid.ebx >> 24, Ordering::Relaxed);
+}
+
+/// Local APIC id of the CPU running this
+pub fn cpu_id() -> u32 {
+    CPU_ID.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_irq_mutex_nesting() {
+        let (outer, inner) = (IrqMutex::new(1), IrqMutex::new(2));
+        assert!(interrupts::are_enabled());
+        {
+            let _outer = outer.lock();
+            assert!(!interrupts::are_enabled());
+            {
+                let mut inner = inner.lock();
+                *inner += 1;
+                assert!(outer.try_lock().is_none());
+            }
+            // the inner guard leaves them disabled for the outer one
+            assert!(!interrupts::are_enabled());
+        }
+        assert!(interrupts::are_enabled());
+        assert_eq!(*inner.lock(), 3);
+
+        // interrupts stay disabled if they already were
+        interrupts::without_interrupts(|| {
+            drop(outer.lock());
+            assert!(!interrupts::are_enabled());
+        });
     }
 
-    /// Synchronizes two ConcurrentDeque queues
-    /// Makes the two queues equal to push[pop.start, push.end]
-    pub fn sync(push: &mut ConcurrentDeque<T>, pop: &mut ConcurrentDeque<T>) {
-        let (mut push_start, push_end) = lock_both(&push.start, &push.end);
-        let (pop_start, mut pop_end) = lock_both(&pop.start, &pop.end);
+    #[test_case]
+    fn test_event_queue_drops_oldest() {
+        let mut queue: EventQueue<usize, 4> = EventQueue::new();
+        assert_eq!(queue.pop(), None);
+        for i in 0..6 {
+            queue.push(i);
+        }
+        assert_eq!(queue.pop(), Some(2));
+        queue.push(6);
+        for i in 3..=6 {
+            assert_eq!(queue.pop(), Some(i));
+        }
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test_case]
+    fn test_ring_buffer_wraps_around() {
+        let ring: RingBuffer<usize, 4> = RingBuffer::new();
+        // far more items than slots, never more than 3 at a time
+        for i in 0..20 {
+            assert!(ring.push(i));
+            if i >= 2 {
+                assert_eq!(ring.pop(), Some(i - 2));
+            }
+        }
+        assert_eq!(ring.pop(), Some(18));
+        assert_eq!(ring.pop(), Some(19));
+        assert_eq!(ring.pop(), None);
+        assert_eq!(ring.dropped(), 0);
+    }
 
-        *push_start = *pop_start;
-        let old_pop_end = *pop_end;
-        *pop_end = *push_end;
+    #[test_case]
+    fn test_ring_buffer_full_and_empty() {
+        let ring: RingBuffer<u8, 4> = RingBuffer::new();
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(), None);
+        for i in 0..4 {
+            assert!(ring.push(i));
+        }
+        assert!(ring.is_full());
+        // the newest items are the ones dropped
+        assert!(!ring.push(4));
+        assert!(!ring.push(5));
+        assert_eq!(ring.dropped(), 2);
+        assert_eq!(ring.pop(), Some(0));
+        assert!(ring.push(6));
+        assert!(ring.is_full());
+        for i in [1, 2, 3, 6] {
+            assert_eq!(ring.pop(), Some(i));
+        }
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(), None);
+    }
 
-        for i in old_pop_end..*push_end {
-            let idx = (i + CONCURRENT_DEQUE_SIZE) % CONCURRENT_DEQUE_SIZE; // need this in case we circled back to start of list
-            pop.items[idx] = push.items[idx];
+    #[test_case]
+    fn test_ring_buffer_interleaved() {
+        // a producer thread racing with the consumer, like the keyboard interrupt with getc()
+        static RING: RingBuffer<usize, 8> = RingBuffer::new();
+        const ITEMS: usize = 1000;
+        let producer = crate::thread::spawn(|| {
+            let mut i = 0;
+            while i < ITEMS {
+                match RING.push(i) {
+                    true => i += 1,
+                    false => crate::thread::yield_now(),
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < ITEMS {
+            match RING.pop() {
+                Some(i) => {
+                    assert_eq!(i, expected);
+                    expected += 1;
+                }
+                None => crate::thread::yield_now(),
+            }
         }
+        producer.join();
+        assert!(RING.is_empty());
     }
 }
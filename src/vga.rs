@@ -8,19 +8,78 @@
 //!  let mut vga = vga::Vga::new(fg_color, bg_color);
 //!  writeln!(vga, "hello {}", 12);
 //!  ```
+//!
+//!  The blinking hardware cursor follows the writer. Apps can move it to write anywhere on the
+//!  screen:
+//!  ```
+//!  let mut vga = vga::stdout();
+//!  vga.set_cursor(12, 40);
+//!  write!(vga, "centered-ish");
+//!  ```
+//!
+//!  Rows scrolled off the top of the screen go to a scrollback history (on the heap), which
+//!  Shift+PageUp/PageDown browse. Writing anything goes back to the live screen.
+//!
+//!  There are CONSOLE_COUNT virtual consoles, each with its own off-screen buffer, cursor and
+//!  colors. Only the active one is copied to the screen, Alt+F1..F6 switches between them.
+//!  `print!` writes to the console of the running thread (see `thread::set_console`; async tasks
+//!  get theirs with `Task::on_console`), keys typed go to the active one, and LOG_CONSOLE mirrors
+//!  kernel logs.
+//!
+//!  `print!` never waits for a console: when it's busy (or older output is still queued) the
+//!  text goes to a print queue, which whoever prints next and `print_daemon` write out in order.
+//!
+//!  After `framebuffer::init` the consoles are drawn on a pixel framebuffer with a bitmap font
+//!  instead, centered and scaled up as much as it fits, and everything above works the same.
 
+use crate::ansi::{self, Action, Csi};
+use crate::allocator;
+use crate::framebuffer::{font::Font, font::FONT, Framebuffer, Rgb};
+use crate::lockdep::rank;
 #[allow(unused)]
 use crate::prelude::*;
+use crate::{task, thread};
+use alloc::collections::VecDeque;
+use core::fmt;
+use core::future::Future;
+use core::ops::DerefMut;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures::task::AtomicWaker;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
 
 // use crate::util::Result;
 
-lazy_static! {
-    pub static ref VGA: Mutex<Vga> = Mutex::new(Vga::new(Color::White, Color::Black));
-}
+/// Number of virtual consoles, shown with Alt+F1..F6
+pub const CONSOLE_COUNT: usize = 6;
+/// Console that mirrors kernel logs (Alt+F6)
+pub const LOG_CONSOLE: usize = CONSOLE_COUNT - 1;
+
+/// Virtual consoles. Only the active one touches the VGA hardware.
+pub static CONSOLES: [IrqMutex<Vga>; CONSOLE_COUNT] =
+    [const { IrqMutex::ranked(Vga::new(Color::White, Color::Black), rank::CONSOLE) };
+        CONSOLE_COUNT];
+static ACTIVE_CONSOLE: AtomicUsize = AtomicUsize::new(0);
+/// Where the active console is drawn when it isn't the VGA text buffer. Only locked by whoever
+/// holds the active console.
+static FRAMEBUFFER_SCREEN: Mutex<Option<FramebufferScreen>> = Mutex::new(None);
+
+/// Characters the print queue holds
+const PRINT_QUEUE_SIZE: usize = 2048;
+/// Bytes written to a console at once when draining the print queue
+const PRINT_BATCH_SIZE: usize = 128;
+/// Output that couldn't be written right away, in order
+static PRINT_QUEUE: RingBuffer<QueuedChar, PRINT_QUEUE_SIZE> = RingBuffer::new();
+// the queue takes one producer and one consumer at a time, these make sure of that
+static PRINT_PRODUCER: IrqMutex<()> = IrqMutex::ranked((), rank::PRINT_PRODUCER);
+static PRINT_CONSUMER: Mutex<()> = Mutex::new(());
+static PRINT_WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED_PRINT_BYTES: AtomicUsize = AtomicUsize::new(0);
 
 #[macro_export]
 macro_rules! print {
-    ($($tt:tt)*) => (write!($crate::vga::VGA.lock(), "{}", format_args!($($tt)*)).unwrap());
+    ($($tt:tt)*) => ($crate::vga::print(format_args!($($tt)*)));
 }
 
 #[macro_export]
@@ -28,13 +87,236 @@ macro_rules! println {
     ($($tt:tt)*) => ($crate::print!("{}\n", format_args!($($tt)*)));
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 const VGA_BUFFER_ADDRESS: *mut u8 = 0xb8000 as *mut u8;
 
-/// Acquires lock for public instance of VGA
-pub fn stdout() -> MutexGuard<'static, Vga> {
-    VGA.lock()
+/// Rows of scrollback history each console keeps by default (each takes 160 bytes of heap)
+pub const DEFAULT_SCROLLBACK_LINES: usize = 50;
+
+// CRT controller, which draws the cursor
+const CRTC_INDEX_PORT: u16 = 0x3D4;
+const CRTC_DATA_PORT: u16 = 0x3D5;
+const CURSOR_START_REGISTER: u8 = 0x0A;
+const CURSOR_END_REGISTER: u8 = 0x0B;
+const CURSOR_LOCATION_HIGH_REGISTER: u8 = 0x0E;
+const CURSOR_LOCATION_LOW_REGISTER: u8 = 0x0F;
+const CURSOR_DISABLED: u8 = 1 << 5;
+/// Scanlines of a character cell
+const CHARACTER_HEIGHT: u8 = 16;
+
+/// Acquires lock for the console of the running thread
+#[track_caller]
+pub fn stdout() -> IrqMutexGuard<'static, Vga> {
+    // what was printed before goes first
+    drain_print_queue();
+    CONSOLES
+        .get(thread::console())
+        .unwrap_or(&CONSOLES[0])
+        .lock()
+}
+
+/// Shows the first console, replacing whatever the firmware left on screen
+pub fn init() {
+    CONSOLES[0].lock().writer.activate();
+}
+
+/// Index of the console on screen
+pub fn active_console() -> usize {
+    ACTIVE_CONSOLE.load(Ordering::SeqCst)
+}
+
+/// Shows console `index`. Returns false when there's no such console, or when one of the two
+/// consoles involved is busy: this never waits, so interrupt handlers can call it.
+pub fn switch_console(index: usize) -> bool {
+    if index >= CONSOLE_COUNT {
+        return false;
+    }
+    let active = active_console();
+    if index == active {
+        return true;
+    }
+    let (mut old, mut new) = match (CONSOLES[active].try_lock(), CONSOLES[index].try_lock()) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return false,
+    };
+    old.writer.active = false;
+    new.writer.activate();
+    ACTIVE_CONSOLE.store(index, Ordering::SeqCst);
+    true
+}
+
+/// Pixels the consoles take on a framebuffer when drawn with `font`
+pub fn framebuffer_min_size(font: &Font) -> (usize, usize) {
+    (BUFFER_WIDTH * font.width(), BUFFER_HEIGHT * font.height())
+}
+
+/// Draws the consoles on `framebuffer` (with the embedded font) instead of the VGA text buffer
+pub fn use_framebuffer(framebuffer: Framebuffer) {
+    let screen = FramebufferScreen::new(framebuffer, *FONT);
+    let mut console = lock_active_console();
+    *FRAMEBUFFER_SCREEN.lock() = Some(screen);
+    console.writer.activate();
+}
+
+/// Runs `f` with the framebuffer the consoles are drawn on, or returns None in text mode.
+/// Letters drawn over are drawn again when they change.
+pub fn with_framebuffer<R>(f: impl FnOnce(&mut Framebuffer) -> R) -> Option<R> {
+    let _console = lock_active_console();
+    let mut screen = FRAMEBUFFER_SCREEN.lock();
+    screen.as_mut().map(|screen| f(&mut screen.framebuffer))
+}
+
+/// Locks the console on screen, which FRAMEBUFFER_SCREEN needs
+fn lock_active_console() -> IrqMutexGuard<'static, Vga> {
+    loop {
+        // the active console can change while we wait for its lock
+        let console = CONSOLES[active_console()].lock();
+        if console.writer.active {
+            return console;
+        }
+    }
+}
+
+/// Writes to the console of the running thread, what `print!` does
+pub fn print(args: fmt::Arguments) {
+    print_to(thread::console(), args);
+}
+
+/// Writes a log message to LOG_CONSOLE
+pub fn log_print(args: fmt::Arguments) {
+    print_to(LOG_CONSOLE, args);
+}
+
+/// Writes to console `index` right away if it's free and nothing is queued before, queues the
+/// text otherwise. Never waits, so interrupt handlers can print.
+pub fn print_to(index: usize, args: fmt::Arguments) {
+    let index = index.min(CONSOLE_COUNT - 1);
+    drain_print_queue();
+    if PRINT_QUEUE.is_empty() {
+        if let Some(mut console) = CONSOLES[index].try_lock() {
+            let _ = console.write_fmt(args);
+            return;
+        }
+    }
+    let producer = PRINT_PRODUCER.lock();
+    let _ = QueueWriter(index as u8).write_fmt(args);
+    drop(producer);
+    PRINT_WAKER.wake();
+}
+
+/// Bytes of output lost because the print queue was full
+pub fn dropped_print_bytes() -> usize {
+    DROPPED_PRINT_BYTES.load(Ordering::Relaxed)
+}
+
+/// Writes out the print queue whenever something is queued. Spawn it on the executor.
+pub async fn print_daemon() {
+    loop {
+        PrintQueued.await;
+        drain_print_queue();
+        // whatever is left waits for its console to be free
+        task::yield_now().await;
+    }
+}
+
+/// Writes out as much of the print queue as it can without waiting for a console
+fn drain_print_queue() {
+    let Some(_consumer) = PRINT_CONSUMER.try_lock() else {
+        return;
+    };
+    let mut held: Option<(usize, IrqMutexGuard<Vga>)> = None;
+    let mut batch = Batch::new();
+    // peek before popping, so nothing leaves the queue without a console to go to
+    while let Some(queued) = PRINT_QUEUE.peek() {
+        let index = queued.console as usize;
+        if held.as_ref().map(|(held, _)| *held) != Some(index) {
+            if let Some((_, console)) = held.as_mut() {
+                batch.flush(console);
+            }
+            held = match CONSOLES[index].try_lock() {
+                Some(console) => Some((index, console)),
+                None => break,
+            };
+        }
+        PRINT_QUEUE.pop();
+        let (_, console) = held.as_mut().unwrap();
+        batch.push(queued.c, console);
+    }
+    if let Some((_, console)) = held.as_mut() {
+        batch.flush(console);
+    }
+}
+
+/// Character waiting in the print queue
+#[derive(Debug, Clone, Copy)]
+struct QueuedChar {
+    console: u8,
+    c: char,
+}
+
+/// Queues what is written to it for a console. Only use it holding PRINT_PRODUCER.
+struct QueueWriter(u8);
+
+impl fmt::Write for QueueWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if !PRINT_QUEUE.push(QueuedChar { console: self.0, c }) {
+                DROPPED_PRINT_BYTES.fetch_add(c.len_utf8(), Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Characters collected to write them to a console at once (the heap may be busy, so it's on
+/// the stack)
+struct Batch {
+    bytes: [u8; PRINT_BATCH_SIZE],
+    len: usize,
+}
+
+impl Batch {
+    fn new() -> Self {
+        Batch {
+            bytes: [0; PRINT_BATCH_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, c: char, console: &mut Vga) {
+        if self.len + c.len_utf8() > PRINT_BATCH_SIZE {
+            self.flush(console);
+        }
+        self.len += c.encode_utf8(&mut self.bytes[self.len..]).len();
+    }
+
+    fn flush(&mut self, console: &mut Vga) {
+        // only whole characters were pushed
+        let text = core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default();
+        let _ = console.write_str(text);
+        self.len = 0;
+    }
+}
+
+/// Resolves once something is in the print queue
+struct PrintQueued;
+
+impl Future for PrintQueued {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if !PRINT_QUEUE.is_empty() {
+            return Poll::Ready(());
+        }
+        PRINT_WAKER.register(cx.waker());
+        match PRINT_QUEUE.is_empty() {
+            true => Poll::Pending,
+            false => {
+                PRINT_WAKER.take();
+                Poll::Ready(())
+            }
+        }
+    }
 }
 
 /// Foreground (text) colors
@@ -59,48 +341,378 @@ pub enum Color {
     Yellow = 0xe,
     White = 0xf,
 }
+/// VGA colors in ANSI order (black, red, green, yellow, blue, magenta, cyan, white), then their
+/// bright variants
+const ANSI_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+
+/// Makes a color bright (bold text)
+const BRIGHT: u8 = 0x8;
+
+/// RGB values of the 16 VGA colors, as the VGA text mode shows them
+const PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00),
+    Rgb::new(0x00, 0x00, 0xAA),
+    Rgb::new(0x00, 0xAA, 0x00),
+    Rgb::new(0x00, 0xAA, 0xAA),
+    Rgb::new(0xAA, 0x00, 0x00),
+    Rgb::new(0xAA, 0x00, 0xAA),
+    Rgb::new(0xAA, 0x55, 0x00),
+    Rgb::new(0xAA, 0xAA, 0xAA),
+    Rgb::new(0x55, 0x55, 0x55),
+    Rgb::new(0x55, 0x55, 0xFF),
+    Rgb::new(0x55, 0xFF, 0x55),
+    Rgb::new(0x55, 0xFF, 0xFF),
+    Rgb::new(0xFF, 0x55, 0x55),
+    Rgb::new(0xFF, 0x55, 0xFF),
+    Rgb::new(0xFF, 0xFF, 0x55),
+    Rgb::new(0xFF, 0xFF, 0xFF),
+];
+
 #[repr(C)]
 pub struct Vga {
     pub fg_color: Color,
     pub bg_color: Color,
+    /// Colors ESC[0m goes back to
+    default_colors: (Color, Color),
+    /// Set by ESC[1m, brightens fg_color
+    bold: bool,
+    /// Set by ESC[7m, swaps fg_color and bg_color
+    reverse: bool,
+    parser: ansi::Parser,
     writer: Writer,
 }
 
 impl Vga {
-    pub fn new(fg_color: Color, bg_color: Color) -> Vga {
+    /// A console that isn't shown anywhere, see CONSOLES for the ones that can be
+    pub const fn new(fg_color: Color, bg_color: Color) -> Vga {
         Vga {
             fg_color,
             bg_color,
+            default_colors: (fg_color, bg_color),
+            bold: false,
+            reverse: false,
+            parser: ansi::Parser::new(),
             writer: Writer::new(),
         }
     }
 
     pub fn backspace(&mut self) {
+        self.writer.scroll_view(0);
         self.writer.backspace();
+        self.writer.update_cursor();
     }
 
     fn color(&self) -> u8 {
-        ((self.bg_color as u8) << 4) | self.fg_color as u8
+        let mut fg = self.fg_color as u8;
+        if self.bold {
+            fg |= BRIGHT;
+        }
+        let bg = self.bg_color as u8;
+        match self.reverse {
+            true => (fg << 4) | bg,
+            false => (bg << 4) | fg,
+        }
     }
 
-    #[allow(dead_code)]
-    fn clear(&mut self) {
+    pub fn clear(&mut self) {
+        self.writer.scroll_view(0);
         self.writer.clear();
+        self.writer.update_cursor();
+    }
+
+    /// Moves the cursor, where the next character is written. Positions off screen are clamped to
+    /// the last row/column.
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        self.writer.scroll_view(0);
+        self.writer.move_to(row, col);
+        self.writer.update_cursor();
+    }
+
+    /// Row and column where the next character is written
+    pub fn cursor(&self) -> (usize, usize) {
+        let position = self.writer.cursor_position();
+        (position.row, position.col)
+    }
+
+    /// Shows the blinking cursor (it is shown at boot)
+    pub fn enable_cursor(&mut self) {
+        self.writer.cursor_enabled = true;
+        self.writer.update_cursor_shape();
+    }
+
+    pub fn disable_cursor(&mut self) {
+        self.writer.cursor_enabled = false;
+        self.writer.update_cursor_shape();
+    }
+
+    /// Changes the cursor shape and shows the cursor
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        self.writer.cursor_shape = shape;
+        self.enable_cursor();
+    }
+
+    /// Shows `lines` older rows of the scrollback history
+    pub fn scroll_back(&mut self, lines: usize) {
+        self.writer.scroll_view(self.writer.view_offset + lines);
+    }
+
+    /// Shows `lines` newer rows, back to the live screen at most
+    pub fn scroll_forward(&mut self, lines: usize) {
+        self.writer
+            .scroll_view(self.writer.view_offset.saturating_sub(lines));
+    }
+
+    /// Goes back to the live screen after scroll_back()
+    pub fn snap_to_bottom(&mut self) {
+        self.writer.scroll_view(0);
+    }
+
+    /// Changes how many rows the scrollback history keeps, dropping the oldest ones if needed
+    pub fn set_scrollback_lines(&mut self, lines: usize) {
+        self.writer.scroll_view(0);
+        self.writer.history_lines = lines;
+        while self.writer.history.len() > lines {
+            self.writer.history.pop_front();
+        }
+        self.writer.history.shrink_to(lines);
+    }
+
+    pub fn scrollback_lines(&self) -> usize {
+        self.writer.history_lines
+    }
+
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::Print(c) => self.writer.write(to_code_page_437(c), self.color()),
+            Action::Escape('7') => self.writer.saved_pos = self.writer.cur_pos,
+            Action::Escape('8') => self.writer.cur_pos = self.writer.saved_pos,
+            Action::Escape(_) => {}
+            Action::Csi(csi) => self.apply_csi(&csi),
+        }
+    }
+
+    fn apply_csi(&mut self, csi: &Csi) {
+        let n = csi.param(0, 1) as usize;
+        let Position { row, col } = self.writer.cursor_position();
+        let color = self.color();
+        match (csi.private, csi.final_byte) {
+            (false, 'm') => self.set_graphics(csi.params()),
+            // cursor movement, rows and columns count from 1
+            (false, 'A') => self.writer.move_to(row.saturating_sub(n), col),
+            (false, 'B') => self.writer.move_to(row + n, col),
+            (false, 'C') => self.writer.move_to(row, col + n),
+            (false, 'D') => self.writer.move_to(row, col.saturating_sub(n)),
+            (false, 'E') => self.writer.move_to(row + n, 0),
+            (false, 'F') => self.writer.move_to(row.saturating_sub(n), 0),
+            (false, 'G') => self.writer.move_to(row, n - 1),
+            (false, 'd') => self.writer.move_to(n - 1, col),
+            (false, 'H') | (false, 'f') => {
+                let col = csi.param(1, 1) as usize;
+                self.writer.move_to(n - 1, col - 1)
+            }
+            (false, 'J') => self.writer.erase_display(csi.param(0, 0), color),
+            (false, 'K') => self.writer.erase_line(csi.param(0, 0), color),
+            (false, 's') => self.writer.saved_pos = self.writer.cur_pos,
+            (false, 'u') => self.writer.cur_pos = self.writer.saved_pos,
+            (false, 'r') => {
+                let bottom = csi.param(1, BUFFER_HEIGHT as u16) as usize;
+                self.writer.set_scroll_region(n - 1, bottom - 1);
+            }
+            (false, 'S') => self.writer.scroll_up(n, color),
+            (false, 'T') => self.writer.scroll_down(n, color),
+            // show/hide the cursor
+            (true, 'h') if csi.params().contains(&25) => self.enable_cursor(),
+            (true, 'l') if csi.params().contains(&25) => self.disable_cursor(),
+            _ => {}
+        }
+    }
+
+    /// Select Graphic Rendition (ESC[...m): colors, bold and reverse video
+    fn set_graphics(&mut self, params: &[u16]) {
+        // ESC[m is a reset
+        for param in params.iter().copied().chain(params.is_empty().then_some(0)) {
+            match param {
+                0 => {
+                    (self.fg_color, self.bg_color) = self.default_colors;
+                    self.bold = false;
+                    self.reverse = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                30..=37 => self.fg_color = ANSI_COLORS[param as usize - 30],
+                39 => self.fg_color = self.default_colors.0,
+                40..=47 => self.bg_color = ANSI_COLORS[param as usize - 40],
+                49 => self.bg_color = self.default_colors.1,
+                90..=97 => self.fg_color = ANSI_COLORS[param as usize - 90 + 8],
+                100..=107 => self.bg_color = ANSI_COLORS[param as usize - 100 + 8],
+                _ => {}
+            }
+        }
     }
 }
 
 impl core::fmt::Write for Vga {
+    /// Writes text, which may contain ANSI escape sequences (see the ansi module)
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let byte_vec: &[u8] = s.as_bytes();
-
-        // characters in byte_vec cannot be out of bounds (0 <= c <= 255)
-        for &byte in byte_vec.iter() {
-            self.writer.write(byte, self.color());
+        self.writer.scroll_view(0);
+        for c in s.chars() {
+            if let Some(action) = self.parser.advance(c) {
+                self.apply(action);
+            }
         }
+        self.writer.update_cursor();
         Ok(())
     }
 }
 
+/// Block shown for characters the VGA font doesn't have
+const UNDEFINED_CHAR: u8 = 0xfe;
+
+/// Byte of `c` in code page 437, the character set of the VGA text mode font
+fn to_code_page_437(c: char) -> u8 {
+    if c.is_ascii() {
+        return c as u8;
+    }
+    match c {
+        'Ç' => 0x80,
+        'ü' => 0x81,
+        'é' => 0x82,
+        'â' => 0x83,
+        'ä' => 0x84,
+        'à' => 0x85,
+        'ç' => 0x87,
+        'ê' => 0x88,
+        'ë' => 0x89,
+        'è' => 0x8a,
+        'ï' => 0x8b,
+        'î' => 0x8c,
+        'ì' => 0x8d,
+        'Ä' => 0x8e,
+        'É' => 0x90,
+        'ô' => 0x93,
+        'ö' => 0x94,
+        'ò' => 0x95,
+        'û' => 0x96,
+        'ù' => 0x97,
+        'ÿ' => 0x98,
+        'Ö' => 0x99,
+        'Ü' => 0x9a,
+        'á' => 0xa0,
+        'í' => 0xa1,
+        'ó' => 0xa2,
+        'ú' => 0xa3,
+        'ñ' => 0xa4,
+        'Ñ' => 0xa5,
+        'ª' => 0xa6,
+        'º' => 0xa7,
+        '¬' => 0xaa,
+        '¢' => 0x9b,
+        '£' => 0x9c,
+        '°' => 0xf8,
+        '²' => 0xfd,
+        '§' => 0x15,
+        // no accented capitals (but the ones above) nor ã/õ, use the bare letter
+        'Á' | 'À' | 'Â' | 'Ã' => b'A',
+        'È' | 'Ê' | 'Ë' => b'E',
+        'Í' | 'Ì' | 'Î' | 'Ï' => b'I',
+        'Ó' | 'Ò' | 'Ô' | 'Õ' => b'O',
+        'Ú' | 'Ù' | 'Û' => b'U',
+        'ã' => b'a',
+        'õ' => b'o',
+        '´' => b'\'',
+        '¨' => b'"',
+        _ => UNDEFINED_CHAR,
+    }
+}
+
+/// Scanlines the cursor covers in a character cell, from 0 (top) to 15 (bottom)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorShape {
+    pub start: u8,
+    pub end: u8,
+}
+
+impl CursorShape {
+    pub const UNDERLINE: CursorShape = CursorShape { start: 14, end: 15 };
+    pub const BLOCK: CursorShape = CursorShape {
+        start: 0,
+        end: CHARACTER_HEIGHT - 1,
+    };
+}
+
+/// Registers of the CRT controller, accessed by writing their index and then the data port
+struct CrtController {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl CrtController {
+    const fn new() -> Self {
+        CrtController {
+            index: Port::new(CRTC_INDEX_PORT),
+            data: Port::new(CRTC_DATA_PORT),
+        }
+    }
+
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+
+    /// Hides the cursor when `shape` is None
+    fn set_cursor_shape(&mut self, shape: Option<CursorShape>) {
+        // the high bits of these registers are about other things
+        let start = self.read(CURSOR_START_REGISTER) & 0b1100_0000;
+        let end = self.read(CURSOR_END_REGISTER) & 0b1110_0000;
+        match shape {
+            Some(shape) => {
+                self.write(CURSOR_START_REGISTER, start | (shape.start & 0b1_1111));
+                self.write(CURSOR_END_REGISTER, end | (shape.end & 0b1_1111));
+            }
+            None => self.write(CURSOR_START_REGISTER, start | CURSOR_DISABLED),
+        }
+    }
+
+    fn set_cursor_location(&mut self, offset: u16) {
+        self.write(CURSOR_LOCATION_HIGH_REGISTER, (offset >> 8) as u8);
+        self.write(CURSOR_LOCATION_LOW_REGISTER, offset as u8);
+    }
+
+    #[cfg(test)]
+    fn cursor_location(&mut self) -> u16 {
+        (self.read(CURSOR_LOCATION_HIGH_REGISTER) as u16) << 8
+            | self.read(CURSOR_LOCATION_LOW_REGISTER) as u16
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 struct Position {
@@ -131,7 +743,7 @@ impl Position {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct Letter {
     byte: u8,
     color: u8,
@@ -150,25 +762,238 @@ struct Buffer {
 }
 
 impl Buffer {
+    const fn new() -> Buffer {
+        Buffer {
+            letters: [[Letter { byte: 0, color: 0 }; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        }
+    }
+
     fn clear(&mut self) {
         self.letters = [[Letter { byte: 0, color: 0 }; BUFFER_WIDTH]; BUFFER_HEIGHT];
     }
 }
 
+/// The VGA text buffer. Only the active console writes to it.
+fn hardware_buffer() -> &'static mut Buffer {
+    unsafe { &mut *(VGA_BUFFER_ADDRESS as *mut Buffer) }
+}
+
+/// Text screen drawn with a bitmap font on a framebuffer, in place of the VGA text buffer
+struct FramebufferScreen<B = &'static mut [u8]> {
+    framebuffer: Framebuffer<B>,
+    font: Font,
+    /// Font pixels are drawn as squares this big, the largest that fit the whole screen
+    scale: usize,
+    /// Top left pixel of the text, which is centered
+    origin: (usize, usize),
+    /// Letters on the framebuffer, to only draw the ones that change
+    shown: Buffer,
+    cursor: Option<Position>,
+    cursor_shape: Option<CursorShape>,
+}
+
+impl<B: DerefMut<Target = [u8]>> FramebufferScreen<B> {
+    fn new(mut framebuffer: Framebuffer<B>, font: Font) -> Self {
+        let (width, height) = framebuffer_min_size(&font);
+        let scale = (framebuffer.width() / width)
+            .min(framebuffer.height() / height)
+            .max(1);
+        let origin = (
+            framebuffer.width().saturating_sub(width * scale) / 2,
+            framebuffer.height().saturating_sub(height * scale) / 2,
+        );
+        // blank letters are black on black
+        framebuffer.clear(PALETTE[0]);
+        FramebufferScreen {
+            framebuffer,
+            font,
+            scale,
+            origin,
+            shown: Buffer::new(),
+            cursor: None,
+            cursor_shape: None,
+        }
+    }
+
+    fn put(&mut self, row: usize, col: usize, letter: Letter) {
+        if self.shown.letters[row][col] != letter {
+            self.shown.letters[row][col] = letter;
+            self.draw(Position::new(row, col));
+        }
+    }
+
+    /// Hides the cursor when `position` is None
+    fn move_cursor(&mut self, position: Option<Position>) {
+        let old = core::mem::replace(&mut self.cursor, position);
+        if old == position {
+            return;
+        }
+        for position in [old, position].into_iter().flatten() {
+            self.draw(position);
+        }
+    }
+
+    /// Hides the cursor when `shape` is None
+    fn set_cursor_shape(&mut self, shape: Option<CursorShape>) {
+        self.cursor_shape = shape;
+        if let Some(position) = self.cursor {
+            self.draw(position);
+        }
+    }
+
+    fn draw(&mut self, position: Position) {
+        let letter = self.shown.letters[position.row][position.col];
+        let fg = PALETTE[(letter.color & 0xF) as usize];
+        let bg = PALETTE[(letter.color >> 4 & 0xF) as usize];
+        let glyph = self.font.glyph(letter.byte as usize);
+        let cursor = self.cursor_shape.filter(|_| self.cursor == Some(position));
+        let (width, height, scale) = (self.font.width(), self.font.height(), self.scale);
+        let x = self.origin.0 + position.col * width * scale;
+        let y = self.origin.1 + position.row * height * scale;
+        for glyph_y in 0..height {
+            // the cursor shape is in scanlines of a 16 pixel high cell
+            let scanline = (glyph_y * CHARACTER_HEIGHT as usize / height) as u8;
+            let in_cursor =
+                cursor.is_some_and(|shape| (shape.start..=shape.end).contains(&scanline));
+            for glyph_x in 0..width {
+                let color = match in_cursor || self.font.is_set(glyph, glyph_x, glyph_y) {
+                    true => fg,
+                    false => bg,
+                };
+                let (pixel_x, pixel_y) = (x + glyph_x * scale, y + glyph_y * scale);
+                self.framebuffer.fill(pixel_x, pixel_y, scale, scale, color);
+            }
+        }
+    }
+}
+
 #[repr(C)]
 struct Writer {
+    /// col is BUFFER_WIDTH after writing to the last column, until the next character wraps
     cur_pos: Position,
-    buffer: &'static mut Buffer, // height = letters.len() / width
+    /// Contents of the console, copied to the screen while it is active
+    screen: Buffer,
+    /// Whether this is the console on screen
+    active: bool,
+    crtc: CrtController,
+    cursor_enabled: bool,
+    /// Shape the cursor gets back when enabled
+    cursor_shape: CursorShape,
+    /// Position saved by ESC 7 or ESC[s
+    saved_pos: Position,
+    /// Rows (inclusive) that scroll when a new line is needed at the bottom, set by ESC[r
+    scroll_top: usize,
+    scroll_bottom: usize,
+    /// Rows scrolled off the top of the screen, oldest first
+    history: VecDeque<[Letter; BUFFER_WIDTH]>,
+    /// Maximum length of history
+    history_lines: usize,
+    /// How many rows of history the screen is scrolled back (0 shows the live screen)
+    view_offset: usize,
 }
 
 impl Writer {
-    fn new() -> Writer {
+    const fn new() -> Writer {
         Writer {
-            cur_pos: Position::new(0, 0),
-            buffer: unsafe { &mut *(VGA_BUFFER_ADDRESS as *mut Buffer) },
+            cur_pos: Position { row: 0, col: 0 },
+            screen: Buffer::new(),
+            active: false,
+            crtc: CrtController::new(),
+            cursor_enabled: true,
+            cursor_shape: CursorShape::UNDERLINE,
+            saved_pos: Position { row: 0, col: 0 },
+            scroll_top: 0,
+            scroll_bottom: BUFFER_HEIGHT - 1,
+            history: VecDeque::new(),
+            history_lines: DEFAULT_SCROLLBACK_LINES,
+            view_offset: 0,
+        }
+    }
+
+    /// Puts this console on screen
+    fn activate(&mut self) {
+        self.active = true;
+        self.redraw();
+        self.update_cursor_shape();
+        self.update_cursor();
+    }
+
+    /// Copies the console (or the part of the history it is scrolled back to) to the screen
+    fn redraw(&mut self) {
+        if !self.active {
+            return;
+        }
+        let mut framebuffer_screen = FRAMEBUFFER_SCREEN.lock();
+        for row in 0..self.height() {
+            let letters = self.visible_row(row);
+            match framebuffer_screen.as_mut() {
+                Some(screen) => {
+                    for (col, letter) in letters.iter().enumerate() {
+                        screen.put(row, col, *letter);
+                    }
+                }
+                None => hardware_buffer().letters[row] = *letters,
+            }
+        }
+    }
+
+    /// Row shown at `row` of the screen, from the history when scrolled back
+    fn visible_row(&self, row: usize) -> &[Letter; BUFFER_WIDTH] {
+        if self.view_offset == 0 {
+            return &self.screen.letters[row];
+        }
+        let start = self.history.len() - self.view_offset;
+        match self.history.get(start + row) {
+            Some(line) => line,
+            None => &self.screen.letters[start + row - self.history.len()],
+        }
+    }
+
+    fn put(&mut self, row: usize, col: usize, letter: Letter) {
+        self.screen.letters[row][col] = letter;
+        if self.active && self.view_offset == 0 {
+            match FRAMEBUFFER_SCREEN.lock().as_mut() {
+                Some(screen) => screen.put(row, col, letter),
+                None => hardware_buffer().letters[row][col] = letter,
+            }
         }
     }
 
+    /// Where the next character shows up
+    fn cursor_position(&self) -> Position {
+        Position::new(self.cur_pos.row, self.cur_pos.col.min(self.width() - 1))
+    }
+
+    /// Moves the hardware cursor to cur_pos
+    fn update_cursor(&mut self) {
+        if !self.active {
+            return;
+        }
+        let position = (self.view_offset == 0).then(|| self.cursor_position());
+        if let Some(screen) = FRAMEBUFFER_SCREEN.lock().as_mut() {
+            return screen.move_cursor(position);
+        }
+        // off screen hides it
+        let position = position.unwrap_or(Position::new(self.height(), 0));
+        self.crtc
+            .set_cursor_location((position.row * self.width() + position.col) as u16);
+    }
+
+    fn update_cursor_shape(&mut self) {
+        if self.active {
+            let shape = self.cursor_enabled.then_some(self.cursor_shape);
+            match FRAMEBUFFER_SCREEN.lock().as_mut() {
+                Some(screen) => screen.set_cursor_shape(shape),
+                None => self.crtc.set_cursor_shape(shape),
+            }
+        }
+    }
+
+    /// Moves cur_pos, clamped to the screen
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.cur_pos = Position::new(row.min(self.height() - 1), col.min(self.width() - 1));
+    }
+
     /// Erases last character from screen
     ///
     /// hello
@@ -176,44 +1001,154 @@ impl Writer {
     /// Back (go to "o" position), write ' ', back again
     /// We go back again because write() moves the pointer forward
     fn backspace(&mut self) {
+        self.cur_pos = self.cursor_position();
         self.cur_pos.back();
         // save background color
-        let color = self.buffer.letters[self.cur_pos.row][self.cur_pos.col].color;
+        let color = self.screen.letters[self.cur_pos.row][self.cur_pos.col].color;
         self.write(b' ', color);
         // we have to back again because write() moves the pointer forward
         self.cur_pos.back();
     }
 
     fn clear(&mut self) {
-        self.buffer.clear();
+        self.screen.clear();
         self.cur_pos = Position::new(0, 0);
+        self.redraw();
     }
 
     fn height(&self) -> usize {
-        self.buffer.letters.len()
+        self.screen.letters.len()
     }
 
     fn width(&self) -> usize {
-        self.buffer.letters[0].len()
+        self.screen.letters[0].len()
     }
 
     fn write(&mut self, byte: u8, color: u8) {
-        if byte == b'\n' || self.cur_pos.col >= self.width() {
+        match byte {
+            b'\n' => return self.new_line(color),
+            b'\r' => return self.cur_pos.col = 0,
+            _ => {}
+        }
+        // wrap only once there's something to put on the next line
+        if self.cur_pos.col >= self.width() {
+            self.new_line(color);
+        }
+        self.put(self.cur_pos.row, self.cur_pos.col, Letter::new(byte, color));
+        self.cur_pos.col += 1;
+    }
+
+    /// Goes to the start of the next line, scrolling when at the bottom of the scroll region
+    fn new_line(&mut self, color: u8) {
+        self.cur_pos.col = 0;
+        if self.cur_pos.row == self.scroll_bottom {
+            self.scroll_up(1, color);
+        } else if self.cur_pos.row < self.height() - 1 {
             self.cur_pos.row += 1;
-            self.cur_pos.col = 0;
-            return;
         }
-        // if row == 25 we are at row number 26, if height is 25 we need to push everything one line up
-        if self.cur_pos.row >= self.height() {
-            for i in 0..self.height() - 1 {
-                self.buffer.letters[i] = self.buffer.letters[i + 1].clone();
+    }
+
+    fn blank(color: u8) -> Letter {
+        Letter::new(b' ', color)
+    }
+
+    /// Moves the rows of the scroll region up, blanking the ones uncovered at its bottom. Rows
+    /// leaving the top of the screen go to the history.
+    fn scroll_up(&mut self, lines: usize, color: u8) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let lines = lines.min(bottom + 1 - top);
+        // the row is lost when the heap can't take it (early at boot, when logging from the
+        // allocator or when it's full)
+        if top == 0 && self.history_lines > 0 && allocator::is_available() {
+            for row in 0..lines {
+                if self.history.len() >= self.history_lines {
+                    self.history.pop_front();
+                }
+                // room for the whole history is taken at once, a no-op once it's there
+                let missing = self.history_lines - self.history.len();
+                if self.history.try_reserve_exact(missing).is_err() {
+                    break;
+                }
+                self.history.push_back(self.screen.letters[row]);
             }
-            self.cur_pos.row = self.height() - 1; // set row to last row
-                                                  // clear last row
-            self.buffer.letters[self.height() - 1] = [Letter::new(0, 0); BUFFER_WIDTH];
         }
-        self.buffer.letters[self.cur_pos.row][self.cur_pos.col] = Letter::new(byte, color);
-        self.cur_pos.col += 1;
+        for row in top..bottom + 1 - lines {
+            self.screen.letters[row] = self.screen.letters[row + lines];
+        }
+        for row in bottom + 1 - lines..=bottom {
+            self.screen.letters[row] = [Writer::blank(color); BUFFER_WIDTH];
+        }
+        self.redraw();
+    }
+
+    /// Moves the rows of the scroll region down, blanking the ones uncovered at its top
+    fn scroll_down(&mut self, lines: usize, color: u8) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let lines = lines.min(bottom + 1 - top);
+        for row in (top + lines..=bottom).rev() {
+            self.screen.letters[row] = self.screen.letters[row - lines];
+        }
+        for row in top..top + lines {
+            self.screen.letters[row] = [Writer::blank(color); BUFFER_WIDTH];
+        }
+        self.redraw();
+    }
+
+    /// Shows the screen scrolled back `offset` rows into the history (at most all of it). Anything
+    /// that writes must go back to offset 0 first.
+    fn scroll_view(&mut self, offset: usize) {
+        let offset = offset.min(self.history.len());
+        if offset == self.view_offset {
+            return;
+        }
+        self.view_offset = offset;
+        self.redraw();
+        self.update_cursor();
+    }
+
+    /// Restricts scrolling to rows `top..=bottom` and moves to the top left corner. Invalid
+    /// regions are ignored.
+    fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        if top >= bottom || bottom >= self.height() {
+            return;
+        }
+        self.scroll_top = top;
+        self.scroll_bottom = bottom;
+        self.cur_pos = Position::new(0, 0);
+    }
+
+    /// ESC[K: erases from the cursor to the end of the line (0), from the start of the line to
+    /// the cursor (1) or the whole line (2)
+    fn erase_line(&mut self, mode: u16, color: u8) {
+        let Position { row, col } = self.cursor_position();
+        let columns = match mode {
+            0 => col..self.width(),
+            1 => 0..col + 1,
+            2 => 0..self.width(),
+            _ => return,
+        };
+        for col in columns {
+            self.put(row, col, Writer::blank(color));
+        }
+    }
+
+    /// ESC[J: erases from the cursor to the end of the screen (0), from the start of the screen
+    /// to the cursor (1) or the whole screen (2)
+    fn erase_display(&mut self, mode: u16, color: u8) {
+        let row = self.cur_pos.row;
+        let rows = match mode {
+            0 => row + 1..self.height(),
+            1 => 0..row,
+            2 | 3 => 0..self.height(),
+            _ => return,
+        };
+        for row in rows {
+            self.screen.letters[row] = [Writer::blank(color); BUFFER_WIDTH];
+        }
+        if mode < 2 {
+            self.erase_line(mode, color);
+        }
+        self.redraw();
     }
 }
 
@@ -226,21 +1161,210 @@ mod tests {
         use x86_64::instructions::interrupts::without_interrupts;
         without_interrupts(|| {
             // First we clear any data already present in the VGA buffer
-            VGA.lock().clear();
+            stdout().clear();
             println!("this is a test line");
-            assert_eq!('t' as u8, VGA.lock().writer.buffer.letters[0][0].byte);
-            assert_eq!('h' as u8, VGA.lock().writer.buffer.letters[0][1].byte);
-            assert_eq!('i' as u8, VGA.lock().writer.buffer.letters[0][2].byte);
-            assert_eq!('s' as u8, VGA.lock().writer.buffer.letters[0][3].byte);
+            assert_eq!(b't', stdout().writer.screen.letters[0][0].byte);
+            assert_eq!(b'h', stdout().writer.screen.letters[0][1].byte);
+            assert_eq!(b'i', stdout().writer.screen.letters[0][2].byte);
+            assert_eq!(b's', stdout().writer.screen.letters[0][3].byte);
 
             println!("another test line");
-            assert_eq!('a' as u8, VGA.lock().writer.buffer.letters[1][0].byte);
-            assert_eq!('n' as u8, VGA.lock().writer.buffer.letters[1][1].byte);
-            assert_eq!('o' as u8, VGA.lock().writer.buffer.letters[1][2].byte);
-            assert_eq!('t' as u8, VGA.lock().writer.buffer.letters[1][3].byte);
-            assert_eq!('h' as u8, VGA.lock().writer.buffer.letters[1][4].byte);
-            assert_eq!('e' as u8, VGA.lock().writer.buffer.letters[1][5].byte);
-            assert_eq!('r' as u8, VGA.lock().writer.buffer.letters[1][6].byte);
+            assert_eq!(b'a', stdout().writer.screen.letters[1][0].byte);
+            assert_eq!(b'n', stdout().writer.screen.letters[1][1].byte);
+            assert_eq!(b'o', stdout().writer.screen.letters[1][2].byte);
+            assert_eq!(b't', stdout().writer.screen.letters[1][3].byte);
+            assert_eq!(b'h', stdout().writer.screen.letters[1][4].byte);
+            assert_eq!(b'e', stdout().writer.screen.letters[1][5].byte);
+            assert_eq!(b'r', stdout().writer.screen.letters[1][6].byte);
+        });
+    }
+
+    #[test_case]
+    fn test_code_page_437() {
+        assert_eq!(to_code_page_437('a'), b'a');
+        assert_eq!(to_code_page_437('ç'), 0x87);
+        assert_eq!(to_code_page_437('é'), 0x82);
+        assert_eq!(to_code_page_437('€'), UNDEFINED_CHAR);
+    }
+
+    #[test_case]
+    fn test_hardware_cursor() {
+        use x86_64::instructions::interrupts::without_interrupts;
+        without_interrupts(|| {
+            let mut vga = stdout();
+            vga.set_cursor(3, 7);
+            assert_eq!(vga.cursor(), (3, 7));
+            assert_eq!(vga.writer.crtc.cursor_location(), 3 * 80 + 7);
+            write!(vga, "abc").unwrap();
+            assert_eq!(vga.writer.screen.letters[3][7].byte, b'a');
+            assert_eq!(vga.writer.crtc.cursor_location(), 3 * 80 + 10);
+            // clamped to the screen
+            vga.set_cursor(100, 100);
+            assert_eq!(vga.cursor(), (24, 79));
+            vga.clear();
+            assert_eq!(vga.writer.crtc.cursor_location(), 0);
+        });
+    }
+
+    /// Text of `row`, without trailing blanks
+    fn row_text(vga: &Vga, row: usize) -> String {
+        let text: String = vga.writer.screen.letters[row]
+            .iter()
+            .map(|letter| match letter.byte {
+                0 => ' ',
+                byte => byte as char,
+            })
+            .collect();
+        String::from(text.trim_end())
+    }
+
+    #[test_case]
+    fn test_ansi_colors() {
+        use x86_64::instructions::interrupts::without_interrupts;
+        without_interrupts(|| {
+            let mut vga = stdout();
+            vga.clear();
+            write!(vga, "\x1b[31ma\x1b[1;44mb\x1b[7mc\x1b[0md").unwrap();
+            let colors: Vec<u8> = (0..4)
+                .map(|col| vga.writer.screen.letters[0][col].color)
+                .collect();
+            let (red, light_red) = (Color::Red as u8, Color::LightRed as u8);
+            let (blue, white) = (Color::Blue as u8, Color::White as u8);
+            assert_eq!(colors, [red, blue << 4 | light_red, light_red << 4 | blue, white]);
+            assert_eq!(row_text(&vga, 0), "abcd");
+            vga.clear();
+        });
+    }
+
+    #[test_case]
+    fn test_ansi_cursor_and_erase() {
+        use x86_64::instructions::interrupts::without_interrupts;
+        without_interrupts(|| {
+            let mut vga = stdout();
+            vga.clear();
+            write!(vga, "hello\nworld\x1b[1;3HX\x1b[2B\x1b[1DY").unwrap();
+            assert_eq!(row_text(&vga, 0), "heXlo");
+            assert_eq!(row_text(&vga, 2), "  Y");
+            // save, move and erase, restore
+            write!(vga, "\x1b[s\x1b[2;3H\x1b[K\x1b[uZ").unwrap();
+            assert_eq!(row_text(&vga, 1), "wo");
+            assert_eq!(row_text(&vga, 2), "  YZ");
+            write!(vga, "\x1b[2J").unwrap();
+            assert!((0..3).all(|row| row_text(&vga, row).is_empty()));
+            vga.clear();
+        });
+    }
+
+    #[test_case]
+    fn test_ansi_scroll_region() {
+        use x86_64::instructions::interrupts::without_interrupts;
+        without_interrupts(|| {
+            let mut vga = stdout();
+            vga.clear();
+            // rows 2 and 3 scroll, row 1 stays
+            write!(vga, "\x1b[2;3r\x1b[1;1Htop\x1b[2;1Ha\nb\nc").unwrap();
+            assert_eq!(row_text(&vga, 0), "top");
+            assert_eq!(row_text(&vga, 1), "b");
+            assert_eq!(row_text(&vga, 2), "c");
+            assert_eq!(vga.cursor(), (2, 1));
+            write!(vga, "\x1b[r").unwrap();
+            vga.clear();
+        });
+    }
+
+    #[test_case]
+    fn test_scrollback() {
+        use x86_64::instructions::interrupts::without_interrupts;
+        without_interrupts(|| {
+            let mut vga = stdout();
+            vga.clear();
+            for i in 0..BUFFER_HEIGHT + 5 {
+                write!(vga, "\nline {i}").unwrap();
+            }
+            // the live screen ends with line 29, line 4 is the newest row of history
+            assert_eq!(row_text(&vga, BUFFER_HEIGHT - 1), "line 29");
+            vga.scroll_back(3);
+            assert_eq!(row_text(&vga, 0), "line 2");
+            assert_eq!(row_text(&vga, BUFFER_HEIGHT - 1), "line 26");
+            vga.scroll_forward(1);
+            assert_eq!(row_text(&vga, 0), "line 3");
+            // writing goes back to the live screen
+            write!(vga, "!").unwrap();
+            assert_eq!(row_text(&vga, BUFFER_HEIGHT - 1), "line 29!");
+            assert_eq!(vga.cursor(), (BUFFER_HEIGHT - 1, 8));
+
+            vga.set_scrollback_lines(2);
+            vga.scroll_back(10);
+            assert_eq!(row_text(&vga, 0), "line 3");
+            vga.set_scrollback_lines(DEFAULT_SCROLLBACK_LINES);
+            vga.clear();
+        });
+    }
+
+    #[test_case]
+    fn test_virtual_consoles() {
+        use x86_64::instructions::interrupts::without_interrupts;
+        without_interrupts(|| {
+            write!(CONSOLES[1].lock(), "\x1b[Hsecond").unwrap();
+            // off screen until switched to
+            assert_ne!(hardware_buffer().letters[0][0].byte, b's');
+            assert!(switch_console(1));
+            assert_eq!(active_console(), 1);
+            assert_eq!(hardware_buffer().letters[0][0].byte, b's');
+            write!(CONSOLES[0].lock(), "\x1b[Hfirst").unwrap();
+            assert_eq!(hardware_buffer().letters[0][0].byte, b's');
+            assert!(switch_console(0));
+            assert_eq!(hardware_buffer().letters[0][0].byte, b'f');
+            assert!(!switch_console(CONSOLE_COUNT));
+        });
+    }
+
+    #[test_case]
+    fn test_framebuffer_screen() {
+        use crate::framebuffer::PixelFormat;
+        // two rows of eight letters, the rest is clipped
+        let framebuffer = Framebuffer::off_screen(64, 32, PixelFormat::Bgr24);
+        let mut screen = FramebufferScreen::new(framebuffer, *FONT);
+        assert_eq!((screen.scale, screen.origin), (1, (0, 0)));
+
+        // white on blue
+        screen.put(0, 1, Letter::new(0xDB, 0x1F));
+        screen.put(0, 2, Letter::new(b' ', 0x1F));
+        let pixel = |screen: &FramebufferScreen<Vec<u8>>, x, y| screen.framebuffer.pixel(x, y);
+        assert_eq!(pixel(&screen, 8 + 4, 8), Some(PALETTE[0xF]));
+        assert_eq!(pixel(&screen, 16 + 4, 8), Some(PALETTE[0x1]));
+        assert_eq!(pixel(&screen, 4, 8), Some(PALETTE[0]));
+
+        // the underline cursor takes the letter's foreground color
+        screen.set_cursor_shape(Some(CursorShape::UNDERLINE));
+        screen.move_cursor(Some(Position::new(0, 2)));
+        assert_eq!(pixel(&screen, 16 + 4, 15), Some(PALETTE[0xF]));
+        assert_eq!(pixel(&screen, 16 + 4, 13), Some(PALETTE[0x1]));
+        screen.move_cursor(None);
+        assert_eq!(pixel(&screen, 16 + 4, 15), Some(PALETTE[0x1]));
+    }
+
+    #[test_case]
+    fn test_print_queue() {
+        use x86_64::instructions::interrupts::without_interrupts;
+        without_interrupts(|| {
+            let console = CONSOLES[3].lock();
+            print_to(3, format_args!("\x1b[Hqueued"));
+            assert_eq!(PRINT_QUEUE.len(), "\x1b[Hqueued".len());
+
+            // printing while the queue is full drops the newest output
+            let dropped = dropped_print_bytes();
+            for _ in 0..PRINT_QUEUE_SIZE {
+                print_to(3, format_args!("\r"));
+            }
+            assert_eq!(dropped_print_bytes() - dropped, "\x1b[Hqueued".len());
+            drop(console);
+
+            // the queue is written out first
+            print_to(3, format_args!("\x1b[H after"));
+            assert!(PRINT_QUEUE.is_empty());
+            let console = CONSOLES[3].lock();
+            assert_eq!(row_text(&console, 0), " after");
         });
     }
 
@@ -0,0 +1,12 @@
+#!/bin/sh
+# Assembles the user programs in this directory into user/bin, where the kernel embeds them from
+# with include_bytes!. Run it again after changing any of them.
+set -e
+cd "$(dirname "$0")"
+mkdir -p bin
+for src in *.S; do
+    name="${src%.S}"
+    as --64 -o "bin/$name.o" "$src"
+    ld -static -nostdlib -z max-page-size=0x1000 -z noexecstack -T link.ld -o "bin/$name" "bin/$name.o"
+    rm "bin/$name.o"
+done